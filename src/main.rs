@@ -2,7 +2,6 @@ mod word2vec;
 mod server;
 use std::path::PathBuf;
 use clap::{Arg, App};
-use ctrlc;
 
 fn main() {
     let matches = App::new("word2vec server")
@@ -13,9 +12,188 @@ fn main() {
                             .short("b")
                             .long("bin")
                             .value_name("FILE")
-                            .help("Path to file containing word/vector pairs, usually *.bin")
+                            .help("Path to file containing word/vector pairs, usually *.bin, optionally gzip or xz compressed, or - for stdin")
                             .takes_value(true)
-                            .required(true))
+                            .required_unless("model"))
+                        .arg(Arg::with_name("model")
+                            .long("model")
+                            .value_name("NAME=FILE")
+                            .help("Serve the model in FILE at /models/NAME/convert, may be given more than once. The first model is also served at /convert")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .validator(|value| if value.contains('=') { Ok(()) } else { Err(String::from("expected NAME=FILE")) })
+                            .conflicts_with("bin")
+                            .required(false))
+                        .arg(Arg::with_name("format")
+                            .short("f")
+                            .long("format")
+                            .value_name("FORMAT")
+                            .help("Layout of the model file, default auto")
+                            .takes_value(true)
+                            .possible_values(&["auto", "binary", "text", "fasttext", "snapshot"])
+                            .required(false))
+                        .arg(Arg::with_name("mmap")
+                            .short("m")
+                            .long("mmap")
+                            .help("Memory map a binary model instead of loading it onto the heap")
+                            .takes_value(false)
+                            .required(false))
+                        .arg(Arg::with_name("threads")
+                            .short("t")
+                            .long("threads")
+                            .value_name("THREADS")
+                            .help("Threads used to parse a binary model, 0 for all cores, default 1")
+                            .takes_value(true)
+                            .required(false))
+                        .arg(Arg::with_name("search-threads")
+                            .long("search-threads")
+                            .value_name("THREADS")
+                            .help("Threads each nearest neighbour query is split across, 0 for all cores, default 1")
+                            .takes_value(true)
+                            .required(false))
+                        .arg(Arg::with_name("max-words")
+                            .long("max-words")
+                            .value_name("N")
+                            .help("Keep only the first N words that pass the other filters")
+                            .takes_value(true)
+                            .required(false))
+                        .arg(Arg::with_name("allowlist")
+                            .long("allowlist")
+                            .value_name("FILE")
+                            .help("Keep only words listed in FILE, one per line")
+                            .takes_value(true)
+                            .required(false))
+                        .arg(Arg::with_name("include")
+                            .long("include")
+                            .value_name("REGEX")
+                            .help("Keep only words matching REGEX")
+                            .takes_value(true)
+                            .required(false))
+                        .arg(Arg::with_name("exclude")
+                            .long("exclude")
+                            .value_name("REGEX")
+                            .help("Drop words matching REGEX")
+                            .takes_value(true)
+                            .required(false))
+                        .arg(Arg::with_name("min-length")
+                            .long("min-length")
+                            .value_name("N")
+                            .help("Drop words shorter than N characters")
+                            .takes_value(true)
+                            .required(false))
+                        .arg(Arg::with_name("invalid-utf8")
+                            .long("invalid-utf8")
+                            .value_name("POLICY")
+                            .help("What to do with words that are not valid UTF-8: fail the load, replace bad bytes with U+FFFD, or keep them raw, default lossy")
+                            .takes_value(true)
+                            .possible_values(&["reject", "lossy", "raw"])
+                            .required(false))
+                        .arg(Arg::with_name("normalize")
+                            .long("normalize")
+                            .help("Keep only unit length vectors, saving the per-word norms. Lookups then return unit vectors")
+                            .takes_value(false)
+                            .required(false))
+                        .arg(Arg::with_name("hnsw")
+                            .long("hnsw")
+                            .help("Keep an HNSW index next to each model for /most_similar?search=approximate, building it first if there is none")
+                            .takes_value(false)
+                            .required(false))
+                        .arg(Arg::with_name("hnsw-m")
+                            .long("hnsw-m")
+                            .value_name("M")
+                            .help("Links per word in a new HNSW index, default 16")
+                            .takes_value(true)
+                            .requires("hnsw")
+                            .required(false))
+                        .arg(Arg::with_name("hnsw-ef-construction")
+                            .long("hnsw-ef-construction")
+                            .value_name("EF")
+                            .help("Candidates considered while building an HNSW index, default 200")
+                            .takes_value(true)
+                            .requires("hnsw")
+                            .required(false))
+                        .arg(Arg::with_name("hnsw-ef")
+                            .long("hnsw-ef")
+                            .value_name("EF")
+                            .help("Candidates considered per HNSW query, default 64")
+                            .takes_value(true)
+                            .requires("hnsw")
+                            .required(false))
+                        .arg(Arg::with_name("hnsw-threads")
+                            .long("hnsw-threads")
+                            .value_name("THREADS")
+                            .help("Threads used to build an HNSW index, default 0 for all cores")
+                            .takes_value(true)
+                            .requires("hnsw")
+                            .required(false))
+                        .arg(Arg::with_name("ivfpq")
+                            .long("ivfpq")
+                            .help("Keep an IVF-PQ index next to each model for /most_similar?search=approximate from compressed codes, training it first if there is none")
+                            .takes_value(false)
+                            .required(false))
+                        .arg(Arg::with_name("ivfpq-lists")
+                            .long("ivfpq-lists")
+                            .value_name("N")
+                            .help("Cells a new IVF-PQ index splits the vectors into, default 1024")
+                            .takes_value(true)
+                            .requires("ivfpq")
+                            .required(false))
+                        .arg(Arg::with_name("ivfpq-subspaces")
+                            .long("ivfpq-subspaces")
+                            .value_name("N")
+                            .help("Bytes per word in a new IVF-PQ index, at most the dimension, default 32")
+                            .takes_value(true)
+                            .requires("ivfpq")
+                            .required(false))
+                        .arg(Arg::with_name("ivfpq-probes")
+                            .long("ivfpq-probes")
+                            .value_name("N")
+                            .help("Cells searched per IVF-PQ query, default 16")
+                            .takes_value(true)
+                            .requires("ivfpq")
+                            .required(false))
+                        .arg(Arg::with_name("ivfpq-rerank")
+                            .long("ivfpq-rerank")
+                            .value_name("N")
+                            .help("Candidates per IVF-PQ query re-ranked by their exact cosine, default 0")
+                            .takes_value(true)
+                            .requires("ivfpq")
+                            .required(false))
+                        .arg(Arg::with_name("ivfpq-threads")
+                            .long("ivfpq-threads")
+                            .value_name("THREADS")
+                            .help("Threads used to train an IVF-PQ index, default 0 for all cores")
+                            .takes_value(true)
+                            .requires("ivfpq")
+                            .required(false))
+                        .arg(Arg::with_name("recall")
+                            .long("recall")
+                            .value_name("K")
+                            .help("Print recall@K of each model's approximate index against exact search and exit instead of serving")
+                            .takes_value(true)
+                            .required(false))
+                        .arg(Arg::with_name("save")
+                            .short("s")
+                            .long("save")
+                            .value_name("FILE")
+                            .help("Write the loaded model to FILE and exit instead of serving it")
+                            .takes_value(true)
+                            .required(false))
+                        .arg(Arg::with_name("save-format")
+                            .long("save-format")
+                            .value_name("FORMAT")
+                            .help("Layout to save in, default auto (text for *.txt and *.vec, snapshot for *.snap, else binary)")
+                            .takes_value(true)
+                            .possible_values(&["auto", "binary", "text", "snapshot"])
+                            .requires("save")
+                            .required(false))
+                        .arg(Arg::with_name("admin-token")
+                            .long("admin-token")
+                            .value_name("TOKEN")
+                            .help("Accept POST /admin/reload with the header `Authorization: Bearer TOKEN`. Models are always reloaded on SIGHUP")
+                            .takes_value(true)
+                            .required(false))
                         .arg(Arg::with_name("port")
                             .short("p")
                             .long("port")
@@ -25,9 +203,87 @@ fn main() {
                             .required(false))
                         .get_matches();
 
-    let model_path = PathBuf::from(matches.value_of("bin").unwrap());
+    // --bin is a single model named "default"
+    let model_paths: Vec<(String,PathBuf)> = match matches.values_of("model") {
+        Some(models) => models.map(|model| {
+            let mut parts = model.splitn(2, '=');
+            (parts.next().unwrap().to_string(), PathBuf::from(parts.next().unwrap()))
+        }).collect(),
+        None => vec![(String::from("default"), PathBuf::from(matches.value_of("bin").unwrap()))],
+    };
     let port: u16  = matches.value_of("port").unwrap_or("3030").parse::<u16>().unwrap();
-    let mut server = server::Server::init(model_path).unwrap();
+    let options = word2vec::LoadOptions {
+        format: matches.value_of("format").unwrap_or("auto").parse::<word2vec::Format>().unwrap(),
+        mmap: matches.is_present("mmap"),
+        threads: matches.value_of("threads").unwrap_or("1").parse::<usize>().unwrap(),
+        filter: word2vec::VocabFilter {
+            max_words: matches.value_of("max-words").map(|n| n.parse::<usize>().unwrap()),
+            allowlist: matches.value_of("allowlist").map(|path| word2vec::VocabFilter::read_allowlist(PathBuf::from(path)).unwrap()),
+            include: matches.value_of("include").map(|pattern| regex::Regex::new(pattern).unwrap()),
+            exclude: matches.value_of("exclude").map(|pattern| regex::Regex::new(pattern).unwrap()),
+            min_length: matches.value_of("min-length").unwrap_or("0").parse::<usize>().unwrap(),
+        },
+        utf8: matches.value_of("invalid-utf8").unwrap_or("lossy").parse::<word2vec::Utf8Policy>().unwrap(),
+        normalize: matches.is_present("normalize"),
+        search_threads: matches.value_of("search-threads").unwrap_or("1").parse::<usize>().unwrap(),
+        hnsw: if matches.is_present("hnsw") {
+            let defaults = word2vec::HnswOptions::default();
+            Some(word2vec::HnswOptions {
+                m: matches.value_of("hnsw-m").map(|n| n.parse::<usize>().unwrap()).unwrap_or(defaults.m),
+                ef_construction: matches.value_of("hnsw-ef-construction").map(|n| n.parse::<usize>().unwrap()).unwrap_or(defaults.ef_construction),
+                ef_search: matches.value_of("hnsw-ef").map(|n| n.parse::<usize>().unwrap()).unwrap_or(defaults.ef_search),
+                threads: matches.value_of("hnsw-threads").map(|n| n.parse::<usize>().unwrap()).unwrap_or(defaults.threads),
+                ..defaults
+            })
+        } else {
+            None
+        },
+        ivfpq: if matches.is_present("ivfpq") {
+            let defaults = word2vec::IvfPqOptions::default();
+            Some(word2vec::IvfPqOptions {
+                lists: matches.value_of("ivfpq-lists").map(|n| n.parse::<usize>().unwrap()).unwrap_or(defaults.lists),
+                subspaces: matches.value_of("ivfpq-subspaces").map(|n| n.parse::<usize>().unwrap()).unwrap_or(defaults.subspaces),
+                probes: matches.value_of("ivfpq-probes").map(|n| n.parse::<usize>().unwrap()).unwrap_or(defaults.probes),
+                rerank: matches.value_of("ivfpq-rerank").map(|n| n.parse::<usize>().unwrap()).unwrap_or(defaults.rerank),
+                threads: matches.value_of("ivfpq-threads").map(|n| n.parse::<usize>().unwrap()).unwrap_or(defaults.threads),
+                ..defaults
+            })
+        } else {
+            None
+        },
+    };
+    if let Some(k) = matches.value_of("recall") {
+        let k = k.parse::<usize>().unwrap();
+        for (name, model_path) in model_paths.iter() {
+            let model = word2vec::Model::with_options(model_path.clone(), &options).unwrap();
+            let recall = match model.recall(k, 1000) {
+                Some(recall) => recall,
+                None => {
+                    println!("{}: no approximate index, pass --hnsw or --ivfpq", name);
+                    continue;
+                }
+            };
+            println!("{}: recall@{} {:.4} over {} queries, {:.3}ms exact, {:.3}ms approximate",
+                name, recall.k, recall.recall, recall.queries,
+                recall.exact_seconds*1000.0, recall.approximate_seconds*1000.0);
+        }
+        return;
+    }
+    if let Some(save_path) = matches.value_of("save") {
+        let format = matches.value_of("save-format").unwrap_or("auto").parse::<word2vec::Format>().unwrap();
+        if model_paths.len() > 1 {
+            println!("--save takes a single model");
+            return;
+        }
+        let model_path = model_paths[0].1.clone();
+        let model = word2vec::Model::with_options(model_path, &options).unwrap();
+        print!("Saving model... ");
+        model.save(PathBuf::from(save_path), format).unwrap();
+        println!("Done");
+        return;
+    }
+    let admin_token = matches.value_of("admin-token").map(String::from);
+    let mut server = server::Server::init(model_paths, options, admin_token).unwrap();
     let shutdown_tx = server.get_shutdown_tx(); 
     ctrlc::set_handler(move || {
         println!("\nReceived Ctrl-C, shutting down servers...");
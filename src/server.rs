@@ -1,22 +1,23 @@
 use crate::word2vec;
 use std::path::PathBuf;
 use threadpool::ThreadPool;
-use tokio;
 use tokio::sync::oneshot;
 use std::collections::HashMap;
 use std::net::{Ipv4Addr, SocketAddrV4, SocketAddr};
 use std::sync::Arc;
 use std::sync::Mutex;
 
-use warp::Filter;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
 // use serde_derive::{Deserialize, Serialize};
 use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
 use crossbeam::channel::{
+    bounded,
     unbounded,
     Sender,
     Receiver,
     SendError, 
-    RecvError,
     // TryIter,
     // TryRecvError,
     Iter};
@@ -24,25 +25,281 @@ use crossbeam::channel::{
 #[derive(Deserialize, Serialize)]
 struct ConvertPayload {
     words: Vec<String>,
+    // build vectors for unknown words from their n-grams, if the model can
+    #[serde(default)]
+    subwords: bool,
 }
 
 #[derive(Deserialize, Serialize)]
 struct ConvertResponse {
     data: HashMap<String,Option<Vec<f32>>>,
+    // words whose vectors were built from n-grams rather than looked up
+    synthesized: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize)]
+struct AnalogyPayload {
+    #[serde(default)]
+    positive: Vec<String>,
+    #[serde(default)]
+    negative: Vec<String>,
+    #[serde(default = "default_k")]
+    k: usize,
+    // add (3CosAdd) or mul (3CosMul), default add
+    #[serde(default)]
+    objective: Option<String>,
+}
+
+fn default_k() -> usize {
+    10
+}
+
+// either a word or a vector to find the neighbours of
+#[derive(Deserialize, Serialize)]
+struct SimilarPayload {
+    #[serde(default)]
+    word: Option<String>,
+    #[serde(default)]
+    vector: Option<Vec<f32>>,
+    #[serde(default = "default_k")]
+    k: usize,
+}
+
+// either words or vectors to find the neighbours of, all in one pass
+#[derive(Deserialize, Serialize)]
+struct BatchPayload {
+    #[serde(default)]
+    words: Option<Vec<String>>,
+    #[serde(default)]
+    vectors: Option<Vec<Vec<f32>>>,
+    #[serde(default = "default_k")]
+    k: usize,
+}
+
+// a list per query in the order asked, null for words not in the model
+#[derive(Deserialize, Serialize)]
+struct BatchResponse {
+    data: Vec<Option<Vec<Scored>>>,
+    unknown: Vec<String>,
+}
+
+// every word at least as close as the threshold to either a word or a
+// vector, up to the limit
+#[derive(Deserialize, Serialize)]
+struct WithinPayload {
+    #[serde(default)]
+    word: Option<String>,
+    #[serde(default)]
+    vector: Option<Vec<f32>>,
+    threshold: f32,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    1000
+}
+
+// closest first, truncated if more words than the limit were close enough
+#[derive(Deserialize, Serialize)]
+struct WithinResponse {
+    data: Vec<Scored>,
+    truncated: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct WordsPayload {
+    words: Vec<String>,
+}
+
+// the known words, most out of place first, and those not in the model
+#[derive(Deserialize, Serialize)]
+struct RankedResponse {
+    data: Vec<Scored>,
+    unknown: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SetsPayload {
+    set_a: Vec<String>,
+    set_b: Vec<String>,
+    // the cosine of every word of set_a with every word of set_b rather
+    // than of the sets as a whole
+    #[serde(default)]
+    pairwise: bool,
+}
+
+// null when either set has no word in the model
+#[derive(Deserialize, Serialize)]
+struct SetSimilarityResponse {
+    similarity: Option<f32>,
+    unknown_a: Vec<String>,
+    unknown_b: Vec<String>,
+}
+
+// data[row][column], for the words of each set in the model
+#[derive(Deserialize, Serialize)]
+struct MatrixResponse {
+    rows: Vec<String>,
+    columns: Vec<String>,
+    data: Vec<Vec<f32>>,
+    unknown_a: Vec<String>,
+    unknown_b: Vec<String>,
+}
+
+// ?metric=, cosine by default, and for /most_similar ?search=, exact by
+// default
+#[derive(Deserialize, Serialize)]
+struct MetricQuery {
+    #[serde(default)]
+    metric: Option<String>,
+    #[serde(default)]
+    search: Option<String>,
+}
+
+// warp rejects a request without a query string at all, so that counts as
+// an empty one
+fn metric_query() -> impl Filter<Extract = (MetricQuery,), Error = Infallible> + Clone {
+    warp::query::<MetricQuery>()
+        .or(warp::any().map(|| MetricQuery { metric: None, search: None }))
+        .unify()
+}
+
+#[derive(Deserialize, Serialize)]
+struct Scored {
+    word: String,
+    score: f32,
+}
+
+// best first
+#[derive(Deserialize, Serialize)]
+struct ScoresResponse {
+    data: Vec<Scored>,
+}
+
+fn scored(scores: Vec<(String, f32)>) -> Vec<Scored> {
+    scores.into_iter().map(|(word, score)| Scored { word, score }).collect()
+}
+
+#[derive(Deserialize, Serialize)]
+struct UnknownWordsResponse {
+    error: String,
+    unknown: Vec<String>,
+}
+
+// One entry of the /models listing
+#[derive(Clone, Deserialize, Serialize)]
+struct ModelInfo {
+    name: String,
+    words: usize,
+    dimension: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ModelsResponse {
+    models: Vec<ModelInfo>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[allow(dead_code)]
 struct TestResponse {
     data: String,
 }
 
+#[derive(Deserialize, Serialize)]
+struct ReloadPayload {
+    model: String,
+    // load from here instead of where the model was last loaded from
+    #[serde(default)]
+    path: Option<PathBuf>,
+}
+
+// Requests name the model they are for. A whole /convert request travels as
+// one message, so a reload can only land between requests, never part way
+// through one. Each query carries the sender its answer goes back on, so
+// requests in flight at the same time never get each other's answers.
 #[derive(Clone, Debug)]
 pub enum ThreadComm {
-    Word2Vec(String),
-    WordVec(Option<Vec<f32>>),
+    // model, words, and whether to build vectors for unknown words from n-grams
+    Convert(String, Vec<String>, bool, Sender<ThreadComm>),
+    // a vector per word, and whether it was built from n-grams
+    Vectors(Vec<(Option<Vec<f32>>, bool)>),
+    // model, what to find the neighbours of, k, metric and whether to use
+    // the model's approximate index
+    Similar(String, Target, usize, word2vec::Metric, word2vec::Search, Sender<ThreadComm>),
+    // model, positive words, negative words, k and objective
+    Analogy(String, Vec<String>, Vec<String>, usize, word2vec::Analogy, Sender<ThreadComm>),
+    // model, what to search around, threshold, limit and metric
+    Within(String, Target, f32, usize, word2vec::Metric, Sender<ThreadComm>),
+    // model and the words to find the odd one out of
+    DoesntMatch(String, Vec<String>, Sender<ThreadComm>),
+    // the words of a DoesntMatch ranked
+    Ranked(Result<word2vec::DoesntMatch, QueryError>),
+    // model, the two sets of words, and whether to compare them pairwise
+    Sets(String, Vec<String>, Vec<String>, bool, Sender<ThreadComm>),
+    // how alike two sets are as a whole
+    SetSimilarity(Result<word2vec::SetSimilarity, QueryError>),
+    // how alike each word of one set is to each of the other
+    Matrix(Result<word2vec::SimilarityMatrix, QueryError>),
+    // model, each of the words or vectors to find the neighbours of, k and
+    // metric
+    SimilarBatch(String, Targets, usize, word2vec::Metric, Sender<ThreadComm>),
+    // words and their scores, best first
+    Scores(Result<Vec<(String, f32)>, QueryError>),
+    // scores for each query of a batch
+    BatchScores(Result<BatchTop, QueryError>),
+    // a freshly loaded model to serve under the name from now on
+    Swap(String, Arc<word2vec::Model>),
     Exit,
 }
 
+// the words and scores for each query of a batch, None for unknown words
+type BatchTop = Vec<Option<Vec<(String, f32)>>>;
+
+#[derive(Clone, Debug)]
+pub enum Target {
+    Word(String),
+    Vector(Vec<f32>),
+}
+
+#[derive(Clone, Debug)]
+pub enum Targets {
+    Words(Vec<String>),
+    Vectors(Vec<Vec<f32>>),
+}
+
+// Why the inference thread couldn't answer a query
+#[derive(Clone, Debug)]
+pub enum QueryError {
+    UnknownModel(String),
+    UnknownWords(Vec<String>),
+    // something wrong with the request itself
+    Invalid(String),
+    Failed(String),
+}
+
+impl From<word2vec::W2VError> for QueryError {
+    fn from(error: word2vec::W2VError) -> QueryError {
+        match error {
+            word2vec::W2VError::UnknownWords(words) => QueryError::UnknownWords(words),
+            error => QueryError::Failed(format!("{:?}", error)),
+        }
+    }
+}
+
+fn check_dimension(model: &word2vec::Model, vector: &[f32]) -> Result<(), QueryError> {
+    if vector.len() == model.size {
+        Ok(())
+    } else {
+        Err(QueryError::Invalid(format!("expected a vector of {} values, not {}", model.size, vector.len())))
+    }
+}
+
 #[derive(Clone)]
 pub struct Comm<T> {
     sender:Sender<T>,
@@ -69,40 +326,145 @@ impl<T> Comm<T> {
     pub fn send(&self, item: T) -> Result<(),SendError<T>> {
         self.sender.send(item)
     }
-    fn recv(&self) -> Result<T,RecvError> {
-        self.receiver.recv()
-    }
-
     fn iter(&self) -> Iter<'_,T> {
         self.receiver.iter()
     }
 }
 
+// Loads models again while the old ones keep serving, and hands each one to
+// the inference thread once it has loaded. A load that fails leaves the old
+// model in place.
+#[derive(Clone)]
+struct Reloader {
+    comm:          Comm<ThreadComm>,
+    options:       word2vec::LoadOptions,
+    // name -> where the model was last loaded from
+    sources:       Arc<Mutex<HashMap<String,PathBuf>>>,
+    // in the order given on the command line, the first is served on /convert
+    infos:         Arc<Mutex<Vec<ModelInfo>>>,
+    // one reload at a time, so there are never more than two copies of a
+    // model in memory
+    busy:          Arc<Mutex<()>>,
+}
+
+impl Reloader {
+    fn contains(&self, name: &str) -> bool {
+        self.sources.lock().unwrap().contains_key(name)
+    }
+
+    fn reload(&self, name: &str, path: Option<PathBuf>) -> Result<ModelInfo, String> {
+        let _busy = self.busy.lock().unwrap();
+        let path = match (path, self.sources.lock().unwrap().get(name)) {
+            (_, None) => return Err(format!("unknown model: {}", name)),
+            (Some(path), Some(_)) => path,
+            (None, Some(source)) => source.clone(),
+        };
+        if path.as_os_str() == "-" {
+            return Err(String::from("a model read from stdin can't be reloaded"));
+        }
+        println!("Reloading model {} from {}", name, path.display());
+        let model = match word2vec::Model::with_options(path.clone(), &self.options) {
+            Ok(model) => model,
+            Err(reason) => return Err(format!("{:?}", reason)),
+        };
+        let info = ModelInfo {
+            name: name.to_string(),
+            words: model.total_words,
+            dimension: model.size,
+        };
+        if self.comm.send(ThreadComm::Swap(name.to_string(), Arc::new(model))).is_err() {
+            return Err(String::from("the inference server has stopped"));
+        }
+        self.sources.lock().unwrap().insert(name.to_string(), path);
+        for entry in self.infos.lock().unwrap().iter_mut().filter(|entry| entry.name == name) {
+            *entry = info.clone();
+        }
+        Ok(info)
+    }
+
+    // every model, from where it was last loaded from
+    fn reload_all(&self) {
+        let names: Vec<String> = self.infos.lock().unwrap().iter().map(|info| info.name.clone()).collect();
+        for name in names {
+            match self.reload(&name, None) {
+                Ok(info) => println!("Reloaded model {}\nwords:{}\nvector size:{}\n", name, info.words, info.dimension),
+                Err(reason) => println!("Could not reload model {}, still serving the old one:\n\t{}", name, reason),
+            }
+        }
+    }
+}
+
+// Expects `Bearer <token>`. Compares in constant time, so the token can't be
+// worked out a byte at a time from response times.
+fn authorized(header: Option<&str>, token: &str) -> bool {
+    let given = match header.and_then(|header| header.strip_prefix("Bearer ")) {
+        Some(given) => given.as_bytes(),
+        None => return false,
+    };
+    given.len() == token.len() && given.iter().zip(token.as_bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
 pub struct Server {
     comm_tx:       Comm<ThreadComm>,
     comm_rx:       Comm<ThreadComm>,
-    model:         Arc<Mutex<word2vec::Model>>,
+    models:        Arc<Mutex<HashMap<String,Arc<word2vec::Model>>>>,
+    reloader:      Reloader,
+    // POST /admin/reload is only served when this is set
+    admin_token:   Option<String>,
     pool:          ThreadPool,
 } 
 
 impl Server {
-    pub fn init(model_path: PathBuf) -> Option<Server> {
-        print!("Loading model... ");
-        let model = match word2vec::Model::new(model_path) {
-            Ok(model_str) => model_str,
-            Err(reason) => {
-                println!("{:?}",reason);
+    /// Loads each `(name, path)` pair. Every model is served under
+    /// /models/{name}/, and the first one on /convert as well. Models are
+    /// reloaded from the same paths on SIGHUP, and with an `admin_token`
+    /// they can also be reloaded over HTTP.
+    pub fn init(model_paths: Vec<(String,PathBuf)>, options: word2vec::LoadOptions, admin_token: Option<String>) -> Option<Server> {
+        let mut models: HashMap<String,Arc<word2vec::Model>> = HashMap::with_capacity(model_paths.len());
+        let mut sources: HashMap<String,PathBuf> = HashMap::with_capacity(model_paths.len());
+        let mut infos: Vec<ModelInfo> = Vec::with_capacity(model_paths.len());
+        for (name, model_path) in model_paths {
+            if models.contains_key(&name) {
+                println!("Model name {} is used more than once", name);
                 return None;
-            }, 
-        };
-        println!("Done");
-        println!("words:{}\nvector size:{}\n",model.total_words, model.size);
+            }
+            print!("Loading model {}... ", name);
+            let model = match word2vec::Model::with_options(model_path.clone(), &options) {
+                Ok(model_str) => model_str,
+                Err(reason) => {
+                    println!("{:?}",reason);
+                    return None;
+                }, 
+            };
+            println!("Done");
+            println!("words:{}\nvector size:{}\n",model.total_words, model.size);
+            infos.push(ModelInfo {
+                name: name.clone(),
+                words: model.total_words,
+                dimension: model.size,
+            });
+            sources.insert(name.clone(), model_path);
+            models.insert(name, Arc::new(model));
+        }
+        if infos.is_empty() {
+            println!("No models to serve");
+            return None;
+        }
         let pool =  ThreadPool::new(1); // one for the model, one for the server
         let (comm_tx,comm_rx):(Comm<ThreadComm>,Comm<ThreadComm>) = Comm::new();
+        let reloader = Reloader {
+            comm: comm_tx.clone(),
+            options,
+            sources: Arc::new(Mutex::new(sources)),
+            infos: Arc::new(Mutex::new(infos)),
+            busy: Arc::new(Mutex::new(())),
+        };
         Some(Server {
             comm_tx,
             comm_rx,
-            model:Arc::new(Mutex::new(model)),
+            models:Arc::new(Mutex::new(models)),
+            reloader,
+            admin_token,
             pool,
         })
     }
@@ -110,14 +472,16 @@ impl Server {
     pub fn begin(&mut self, port: u16){
         let (http_shutdown_tx, http_server_shutdown_rx): (oneshot::Sender<()>, oneshot::Receiver<()>) = oneshot::channel();
         let comm_b =  self.comm_tx.clone();
+        let reloader = self.reloader.clone();
+        let admin_token = self.admin_token.clone();
 
         self.pool.execute(move || {
             let mut rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(Self::serve(comm_b,port, http_server_shutdown_rx));
+            rt.block_on(Self::serve(comm_b, reloader, admin_token, port, http_server_shutdown_rx));
         });
-        let local_model = self.model.clone();
+        let local_models = self.models.clone();
 
-        self.infer(local_model);
+        self.infer(local_models);
             // after killing 
         http_shutdown_tx.send(()).unwrap();
     }
@@ -126,25 +490,115 @@ impl Server {
         self.comm_tx.clone()
     }
 
-    fn infer(&self, model: Arc<Mutex<word2vec::Model>>) {
+    fn infer(&self, models: Arc<Mutex<HashMap<String,Arc<word2vec::Model>>>>) {
         println!("starting inference server");
-        // get model out of the Arc/Mutex
-        let model = model.as_ref().lock().unwrap();
+        // get models out of the Arc/Mutex
+        let mut models = models.as_ref().lock().unwrap();
         for message in self.comm_rx.iter() {
             match message {
-                ThreadComm::Word2Vec(word) => {
-                    let return_message = match model.word2vec(&word) {
-                        Some(vector) => {
-                            Some((*vector).clone())
-                        }
-                        None => {
-                            None
-                        }
+                ThreadComm::Convert(name, words, subwords, reply) => {
+                    let vectors = match models.get(&name) {
+                        Some(model) => words.iter().map(|word| {
+                            if subwords {
+                                match model.word2vec_subwords(word) {
+                                    Some((vector, synthesized)) => (Some(vector.into_owned()), synthesized),
+                                    None => (None, false),
+                                }
+                            } else {
+                                (model.word2vec(word).map(|vector| vector.to_vec()), false)
+                            }
+                        }).collect(),
+                        None => vec![(None, false); words.len()],
+                    };
+                    if let Err(reason) = reply.send(ThreadComm::Vectors(vectors)) {
+                        println!("Warning, could not send message because:\n\t{}",reason);
+                    }
+                },
+                ThreadComm::Similar(name, target, k, metric, search, reply) => {
+                    let scores = match (models.get(&name), target, search) {
+                        (None, _, _) => Err(QueryError::UnknownModel(name)),
+                        (Some(model), Target::Word(word), word2vec::Search::Exact) => model.most_similar_by(&word, k, metric).ok_or_else(|| QueryError::UnknownWords(vec![word])),
+                        (Some(model), Target::Vector(vector), word2vec::Search::Exact) => check_dimension(model, &vector).map(|_| model.most_similar_vec_by(&vector, k, metric)),
+                        // the indexes only rank by cosine, which similar() checks
+                        (Some(model), Target::Word(word), search) => model.search(&word, k, search).ok_or_else(|| QueryError::UnknownWords(vec![word])),
+                        (Some(model), Target::Vector(vector), search) => check_dimension(model, &vector).map(|_| model.search_vec(&vector, k, search)),
+                    };
+                    if let Err(reason) = reply.send(ThreadComm::Scores(scores)) {
+                        println!("Warning, could not send message because:\n\t{}",reason);
+                    }
+                },
+                ThreadComm::Within(name, target, threshold, limit, metric, reply) => {
+                    let scores = match (models.get(&name), target) {
+                        (None, _) => Err(QueryError::UnknownModel(name)),
+                        (Some(model), Target::Word(word)) => model.within(&word, threshold, metric, Some(limit)).ok_or_else(|| QueryError::UnknownWords(vec![word])),
+                        (Some(model), Target::Vector(vector)) => check_dimension(model, &vector).map(|_| model.within_vec(&vector, threshold, metric, Some(limit))),
+                    };
+                    if let Err(reason) = reply.send(ThreadComm::Scores(scores)) {
+                        println!("Warning, could not send message because:\n\t{}",reason);
+                    }
+                },
+                ThreadComm::DoesntMatch(name, words, reply) => {
+                    let ranked = match models.get(&name) {
+                        Some(model) => {
+                            let words: Vec<&str> = words.iter().map(String::as_str).collect();
+                            Ok(model.doesnt_match(&words))
+                        },
+                        None => Err(QueryError::UnknownModel(name)),
+                    };
+                    if let Err(reason) = reply.send(ThreadComm::Ranked(ranked)) {
+                        println!("Warning, could not send message because:\n\t{}",reason);
+                    }
+                },
+                ThreadComm::Sets(name, set_a, set_b, pairwise, reply) => {
+                    let set_a: Vec<&str> = set_a.iter().map(String::as_str).collect();
+                    let set_b: Vec<&str> = set_b.iter().map(String::as_str).collect();
+                    let answer = match (models.get(&name), pairwise) {
+                        (None, false) => ThreadComm::SetSimilarity(Err(QueryError::UnknownModel(name))),
+                        (None, true) => ThreadComm::Matrix(Err(QueryError::UnknownModel(name))),
+                        (Some(model), false) => ThreadComm::SetSimilarity(Ok(model.n_similarity(&set_a, &set_b))),
+                        (Some(model), true) => ThreadComm::Matrix(Ok(model.similarity_matrix(&set_a, &set_b))),
+                    };
+                    if let Err(reason) = reply.send(answer) {
+                        println!("Warning, could not send message because:\n\t{}",reason);
+                    }
+                },
+                ThreadComm::SimilarBatch(name, targets, k, metric, reply) => {
+                    let scores = match (models.get(&name), targets) {
+                        (None, _) => Err(QueryError::UnknownModel(name)),
+                        (Some(model), Targets::Words(words)) => {
+                            let words: Vec<&str> = words.iter().map(String::as_str).collect();
+                            Ok(model.most_similar_batch(&words, k, metric))
+                        },
+                        (Some(model), Targets::Vectors(vectors)) => match vectors.iter().find(|vector| vector.len() != model.size) {
+                            Some(vector) => Err(QueryError::Invalid(format!("expected vectors of {} values, not {}", model.size, vector.len()))),
+                            None => {
+                                let vectors: Vec<&[f32]> = vectors.iter().map(Vec::as_slice).collect();
+                                Ok(model.most_similar_vec_batch(&vectors, k, metric))
+                            },
+                        },
                     };
-                    if let Err(reason) = self.comm_rx.send(ThreadComm::WordVec(return_message)) {
+                    if let Err(reason) = reply.send(ThreadComm::BatchScores(scores)) {
                         println!("Warning, could not send message because:\n\t{}",reason);
                     }
                 },
+                ThreadComm::Analogy(name, positive, negative, k, objective, reply) => {
+                    let scores = match models.get(&name) {
+                        Some(model) => {
+                            let positive: Vec<&str> = positive.iter().map(String::as_str).collect();
+                            let negative: Vec<&str> = negative.iter().map(String::as_str).collect();
+                            model.analogy(&positive, &negative, k, objective).map_err(QueryError::from)
+                        },
+                        None => Err(QueryError::UnknownModel(name)),
+                    };
+                    if let Err(reason) = reply.send(ThreadComm::Scores(scores)) {
+                        println!("Warning, could not send message because:\n\t{}",reason);
+                    }
+                },
+                ThreadComm::Swap(name, model) => {
+                    // the old model is dropped here, after every request
+                    // that arrived before the swap has been answered
+                    models.insert(name, model);
+                },
                 ThreadComm::Exit => {
                     break;
                 },
@@ -157,35 +611,438 @@ impl Server {
         println!("Exiting inference server");
     }
     
-    async fn serve(comm:Comm<ThreadComm>, port: u16, shutdown_rx: oneshot::Receiver<()>) {
+    async fn serve(comm:Comm<ThreadComm>, reloader: Reloader, admin_token: Option<String>, port: u16, shutdown_rx: oneshot::Receiver<()>) {
         let socket = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0,0,0,0),port));
         println!("starting HTTP server on: {}",socket);
+        #[cfg(unix)]
+        {
+            let reloader = reloader.clone();
+            tokio::spawn(async move {
+                let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                    Ok(hangups) => hangups,
+                    Err(reason) => {
+                        println!("Warning, models won't reload on SIGHUP because:\n\t{}",reason);
+                        return;
+                    },
+                };
+                while hangups.recv().await.is_some() {
+                    println!("Received SIGHUP, reloading models...");
+                    let reloader = reloader.clone();
+                    if tokio::task::spawn_blocking(move || reloader.reload_all()).await.is_err() {
+                        println!("Warning, reloading models panicked");
+                    }
+                }
+            });
+        }
+        let (_addr, server) = warp::serve(Self::routes(comm, reloader, admin_token)).bind_with_graceful_shutdown(socket, async {shutdown_rx.await.ok(); });
+        server.await;
+        println!("Exiting HTTP server");
+    }
+
+    fn routes(comm:Comm<ThreadComm>, reloader: Reloader, admin_token: Option<String>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let default_model = reloader.infos.lock().unwrap()[0].name.clone();
+        let convert_comm = comm.clone();
+        let convert_default = default_model.clone();
         let convert = warp::get()
             .and(warp::path("convert"))
             // Only accept bodies smaller than 1Mb...
             .and(warp::body::content_length_limit(1024*1024))
             .and(warp::body::json())
-            .map(move |payload: ConvertPayload| {
-                let mut response_map: HashMap<String,Option<Vec<f32>>> = HashMap::with_capacity(payload.words.len());
-                for word in payload.words.iter() {
-                    let s: String = (*word).clone();
-                    if let Err(reason) = comm.send(ThreadComm::Word2Vec(s.clone())) {
-                        println!("I errored bc:\n\t{}",reason);
+            .and_then(move |payload: ConvertPayload| Self::convert(convert_comm.clone(), convert_default.clone(), payload));
+        let analogy_comm = comm.clone();
+        let analogy_default = default_model.clone();
+        let analogy = warp::get()
+            .and(warp::path!("analogy"))
+            .and(warp::body::content_length_limit(1024*1024))
+            .and(warp::body::json())
+            .and_then(move |payload: AnalogyPayload| Self::analogy(analogy_comm.clone(), analogy_default.clone(), payload));
+        let similar_comm = comm.clone();
+        let similar_default = default_model.clone();
+        let similar = warp::get()
+            .and(warp::path!("most_similar"))
+            .and(metric_query())
+            .and(warp::body::content_length_limit(1024*1024))
+            .and(warp::body::json())
+            .and_then(move |query: MetricQuery, payload: SimilarPayload| Self::similar(similar_comm.clone(), similar_default.clone(), query, payload));
+        let within_comm = comm.clone();
+        let within_default = default_model.clone();
+        let within = warp::get()
+            .and(warp::path!("within"))
+            .and(metric_query())
+            .and(warp::body::content_length_limit(1024*1024))
+            .and(warp::body::json())
+            .and_then(move |query: MetricQuery, payload: WithinPayload| Self::within(within_comm.clone(), within_default.clone(), query, payload));
+        let odd_comm = comm.clone();
+        let odd_default = default_model.clone();
+        let odd = warp::get()
+            .and(warp::path!("doesnt_match"))
+            .and(warp::body::content_length_limit(1024*1024))
+            .and(warp::body::json())
+            .and_then(move |payload: WordsPayload| Self::doesnt_match(odd_comm.clone(), odd_default.clone(), payload));
+        let sets_comm = comm.clone();
+        let sets_default = default_model.clone();
+        let sets = warp::get()
+            .and(warp::path!("n_similarity"))
+            .and(warp::body::content_length_limit(1024*1024))
+            .and(warp::body::json())
+            .and_then(move |payload: SetsPayload| Self::n_similarity(sets_comm.clone(), sets_default.clone(), payload));
+        let batch_comm = comm.clone();
+        let batch_default = default_model;
+        let batch = warp::get()
+            .and(warp::path!("most_similar_batch"))
+            .and(metric_query())
+            .and(warp::body::content_length_limit(16*1024*1024))
+            .and(warp::body::json())
+            .and_then(move |query: MetricQuery, payload: BatchPayload| Self::similar_batch(batch_comm.clone(), batch_default.clone(), query, payload));
+        let list_reloader = reloader.clone();
+        let list = warp::get()
+            .and(warp::path("models"))
+            .and(warp::path::end())
+            .map(move || {
+                let models = list_reloader.infos.lock().unwrap().clone();
+                warp::reply::json(&ModelsResponse { models })
+            });
+        let convert_reloader = reloader.clone();
+        let convert_comm = comm.clone();
+        let model_convert = warp::get()
+            .and(warp::path!("models" / String / "convert"))
+            .and(warp::body::content_length_limit(1024*1024))
+            .and(warp::body::json())
+            .and_then(move |name: String, payload: ConvertPayload| {
+                let known = convert_reloader.contains(&name);
+                let comm = convert_comm.clone();
+                async move {
+                    if !known {
+                        return Ok(Self::unknown_model(&name));
                     }
-                    if let ThreadComm::WordVec(vec_response_opt) = comm.recv().unwrap() {
-                        response_map.insert(s, vec_response_opt);
-                    } else {
-                        response_map.insert(s, None);
+                    Self::convert(comm, name, payload).await
+                }
+            });
+        let similar_reloader = reloader.clone();
+        let similar_comm = comm.clone();
+        let model_similar = warp::get()
+            .and(warp::path!("models" / String / "most_similar"))
+            .and(metric_query())
+            .and(warp::body::content_length_limit(1024*1024))
+            .and(warp::body::json())
+            .and_then(move |name: String, query: MetricQuery, payload: SimilarPayload| {
+                let known = similar_reloader.contains(&name);
+                let comm = similar_comm.clone();
+                async move {
+                    if !known {
+                        return Ok(Self::unknown_model(&name));
                     }
+                    Self::similar(comm, name, query, payload).await
                 }
-                warp::reply::json(&ConvertResponse {
-                    data: response_map,
-                })
             });
-    
-            let (_addr, server) = warp::serve(convert).bind_with_graceful_shutdown(socket, async {shutdown_rx.await.ok(); });
-            server.await;
-            println!("Exiting HTTP server");
+        let within_reloader = reloader.clone();
+        let within_comm = comm.clone();
+        let model_within = warp::get()
+            .and(warp::path!("models" / String / "within"))
+            .and(metric_query())
+            .and(warp::body::content_length_limit(1024*1024))
+            .and(warp::body::json())
+            .and_then(move |name: String, query: MetricQuery, payload: WithinPayload| {
+                let known = within_reloader.contains(&name);
+                let comm = within_comm.clone();
+                async move {
+                    if !known {
+                        return Ok(Self::unknown_model(&name));
+                    }
+                    Self::within(comm, name, query, payload).await
+                }
+            });
+        let odd_reloader = reloader.clone();
+        let odd_comm = comm.clone();
+        let model_odd = warp::get()
+            .and(warp::path!("models" / String / "doesnt_match"))
+            .and(warp::body::content_length_limit(1024*1024))
+            .and(warp::body::json())
+            .and_then(move |name: String, payload: WordsPayload| {
+                let known = odd_reloader.contains(&name);
+                let comm = odd_comm.clone();
+                async move {
+                    if !known {
+                        return Ok(Self::unknown_model(&name));
+                    }
+                    Self::doesnt_match(comm, name, payload).await
+                }
+            });
+        let sets_reloader = reloader.clone();
+        let sets_comm = comm.clone();
+        let model_sets = warp::get()
+            .and(warp::path!("models" / String / "n_similarity"))
+            .and(warp::body::content_length_limit(1024*1024))
+            .and(warp::body::json())
+            .and_then(move |name: String, payload: SetsPayload| {
+                let known = sets_reloader.contains(&name);
+                let comm = sets_comm.clone();
+                async move {
+                    if !known {
+                        return Ok(Self::unknown_model(&name));
+                    }
+                    Self::n_similarity(comm, name, payload).await
+                }
+            });
+        let batch_reloader = reloader.clone();
+        let batch_comm = comm.clone();
+        let model_batch = warp::get()
+            .and(warp::path!("models" / String / "most_similar_batch"))
+            .and(metric_query())
+            .and(warp::body::content_length_limit(16*1024*1024))
+            .and(warp::body::json())
+            .and_then(move |name: String, query: MetricQuery, payload: BatchPayload| {
+                let known = batch_reloader.contains(&name);
+                let comm = batch_comm.clone();
+                async move {
+                    if !known {
+                        return Ok(Self::unknown_model(&name));
+                    }
+                    Self::similar_batch(comm, name, query, payload).await
+                }
+            });
+        let analogy_reloader = reloader.clone();
+        let model_analogy = warp::get()
+            .and(warp::path!("models" / String / "analogy"))
+            .and(warp::body::content_length_limit(1024*1024))
+            .and(warp::body::json())
+            .and_then(move |name: String, payload: AnalogyPayload| {
+                let known = analogy_reloader.contains(&name);
+                let comm = comm.clone();
+                async move {
+                    if !known {
+                        return Ok(Self::unknown_model(&name));
+                    }
+                    Self::analogy(comm, name, payload).await
+                }
+            });
+        let reload = warp::post()
+            .and(warp::path!("admin" / "reload"))
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::body::content_length_limit(16*1024))
+            .and(warp::body::json())
+            .and_then(move |authorization: Option<String>, payload: ReloadPayload| {
+                let reloader = reloader.clone();
+                let admin_token = admin_token.clone();
+                async move {
+                    Ok::<_, warp::Rejection>(Self::reload(reloader, admin_token, authorization, payload).await)
+                }
+            });
+        convert.or(similar).or(within).or(batch).or(analogy).or(odd).or(sets).or(list)
+            .or(model_convert).or(model_similar).or(model_within).or(model_batch).or(model_analogy).or(model_odd).or(model_sets)
+            .or(reload)
+    }
+
+    fn unknown_model(name: &str) -> warp::reply::Response {
+        let error = ErrorResponse { error: format!("unknown model: {}", name) };
+        warp::reply::with_status(warp::reply::json(&error), StatusCode::NOT_FOUND).into_response()
+    }
+
+    // Looks up each word of the payload in the named model
+    async fn convert(comm: Comm<ThreadComm>, name: String, payload: ConvertPayload) -> Result<warp::reply::Response, Infallible> {
+        let mut response_map: HashMap<String,Option<Vec<f32>>> = HashMap::with_capacity(payload.words.len());
+        let mut synthesized: Vec<String> = Vec::new();
+        let words = payload.words.clone();
+        let mut vectors = match Self::ask(comm, |reply| ThreadComm::Convert(name, words, payload.subwords, reply)).await {
+            Ok(ThreadComm::Vectors(vectors)) => vectors.into_iter(),
+            Ok(_) => Vec::new().into_iter(),
+            Err(response) => return Ok(response),
+        };
+        for word in payload.words {
+            let (vector, is_synthesized) = vectors.next().unwrap_or((None, false));
+            if is_synthesized {
+                synthesized.push(word.clone());
+            }
+            response_map.insert(word, vector);
+        }
+        Ok(warp::reply::json(&ConvertResponse {
+            data: response_map,
+            synthesized,
+        }).into_response())
+    }
+
+    fn error(status: StatusCode, error: String) -> warp::reply::Response {
+        warp::reply::with_status(warp::reply::json(&ErrorResponse { error }), status).into_response()
+    }
+
+    // ?metric=, cosine if it's not given. The helpers below return why the
+    // request is bad, for the handler to answer with.
+    fn metric(query: &MetricQuery) -> Result<word2vec::Metric, String> {
+        query.metric.as_deref().unwrap_or("cosine").parse::<word2vec::Metric>()
+    }
+
+    fn target(word: Option<String>, vector: Option<Vec<f32>>) -> Result<Target, String> {
+        match (word, vector) {
+            (Some(word), None) => Ok(Target::Word(word)),
+            (None, Some(vector)) => Ok(Target::Vector(vector)),
+            _ => Err(String::from("expected either a word or a vector")),
+        }
+    }
+
+    // ?search=, exact if it's not given
+    fn search(query: &MetricQuery) -> Result<word2vec::Search, String> {
+        query.search.as_deref().unwrap_or("exact").parse::<word2vec::Search>()
+    }
+
+    // The words in the named model closest to the payload's word or vector
+    async fn similar(comm: Comm<ThreadComm>, name: String, query: MetricQuery, payload: SimilarPayload) -> Result<warp::reply::Response, Infallible> {
+        let (metric, search, target) = match (Self::metric(&query), Self::search(&query), Self::target(payload.word, payload.vector)) {
+            (Ok(metric), Ok(search), Ok(target)) => (metric, search, target),
+            (Err(reason), _, _) | (_, Err(reason), _) | (_, _, Err(reason)) => return Ok(Self::error(StatusCode::BAD_REQUEST, reason)),
+        };
+        if search == word2vec::Search::Approximate && metric != word2vec::Metric::Cosine {
+            return Ok(Self::error(StatusCode::BAD_REQUEST, String::from("approximate search only ranks by cosine")));
+        }
+        let k = payload.k;
+        Ok(Self::query(comm, |reply| ThreadComm::Similar(name, target, k, metric, search, reply)).await)
+    }
+
+    // The closest words in the named model to each of the payload's words or
+    // vectors
+    async fn similar_batch(comm: Comm<ThreadComm>, name: String, query: MetricQuery, payload: BatchPayload) -> Result<warp::reply::Response, Infallible> {
+        let metric = match Self::metric(&query) {
+            Ok(metric) => metric,
+            Err(reason) => return Ok(Self::error(StatusCode::BAD_REQUEST, reason)),
+        };
+        let (targets, words) = match (payload.words, payload.vectors) {
+            (Some(words), None) => (Targets::Words(words.clone()), words),
+            (None, Some(vectors)) => (Targets::Vectors(vectors), Vec::new()),
+            _ => return Ok(Self::error(StatusCode::BAD_REQUEST, String::from("expected either words or vectors"))),
+        };
+        let k = payload.k;
+        Ok(match Self::ask(comm, |reply| ThreadComm::SimilarBatch(name, targets, k, metric, reply)).await {
+            Ok(ThreadComm::BatchScores(Ok(batch))) => {
+                let unknown = words.into_iter().zip(batch.iter()).filter(|(_, top)| top.is_none()).map(|(word, _)| word).collect();
+                let data = batch.into_iter().map(|top| top.map(scored)).collect();
+                warp::reply::json(&BatchResponse { data, unknown }).into_response()
+            },
+            Ok(ThreadComm::BatchScores(Err(error))) => Self::query_error(error),
+            Ok(_) => Self::error(StatusCode::INTERNAL_SERVER_ERROR, String::from("unexpected answer from the inference server")),
+            Err(response) => response,
+        })
+    }
+
+    // Every word in the named model within the payload's threshold of its
+    // word or vector, closest first and no more than its limit
+    async fn within(comm: Comm<ThreadComm>, name: String, query: MetricQuery, payload: WithinPayload) -> Result<warp::reply::Response, Infallible> {
+        let (metric, target) = match (Self::metric(&query), Self::target(payload.word, payload.vector)) {
+            (Ok(metric), Ok(target)) => (metric, target),
+            (Err(reason), _) | (_, Err(reason)) => return Ok(Self::error(StatusCode::BAD_REQUEST, reason)),
+        };
+        // one past the limit shows whether there were more
+        let (threshold, limit) = (payload.threshold, payload.limit.saturating_add(1));
+        Ok(match Self::ask(comm, |reply| ThreadComm::Within(name, target, threshold, limit, metric, reply)).await {
+            Ok(ThreadComm::Scores(Ok(mut scores))) => {
+                let truncated = scores.len() > payload.limit;
+                scores.truncate(payload.limit);
+                warp::reply::json(&WithinResponse { data: scored(scores), truncated }).into_response()
+            },
+            Ok(ThreadComm::Scores(Err(error))) => Self::query_error(error),
+            Ok(_) => Self::error(StatusCode::INTERNAL_SERVER_ERROR, String::from("unexpected answer from the inference server")),
+            Err(response) => response,
+        })
+    }
+
+    // The words that best complete the payload's analogy in the named model
+    async fn analogy(comm: Comm<ThreadComm>, name: String, payload: AnalogyPayload) -> Result<warp::reply::Response, Infallible> {
+        let objective = match payload.objective.as_deref().unwrap_or("add").parse::<word2vec::Analogy>() {
+            Ok(objective) => objective,
+            Err(reason) => return Ok(Self::error(StatusCode::BAD_REQUEST, reason)),
+        };
+        Ok(Self::query(comm, |reply| ThreadComm::Analogy(name, payload.positive, payload.negative, payload.k, objective, reply)).await)
+    }
+
+    // The payload's words ranked from most to least out of place in the
+    // named model
+    async fn doesnt_match(comm: Comm<ThreadComm>, name: String, payload: WordsPayload) -> Result<warp::reply::Response, Infallible> {
+        Ok(match Self::ask(comm, |reply| ThreadComm::DoesntMatch(name, payload.words, reply)).await {
+            Ok(ThreadComm::Ranked(Ok(ranked))) => warp::reply::json(&RankedResponse {
+                data: scored(ranked.ranked),
+                unknown: ranked.unknown,
+            }).into_response(),
+            Ok(ThreadComm::Ranked(Err(error))) => Self::query_error(error),
+            Ok(_) => Self::error(StatusCode::INTERNAL_SERVER_ERROR, String::from("unexpected answer from the inference server")),
+            Err(response) => response,
+        })
+    }
+
+    // How alike the payload's two sets of words are in the named model, as a
+    // whole or word by word
+    async fn n_similarity(comm: Comm<ThreadComm>, name: String, payload: SetsPayload) -> Result<warp::reply::Response, Infallible> {
+        Ok(match Self::ask(comm, |reply| ThreadComm::Sets(name, payload.set_a, payload.set_b, payload.pairwise, reply)).await {
+            Ok(ThreadComm::SetSimilarity(Ok(sets))) => warp::reply::json(&SetSimilarityResponse {
+                similarity: sets.similarity,
+                unknown_a: sets.unknown_a,
+                unknown_b: sets.unknown_b,
+            }).into_response(),
+            Ok(ThreadComm::Matrix(Ok(matrix))) => warp::reply::json(&MatrixResponse {
+                rows: matrix.rows,
+                columns: matrix.columns,
+                data: matrix.values,
+                unknown_a: matrix.unknown_a,
+                unknown_b: matrix.unknown_b,
+            }).into_response(),
+            Ok(ThreadComm::SetSimilarity(Err(error))) | Ok(ThreadComm::Matrix(Err(error))) => Self::query_error(error),
+            Ok(_) => Self::error(StatusCode::INTERNAL_SERVER_ERROR, String::from("unexpected answer from the inference server")),
+            Err(response) => response,
+        })
+    }
+
+    // Hands a query to the inference thread along with a channel of its own
+    // for the answer, and waits for it on a blocking thread so the server's
+    // workers stay free for other requests
+    async fn ask(comm: Comm<ThreadComm>, query: impl FnOnce(Sender<ThreadComm>) -> ThreadComm) -> Result<ThreadComm, warp::reply::Response> {
+        let (reply, answer) = bounded(1);
+        if let Err(reason) = comm.send(query(reply)) {
+            println!("I errored bc:\n\t{}",reason);
+        }
+        match tokio::task::spawn_blocking(move || answer.recv()).await {
+            Ok(Ok(answer)) => Ok(answer),
+            _ => Err(Self::error(StatusCode::INTERNAL_SERVER_ERROR, String::from("the inference server has stopped"))),
+        }
+    }
+
+    // Hands a query to the inference thread and answers with its scores
+    async fn query(comm: Comm<ThreadComm>, query: impl FnOnce(Sender<ThreadComm>) -> ThreadComm) -> warp::reply::Response {
+        match Self::ask(comm, query).await {
+            Ok(ThreadComm::Scores(Ok(scores))) => warp::reply::json(&ScoresResponse { data: scored(scores) }).into_response(),
+            Ok(ThreadComm::Scores(Err(error))) => Self::query_error(error),
+            Ok(_) => Self::error(StatusCode::INTERNAL_SERVER_ERROR, String::from("unexpected answer from the inference server")),
+            Err(response) => response,
+        }
+    }
+
+    fn query_error(error: QueryError) -> warp::reply::Response {
+        match error {
+            QueryError::UnknownWords(unknown) => {
+                let response = UnknownWordsResponse { error: String::from("unknown words"), unknown };
+                warp::reply::with_status(warp::reply::json(&response), StatusCode::BAD_REQUEST).into_response()
+            },
+            QueryError::UnknownModel(name) => Self::unknown_model(&name),
+            QueryError::Invalid(reason) => Self::error(StatusCode::BAD_REQUEST, reason),
+            QueryError::Failed(reason) => Self::error(StatusCode::INTERNAL_SERVER_ERROR, reason),
+        }
+    }
+
+    // Loads on a blocking thread, so requests keep being served meanwhile,
+    // and answers once the new model has been handed over or has failed
+    async fn reload(reloader: Reloader, admin_token: Option<String>, authorization: Option<String>, payload: ReloadPayload) -> warp::reply::WithStatus<warp::reply::Json> {
+        let error = |status: StatusCode, error: String| warp::reply::with_status(warp::reply::json(&ErrorResponse { error }), status);
+        let token = match admin_token {
+            Some(token) => token,
+            None => return error(StatusCode::NOT_FOUND, String::from("reloading over HTTP is disabled, start the server with --admin-token")),
+        };
+        if !authorized(authorization.as_deref(), &token) {
+            return error(StatusCode::UNAUTHORIZED, String::from("missing or wrong admin token"));
+        }
+        if !reloader.contains(&payload.model) {
+            return error(StatusCode::NOT_FOUND, format!("unknown model: {}", payload.model));
+        }
+        match tokio::task::spawn_blocking(move || reloader.reload(&payload.model, payload.path)).await {
+            Ok(Ok(info)) => warp::reply::with_status(warp::reply::json(&info), StatusCode::OK),
+            Ok(Err(reason)) => error(StatusCode::INTERNAL_SERVER_ERROR, reason),
+            Err(_) => error(StatusCode::INTERNAL_SERVER_ERROR, String::from("reloading the model panicked")),
+        }
     }
 }
 
@@ -196,17 +1053,277 @@ impl Server {
 #[cfg(test)]
 mod test {
     use super::*;
+
+    fn write_model(name: &str, words: &[(&str, Vec<f32>)]) -> PathBuf {
+        let model_path = std::env::temp_dir().join(format!("w2v_server_test_{}_{}.bin", std::process::id(), name));
+        std::fs::write(&model_path, word2vec::test::write_binary_bytes(words)).unwrap();
+        model_path
+    }
+
+    fn body<B: AsRef<[u8]>>(response: &warp::http::Response<B>) -> String {
+        String::from_utf8(response.body().as_ref().to_vec()).unwrap()
+    }
+
+    // Serves the models and sends every (path, body) GET request at once, so
+    // they all wait on the inference thread together. The responses come
+    // back in the order the requests were given.
+    fn serve(model_paths: Vec<(String, PathBuf)>, requests: &[(&str, &str)]) -> Vec<warp::http::Response<warp::hyper::body::Bytes>> {
+        let serv = Server::init(model_paths, word2vec::LoadOptions::default(), None).unwrap();
+        let comm = serv.get_shutdown_tx();
+        let reloader = serv.reloader.clone();
+        let requests: Vec<(String, String)> = requests.iter().map(|(path, body)| (path.to_string(), body.to_string())).collect();
+        let client = std::thread::spawn(move || {
+            let routes = Server::routes(comm.clone(), reloader, None);
+            let mut rt = tokio::runtime::Runtime::new().unwrap();
+            let responses = rt.block_on(async move {
+                let pending: Vec<_> = requests.into_iter().map(|(path, body)| {
+                    let routes = routes.clone();
+                    tokio::spawn(async move { warp::test::request().method("GET").path(&path).body(body).reply(&routes).await })
+                }).collect();
+                let mut responses = Vec::with_capacity(pending.len());
+                for response in pending {
+                    responses.push(response.await.unwrap());
+                }
+                responses
+            });
+            comm.send(ThreadComm::Exit).unwrap();
+            responses
+        });
+        serv.infer(serv.models.clone());
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn convert_non_ascii_words() {
+        let model_path = write_model("utf8", &[("caf\u{e9}", vec![1.0, 0.5]), ("\u{6771}\u{4eac}", vec![-2.0, 0.25]), ("\u{1f642}", vec![0.0, 3.0])]);
+        let payload = "{\"words\":[\"caf\u{e9}\",\"\u{6771}\u{4eac}\",\"\u{1f642}\",\"cafe\"]}";
+        let responses = serve(vec![("default".to_string(), model_path)], &[("/convert", payload)]);
+        let response = &responses[0];
+        assert_eq!(response.status(), 200);
+        let body = body(response);
+        // serde_json writes non-ASCII as plain UTF-8, so the words come back as sent
+        assert!(body.contains("\"caf\u{e9}\":[1.0,0.5]"), "{}", body);
+        assert!(body.contains("\"\u{6771}\u{4eac}\":[-2.0,0.25]"), "{}", body);
+        assert!(body.contains("\"\u{1f642}\":[0.0,3.0]"), "{}", body);
+        assert!(body.contains("\"cafe\":null"), "{}", body);
+    }
+
+    #[test]
+    fn serve_named_models() {
+        let model_paths = vec![
+            ("news".to_string(), write_model("news", &[("word", vec![0.0, 1.0])])),
+            ("wiki".to_string(), write_model("wiki", &[("word", vec![0.0, 1.0, 2.0])])),
+        ];
+        let payload = r#"{"words":["word"]}"#;
+        let responses = serve(model_paths, &[("/models", payload), ("/models/wiki/convert", payload), ("/convert", payload), ("/models/missing/convert", payload)]);
+        let bodies: Vec<String> = responses.iter().map(body).collect();
+        assert_eq!(bodies[0], r#"{"models":[{"name":"news","words":1,"dimension":2},{"name":"wiki","words":1,"dimension":3}]}"#);
+        assert!(bodies[1].contains(r#""word":[0.0,1.0,2.0]"#), "{}", bodies[1]);
+        // /convert serves the first model
+        assert!(bodies[2].contains(r#""word":[0.0,1.0]"#), "{}", bodies[2]);
+        assert_eq!(responses[3].status(), 404);
+        assert_eq!(bodies[3], r#"{"error":"unknown model: missing"}"#);
+    }
+
+    #[test]
+    fn analogy_excludes_query_words() {
+        let model_paths = vec![
+            ("royals".to_string(), write_model("analogy", &[("man", vec![1.0, 0.0, 0.0]), ("woman", vec![0.0, 1.0, 0.0]), ("king", vec![2.0, 0.0, 2.0]), ("queen", vec![0.0, 1.0, 1.0])])),
+        ];
+        let responses = serve(model_paths, &[
+            ("/analogy", r#"{"positive":["king","woman"],"negative":["man"],"k":3}"#),
+            ("/models/royals/analogy", r#"{"positive":["king","woman"],"negative":["man"],"objective":"mul"}"#),
+            ("/analogy", r#"{"positive":["king","princess"],"negative":["duke"]}"#),
+            ("/analogy", r#"{"positive":["king"],"objective":"sub"}"#),
+            ("/models/missing/analogy", r#"{"positive":["king"]}"#),
+        ]);
+        let statuses: Vec<u16> = responses.iter().map(|response| response.status().as_u16()).collect();
+        assert_eq!(statuses, vec![200, 200, 400, 400, 404]);
+        // only queen is left once king, woman and man are left out
+        assert!(body(&responses[0]).starts_with(r#"{"data":[{"word":"queen","score":"#), "{}", body(&responses[0]));
+        assert_eq!(body(&responses[0]).matches(r#""word""#).count(), 1);
+        assert!(body(&responses[1]).starts_with(r#"{"data":[{"word":"queen","score":"#), "{}", body(&responses[1]));
+        assert_eq!(body(&responses[2]), r#"{"error":"unknown words","unknown":["princess","duke"]}"#);
+        assert_eq!(body(&responses[3]), r#"{"error":"unknown analogy objective: sub"}"#);
+    }
+
+    #[test]
+    fn most_similar_by_metric() {
+        let model_paths = vec![
+            ("default".to_string(), write_model("similar", &[("a", vec![1.0, 0.0]), ("near", vec![1.5, 0.5]), ("long", vec![10.0, 1.0]), ("back", vec![-1.0, 0.0])])),
+        ];
+        let responses = serve(model_paths, &[
+            ("/most_similar", r#"{"word":"a","k":1}"#),
+            ("/most_similar?metric=euclidean", r#"{"word":"a","k":1}"#),
+            ("/models/default/most_similar?metric=dot", r#"{"vector":[1.0,1.0],"k":4}"#),
+            ("/most_similar?metric=hamming", r#"{"word":"a"}"#),
+            ("/most_similar", r#"{"word":"missing"}"#),
+            ("/most_similar", r#"{"vector":[1.0,2.0,3.0]}"#),
+            ("/most_similar", r#"{"k":3}"#),
+            // without an index an approximate search scores every word
+            ("/models/default/most_similar?search=approximate", r#"{"word":"a","k":1}"#),
+            ("/most_similar?search=approximate&metric=dot", r#"{"word":"a"}"#),
+            ("/most_similar?search=guess", r#"{"word":"a"}"#),
+        ]);
+        let statuses: Vec<u16> = responses.iter().map(|response| response.status().as_u16()).collect();
+        assert_eq!(statuses, vec![200, 200, 200, 400, 400, 400, 400, 200, 400, 400]);
+        assert!(body(&responses[0]).starts_with(r#"{"data":[{"word":"long","score":0.99"#), "{}", body(&responses[0]));
+        // a distance, so the nearest comes first with the smallest score
+        assert!(body(&responses[1]).starts_with(r#"{"data":[{"word":"near","score":0.707"#), "{}", body(&responses[1]));
+        assert_eq!(body(&responses[2]), r#"{"data":[{"word":"long","score":11.0},{"word":"near","score":2.0},{"word":"a","score":1.0},{"word":"back","score":-1.0}]}"#);
+        assert_eq!(body(&responses[3]), r#"{"error":"unknown metric: hamming"}"#);
+        assert_eq!(body(&responses[4]), r#"{"error":"unknown words","unknown":["missing"]}"#);
+        assert_eq!(body(&responses[5]), r#"{"error":"expected a vector of 2 values, not 3"}"#);
+        assert_eq!(body(&responses[6]), r#"{"error":"expected either a word or a vector"}"#);
+        assert_eq!(body(&responses[7]), body(&responses[0]));
+        assert_eq!(body(&responses[8]), r#"{"error":"approximate search only ranks by cosine"}"#);
+        assert_eq!(body(&responses[9]), r#"{"error":"unknown search: guess"}"#);
+    }
+
+    #[test]
+    fn most_similar_batch() {
+        let model_paths = vec![
+            ("default".to_string(), write_model("batch", &[("a", vec![1.0, 0.0]), ("near", vec![1.5, 0.5]), ("long", vec![10.0, 1.0]), ("back", vec![-1.0, 0.0])])),
+        ];
+        let responses = serve(model_paths, &[
+            ("/most_similar_batch?metric=euclidean", r#"{"words":["a","missing","back"],"k":1}"#),
+            ("/models/default/most_similar_batch?metric=dot", r#"{"vectors":[[1.0,1.0],[-1.0,0.0]],"k":2}"#),
+            ("/most_similar_batch", r#"{"vectors":[[1.0,1.0],[1.0]]}"#),
+            ("/most_similar_batch", r#"{"words":["a"],"vectors":[[1.0,1.0]]}"#),
+        ]);
+        let statuses: Vec<u16> = responses.iter().map(|response| response.status().as_u16()).collect();
+        assert_eq!(statuses, vec![200, 200, 400, 400]);
+        assert_eq!(body(&responses[0]), r#"{"data":[[{"word":"near","score":0.70710677}],null,[{"word":"a","score":2.0}]],"unknown":["missing"]}"#);
+        assert_eq!(body(&responses[1]), r#"{"data":[[{"word":"long","score":11.0},{"word":"near","score":2.0}],[{"word":"back","score":1.0},{"word":"a","score":-1.0}]],"unknown":[]}"#);
+        assert_eq!(body(&responses[2]), r#"{"error":"expected vectors of 2 values, not 1"}"#);
+        assert_eq!(body(&responses[3]), r#"{"error":"expected either words or vectors"}"#);
+    }
+
+    #[test]
+    fn within_threshold() {
+        let model_paths = vec![
+            ("default".to_string(), write_model("within", &[("a", vec![1.0, 0.0]), ("near", vec![2.0, 0.0]), ("half", vec![1.0, 1.0]), ("side", vec![0.0, 1.0])])),
+        ];
+        let responses = serve(model_paths, &[
+            ("/within", r#"{"word":"a","threshold":0.5}"#),
+            ("/within", r#"{"word":"a","threshold":-1.0,"limit":2}"#),
+            ("/models/default/within?metric=manhattan", r#"{"vector":[0.0,0.0],"threshold":1.0}"#),
+            ("/within", r#"{"word":"missing","threshold":0.5}"#),
+            ("/within", r#"{"word":"a"}"#),
+        ]);
+        let statuses: Vec<u16> = responses.iter().map(|response| response.status().as_u16()).collect();
+        assert_eq!(statuses, vec![200, 200, 200, 400, 400]);
+        assert!(body(&responses[0]).starts_with(r#"{"data":[{"word":"near","score":1.0},{"word":"half","score":0.707"#), "{}", body(&responses[0]));
+        assert!(body(&responses[0]).ends_with(r#"}],"truncated":false}"#), "{}", body(&responses[0]));
+        assert!(body(&responses[1]).ends_with(r#"}],"truncated":true}"#), "{}", body(&responses[1]));
+        assert_eq!(body(&responses[1]).matches(r#""word""#).count(), 2);
+        assert_eq!(body(&responses[2]), r#"{"data":[{"word":"a","score":1.0},{"word":"side","score":1.0}],"truncated":false}"#);
+        assert_eq!(body(&responses[3]), r#"{"error":"unknown words","unknown":["missing"]}"#);
+    }
+
+    #[test]
+    fn doesnt_match_ranks_outliers() {
+        let model_paths = vec![
+            ("meals".to_string(), write_model("doesnt_match", &[("breakfast", vec![1.0, 0.1]), ("lunch", vec![0.9, 0.2]), ("dinner", vec![3.0, 0.3]), ("car", vec![0.0, 1.0])])),
+        ];
+        let responses = serve(model_paths, &[
+            ("/doesnt_match", r#"{"words":["breakfast","car","brunch","lunch","dinner"]}"#),
+            ("/models/meals/doesnt_match", r#"{"words":["brunch"]}"#),
+            ("/models/missing/doesnt_match", r#"{"words":["car"]}"#),
+        ]);
+        let statuses: Vec<u16> = responses.iter().map(|response| response.status().as_u16()).collect();
+        assert_eq!(statuses, vec![200, 200, 404]);
+        let ranked = body(&responses[0]);
+        assert!(ranked.starts_with(r#"{"data":[{"word":"car","score":"#), "{}", ranked);
+        assert!(ranked.ends_with(r#"}],"unknown":["brunch"]}"#), "{}", ranked);
+        assert_eq!(ranked.matches(r#""word""#).count(), 4);
+        assert_eq!(body(&responses[1]), r#"{"data":[],"unknown":["brunch"]}"#);
+    }
+
+    #[test]
+    fn n_similarity_of_sets() {
+        let model_paths = vec![
+            ("royal".to_string(), write_model("n_similarity", &[("king", vec![1.0, 0.0]), ("queen", vec![1.0, 1.0]), ("car", vec![0.0, 2.0])])),
+        ];
+        let responses = serve(model_paths, &[
+            ("/n_similarity", r#"{"set_a":["king","throne"],"set_b":["king"]}"#),
+            ("/models/royal/n_similarity", r#"{"set_a":["king","throne"],"set_b":["car","lane","king"],"pairwise":true}"#),
+            ("/n_similarity", r#"{"set_a":["throne"],"set_b":["king"]}"#),
+            ("/models/missing/n_similarity", r#"{"set_a":["king"],"set_b":["king"]}"#),
+        ]);
+        let statuses: Vec<u16> = responses.iter().map(|response| response.status().as_u16()).collect();
+        assert_eq!(statuses, vec![200, 200, 200, 404]);
+        assert_eq!(body(&responses[0]), r#"{"similarity":1.0,"unknown_a":["throne"],"unknown_b":[]}"#);
+        assert_eq!(body(&responses[1]), r#"{"rows":["king"],"columns":["car","king"],"data":[[0.0,1.0]],"unknown_a":["throne"],"unknown_b":["lane"]}"#);
+        assert_eq!(body(&responses[2]), r#"{"similarity":null,"unknown_a":["throne"],"unknown_b":[]}"#);
+    }
+
+    #[test]
+    fn reload_model() {
+        let old_path = write_model("reload_old", &[("word", vec![1.0, 2.0])]);
+        let new_path = write_model("reload_new", &[("word", vec![3.0, 4.0, 5.0])]);
+        let serv = Server::init(vec![("news".to_string(), old_path)], word2vec::LoadOptions::default(), Some("secret".to_string())).unwrap();
+
+        let comm = serv.get_shutdown_tx();
+        let reloader = serv.reloader.clone();
+        let client = std::thread::spawn(move || {
+            let routes = Server::routes(comm.clone(), reloader, Some("secret".to_string()));
+            let mut rt = tokio::runtime::Runtime::new().unwrap();
+            let mut send = |method: &str, path: &str, authorization: &str, body: String| {
+                rt.block_on(warp::test::request()
+                    .method(method)
+                    .path(path)
+                    .header("authorization", authorization)
+                    .body(body)
+                    .reply(&routes))
+            };
+            let convert = || String::from(r#"{"words":["word"]}"#);
+            let reload = |path: &PathBuf| format!(r#"{{"model":"news","path":"{}"}}"#, path.display());
+            let responses = vec![
+                send("POST", "/admin/reload", "Bearer wrong", reload(&new_path)),
+                send("POST", "/admin/reload", "Bearer secret", String::from(r#"{"model":"missing"}"#)),
+                send("POST", "/admin/reload", "Bearer secret", reload(&PathBuf::from("/nonexistent/model.bin"))),
+                // still the old model after both failures
+                send("GET", "/convert", "", convert()),
+                send("POST", "/admin/reload", "Bearer secret", reload(&new_path)),
+                send("GET", "/convert", "", convert()),
+                send("GET", "/models", "", String::new()),
+            ];
+            comm.send(ThreadComm::Exit).unwrap();
+            responses
+        });
+        serv.infer(serv.models.clone());
+
+        let responses = client.join().unwrap();
+        let statuses: Vec<u16> = responses.iter().map(|response| response.status().as_u16()).collect();
+        assert_eq!(statuses, vec![401, 404, 500, 200, 200, 200, 200]);
+        assert!(body(&responses[2]).contains("NoFileAtPath"), "{}", body(&responses[2]));
+        assert!(body(&responses[3]).contains(r#""word":[1.0,2.0]"#), "{}", body(&responses[3]));
+        assert_eq!(body(&responses[4]), r#"{"name":"news","words":1,"dimension":3}"#);
+        assert!(body(&responses[5]).contains(r#""word":[3.0,4.0,5.0]"#), "{}", body(&responses[5]));
+        assert_eq!(body(&responses[6]), r#"{"models":[{"name":"news","words":1,"dimension":3}]}"#);
+    }
+
+    #[test]
+    fn authorization_header() {
+        assert!(authorized(Some("Bearer secret"), "secret"));
+        assert!(!authorized(Some("Bearer secreT"), "secret"));
+        assert!(!authorized(Some("Bearer secret2"), "secret"));
+        assert!(!authorized(Some("secret"), "secret"));
+        assert!(!authorized(None, "secret"));
+    }
+
     #[test]
     fn run_server_small() {
         let short_model_path =PathBuf::from("./test_material/vectors.bin"); 
-        let mut serv = Server::init(short_model_path).unwrap();
+        let mut serv = Server::init(vec![("default".to_string(), short_model_path)], word2vec::LoadOptions::default(), None).unwrap();
         serv.begin(3030);
     }
 
     #[test]
     fn run_server_big() {
         let short_model_path =PathBuf::from("./test_material/GoogleNews-vectors-negative300.bin");
-        let mut serv = Server::init(short_model_path).unwrap();
+        let mut serv = Server::init(vec![("default".to_string(), short_model_path)], word2vec::LoadOptions::default(), None).unwrap();
         serv.begin(3030); 
     }
 }
@@ -1,25 +1,940 @@
 use byteorder::{ByteOrder, LittleEndian};
+use flate2::bufread::MultiGzDecoder;
+use xz2::bufread::XzDecoder;
+use memmap2::Mmap;
 use std::fs;
 use std::io::prelude::*;
-use std::io::BufReader;
-use std::path::PathBuf;
-use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::borrow::Cow;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+mod fasttext;
+mod hnsw;
+mod ivfpq;
+mod kernels;
+mod mapped;
+mod parallel;
+mod save;
+mod snapshot;
+mod text;
+mod topk;
 
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct Model {
     pub total_words: usize,
     pub size: usize,
-    lookup: HashMap<String,Vec<f32>>
+    lookup: Lookup,
+    // n-gram buckets, for models that can build vectors for unknown words
+    subwords: Option<fasttext::Subwords>,
+    // threads each nearest neighbour query is split across
+    search: SearchPool,
+    hnsw: Option<hnsw::Hnsw>,
+    ivfpq: Option<ivfpq::IvfPq>,
+    // how invalid UTF-8 in the words was read, so that save only turns
+    // chars back into raw bytes for models read with Utf8Policy::Raw
+    utf8: Utf8Policy,
 }
 
+// Every word with its vector and the vector's L2 norm
+type WithNorms<'a> = Box<dyn Iterator<Item=(&'a str, kernels::Floats<'a>, f32)> + 'a>;
+
+// Where the vectors live: copied onto the heap, left in a memory mapped
+// binary file, or served straight from a mapped snapshot.
 #[derive(Debug)]
+enum Lookup {
+    Owned(Matrix),
+    Mapped(mapped::MappedVectors),
+    Snapshot(snapshot::Snapshot),
+}
+
+impl Lookup {
+    fn len(&self) -> usize {
+        match self {
+            Lookup::Owned(matrix) => matrix.len(),
+            Lookup::Mapped(vectors) => vectors.len(),
+            Lookup::Snapshot(snapshot) => snapshot.len(),
+        }
+    }
+
+    fn contains_key(&self, word: &str) -> bool {
+        match self {
+            Lookup::Owned(matrix) => matrix.index.contains_key(word),
+            Lookup::Mapped(vectors) => vectors.contains_key(word),
+            Lookup::Snapshot(snapshot) => snapshot.find(word).is_some(),
+        }
+    }
+
+    fn get(&self, word: &str) -> Option<&[f32]> {
+        match self {
+            Lookup::Owned(matrix) => matrix.get(word),
+            Lookup::Mapped(vectors) => vectors.get(word),
+            Lookup::Snapshot(snapshot) => snapshot.get(word),
+        }
+    }
+
+    // Like get, plus the vector's L2 norm, which every lookup keeps so that
+    // cosine similarity only needs a dot product
+    fn get_with_norm(&self, word: &str) -> Option<(Cow<'_, [f32]>, f32)> {
+        match self {
+            Lookup::Owned(matrix) => matrix.index.get(word).map(|row| (Cow::Borrowed(matrix.row(*row)), matrix.norm(*row))),
+            Lookup::Mapped(vectors) => vectors.get_with_norm(word),
+            Lookup::Snapshot(snapshot) => snapshot.find(word).map(|row| (Cow::Borrowed(snapshot.row(row)), snapshot.norm(row))),
+        }
+    }
+
+    // Every word with its vector and the vector's L2 norm
+    fn iter_with_norms(&self) -> WithNorms<'_> {
+        match self {
+            Lookup::Owned(matrix) => Box::new(matrix.iter().enumerate().map(move |(row, (word, vector))| (word, vector.into(), matrix.norm(row)))),
+            Lookup::Mapped(vectors) => Box::new(vectors.iter_with_norms()),
+            Lookup::Snapshot(snapshot) => Box::new(snapshot.iter().enumerate().map(move |(row, (word, vector))| (word, vector.into(), snapshot.norm(row)))),
+        }
+    }
+
+    // Like iter_with_norms, but only the `start..end` part of the order it
+    // walks in, so a scan can be shared out between threads
+    fn iter_range(&self, start: usize, end: usize) -> WithNorms<'_> {
+        match self {
+            Lookup::Owned(matrix) => Box::new((start..end).map(move |row| (matrix.words[row].as_ref(), matrix.row(row).into(), matrix.norm(row)))),
+            Lookup::Mapped(vectors) => Box::new(vectors.iter_range(start, end)),
+            Lookup::Snapshot(snapshot) => Box::new((start..end).map(move |row| (snapshot.word(row), snapshot.row(row).into(), snapshot.norm(row)))),
+        }
+    }
+
+    fn rows(&self) -> Option<Rows<'_>> {
+        match self {
+            Lookup::Owned(matrix) => Some(Rows::Matrix(matrix)),
+            Lookup::Mapped(_) => None,
+            Lookup::Snapshot(snapshot) => Some(Rows::Snapshot(snapshot)),
+        }
+    }
+
+    // words in the order they appear in the model file
+    fn words(&self) -> Vec<&str> {
+        match self {
+            Lookup::Owned(matrix) => matrix.words.iter().map(|word| word.as_ref()).collect(),
+            Lookup::Mapped(vectors) => vectors.words(),
+            Lookup::Snapshot(snapshot) => snapshot.words(),
+        }
+    }
+}
+
+// Rows by their position in the file, for the lookups that can lend every
+// row as a slice. Most rows of a mapped binary aren't 4 byte aligned.
+#[derive(Clone, Copy)]
+enum Rows<'a> {
+    Matrix(&'a Matrix),
+    Snapshot(&'a snapshot::Snapshot),
+}
+
+impl<'a> Rows<'a> {
+    fn len(&self) -> usize {
+        match self {
+            Rows::Matrix(matrix) => matrix.len(),
+            Rows::Snapshot(snapshot) => snapshot.len(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Rows::Matrix(matrix) => matrix.size,
+            Rows::Snapshot(snapshot) => snapshot.size(),
+        }
+    }
+
+    fn word(&self, row: usize) -> &'a str {
+        match self {
+            Rows::Matrix(matrix) => &matrix.words[row],
+            Rows::Snapshot(snapshot) => snapshot.word(row),
+        }
+    }
+
+    fn vector(&self, row: usize) -> &'a [f32] {
+        match self {
+            Rows::Matrix(matrix) => matrix.row(row),
+            Rows::Snapshot(snapshot) => snapshot.row(row),
+        }
+    }
+
+    fn norm(&self, row: usize) -> f32 {
+        match self {
+            Rows::Matrix(matrix) => matrix.norm(row),
+            Rows::Snapshot(snapshot) => snapshot.norm(row),
+        }
+    }
+
+    // Identifies the words and vectors an index was built for, so a model
+    // retrained on the same vocabulary doesn't get the old model's index
+    fn fingerprint(&self) -> u64 {
+        let mut checksum = snapshot::Checksum::new();
+        checksum.update(&(self.size() as u64).to_le_bytes());
+        let mut bytes: Vec<u8> = Vec::with_capacity(self.size()*4);
+        for row in 0..self.len() {
+            let word = self.word(row);
+            checksum.update(&(word.len() as u64).to_le_bytes());
+            checksum.update(word.as_bytes());
+            bytes.clear();
+            for value in self.vector(row) {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            checksum.update(&bytes);
+        }
+        checksum.finish()
+    }
+}
+
+// Vectors copied onto the heap as one row-major matrix, so scans over the
+// vocabulary walk memory in order. Rows follow the file's order, which is by
+// frequency and worth keeping when the model is written back out. Each word
+// is allocated once and shared by the index and the row table.
+#[derive(Debug, Default)]
+struct Matrix {
+    size: usize,
+    rows: Vec<f32>,
+    // L2 norm of each row, empty once the rows are normalized
+    norms: Vec<f32>,
+    normalized: bool,
+    // word -> row
+    index: HashMap<Arc<str>,usize>,
+    // row -> word
+    words: Vec<Arc<str>>,
+}
+
+impl Matrix {
+    fn with_capacity(capacity: usize, size: usize) -> Matrix {
+        Matrix {
+            size,
+            rows: Vec::with_capacity(capacity*size),
+            norms: Vec::with_capacity(capacity),
+            normalized: false,
+            index: HashMap::with_capacity(capacity),
+            words: Vec::with_capacity(capacity),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    fn row(&self, row: usize) -> &[f32] {
+        &self.rows[row*self.size..(row + 1)*self.size]
+    }
+
+    fn norm(&self, row: usize) -> f32 {
+        if self.normalized {
+            1.0
+        } else {
+            self.norms[row]
+        }
+    }
+
+    fn get(&self, word: &str) -> Option<&[f32]> {
+        self.index.get(word).map(|row| self.row(*row))
+    }
+
+    fn iter(&self) -> impl Iterator<Item=(&str, &[f32])> {
+        self.words.iter().map(|word| word.as_ref()).zip(self.rows.chunks_exact(self.size.max(1)))
+    }
+
+    // a repeated word keeps its first row and its last vector
+    fn insert(&mut self, word: String, vector: &[f32]) {
+        if self.words.is_empty() {
+            self.size = vector.len();
+        }
+        if let Some(row) = self.index.get(word.as_str()) {
+            let start = row*self.size;
+            self.rows[start..start + self.size].copy_from_slice(vector);
+            self.norms[*row] = norm(vector);
+        } else {
+            let word: Arc<str> = Arc::from(word);
+            self.index.insert(word.clone(), self.words.len());
+            self.words.push(word);
+            self.rows.extend_from_slice(vector);
+            self.norms.push(norm(vector));
+        }
+    }
+
+    // Scales every row to unit length in place and drops the norms, leaving
+    // nothing on the heap but the vectors. Zero vectors stay zero.
+    fn normalize(&mut self) {
+        if self.normalized {
+            return;
+        }
+        for (row, norm) in self.rows.chunks_exact_mut(self.size.max(1)).zip(self.norms.iter()) {
+            let scale = inverse(*norm);
+            row.iter_mut().for_each(|value| *value *= scale);
+        }
+        self.norms = Vec::new();
+        self.normalized = true;
+    }
+
+    fn append(&mut self, other: Matrix) {
+        for (word, vector) in other.iter() {
+            self.insert(word.to_string(), vector);
+        }
+    }
+
+    // keep only the first `len` words
+    fn truncate(&mut self, len: usize) {
+        if len < self.words.len() {
+            for word in self.words.drain(len..) {
+                self.index.remove(&word);
+            }
+            self.rows.truncate(len*self.size);
+            self.norms.truncate(len);
+        }
+    }
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]
 pub enum W2VError {
     // NotImplemented,
     NoFileAtPath,
     CouldNotOpenFile,
     ReadError(usize),
     // UnexpectedEoF,
+    NotMappable,
+    /// The header line is missing or malformed, with the byte offset of the
+    /// offending field
+    BadHeader(usize),
+    /// The vector dimension is zero or larger than MAX_DIMENSION
+    BadDimension(usize),
+    /// A word runs past MAX_WORD_BYTES without its separating space
+    WordTooLong { record: usize, offset: usize },
+    /// The file ends part way through a record
+    TruncatedRecord { record: usize, offset: usize },
+    /// The header promised `expected` words but the file holds `found`
+    WordCountMismatch { expected: usize, found: usize },
+    /// fastText models with product quantized matrices (.ftz) are not supported
+    QuantizedModel,
+    CouldNotCreateFile,
+    /// Writing failed at the given record
+    WriteError(usize),
+    /// A snapshot written by a different version of the format, which has
+    /// to be regenerated from the original model
+    SnapshotVersion(u32),
+    /// A snapshot whose contents no longer match the checksum in its header
+    ChecksumMismatch,
+    /// A word that is not valid UTF-8, under `Utf8Policy::Reject`
+    InvalidUtf8 { record: usize, offset: usize },
+    /// Indexes need rows in file order, which a model mapped straight from a
+    /// binary file doesn't have, nor can a model read from stdin keep one
+    /// next to it
+    NotIndexable,
+    /// The model has no index of that kind to save
+    NoIndex,
+    /// An index written by a different version of its format
+    IndexVersion(u32),
+    /// An index built for different words or vectors
+    IndexMismatch,
+    /// Query words that aren't in the vocabulary, in the order given
+    UnknownWords(Vec<String>),
+}
+
+// Limits past which a file is treated as corrupt rather than trusted
+const MAX_HEADER_BYTES: usize = 64;
+const MAX_WORD_BYTES: usize = 1024;
+const MAX_DIMENSION: usize = 100_000;
+
+fn check_dimension(size: usize) -> Result<(), W2VError> {
+    if size == 0 || size > MAX_DIMENSION {
+        Err(W2VError::BadDimension(size))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_word_count(expected: usize, found: usize) -> Result<(), W2VError> {
+    if expected != found {
+        Err(W2VError::WordCountMismatch { expected, found })
+    } else {
+        Ok(())
+    }
+}
+
+// Entries reserved up front when reading a stream of unknown length
+const STREAM_CAPACITY: usize = 1 << 20;
+
+// The header's word count is untrusted, so never reserve more entries than
+// the file could possibly hold, given each one needs at least a one byte
+// word, a separator and `bytes_per_word` of vector. Streams have no length,
+// so their maps start modestly and grow as words arrive.
+fn capacity_hint(total_words: usize, bytes_per_word: usize, file_len: Option<usize>) -> usize {
+    match file_len {
+        Some(file_len) => total_words.min(file_len / (bytes_per_word + 2)),
+        None => total_words.min(STREAM_CAPACITY),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    None,
+    Gzip,
+    Xz,
+}
+
+// Peek at the magic bytes of the stream without consuming them
+fn detect_compression<R: BufRead>(reader: &mut R) -> Result<Compression, W2VError> {
+    let peek = match reader.fill_buf() {
+        Ok(buffer) => buffer,
+        Err(_) => return Err(W2VError::ReadError(0)),
+    };
+    if peek.starts_with(&[0x1f, 0x8b]) {
+        Ok(Compression::Gzip)
+    } else if peek.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Ok(Compression::Xz)
+    } else {
+        Ok(Compression::None)
+    }
+}
+
+// Archives are often concatenations of several members, so both decoders
+// keep going past the end of the first one.
+fn decompress<R: BufRead + 'static>(reader: R, compression: Compression) -> Box<dyn Read> {
+    match compression {
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+        Compression::None => Box::new(reader),
+    }
+}
+
+// splitmix64, enough for the random choices index builds make while keeping
+// them repeatable
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // uniform in (0, 1]
+    fn unit(&mut self) -> f64 {
+        1.0 - (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // uniform in 0..n
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// The threads exact nearest neighbour queries are split across, started
+// once and kept for every query after. Queries take turns with it.
+struct SearchPool {
+    threads: usize,
+    pool: Option<Mutex<scoped_threadpool::Pool>>,
+}
+
+impl SearchPool {
+    fn new(threads: usize) -> SearchPool {
+        SearchPool {
+            threads,
+            pool: if threads > 1 { Some(Mutex::new(scoped_threadpool::Pool::new(threads as u32))) } else { None },
+        }
+    }
+
+    // Splits 0..len into one contiguous share per thread and runs `scan` on
+    // each, handing back the results in order
+    fn map<T: Send, F: Fn(usize, usize) -> T + Sync>(&self, len: usize, scan: F) -> Vec<T> {
+        let threads = self.threads.clamp(1, len.max(1));
+        let pool = match &self.pool {
+            Some(pool) if threads > 1 => pool,
+            _ => return vec![scan(0, len)],
+        };
+        let share = len.div_ceil(threads);
+        let mut parts: Vec<Option<T>> = (0..len).step_by(share).map(|_| None).collect();
+        pool.lock().unwrap().scoped(|scope| {
+            for (part, start) in parts.iter_mut().zip((0..len).step_by(share)) {
+                let scan = &scan;
+                scope.execute(move || *part = Some(scan(start, (start + share).min(len))));
+            }
+        });
+        parts.into_iter().map(|part| part.unwrap()).collect()
+    }
+}
+
+impl std::fmt::Debug for SearchPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SearchPool").field("threads", &self.threads).finish()
+    }
+}
+
+fn resolve_threads(threads: usize) -> usize {
+    match threads {
+        0 => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        threads => threads,
+    }
+}
+
+/// On-disk layout of a model file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Work the format out from the first few bytes of the file
+    Auto,
+    /// Google's binary layout: ASCII header, then word, space, raw little-endian f32s
+    Binary,
+    /// One word per line followed by its values as decimal text, with or
+    /// without a `count dim` header line (word2vec .txt, fastText .vec, GloVe)
+    Text,
+    /// fastText's own .bin, which keeps character n-grams so that words
+    /// outside the vocabulary still get a vector
+    FastText,
+    /// This crate's own layout: the vectors as an aligned matrix plus a
+    /// prebuilt word index and norms, so loading is a map and a checksum
+    /// instead of a parse. Written by `Model::save`, and always memory
+    /// mapped. fastText n-grams are not kept.
+    Snapshot,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Format, String> {
+        match name {
+            "auto" => Ok(Format::Auto),
+            "binary" | "bin" => Ok(Format::Binary),
+            "text" | "txt" | "vec" => Ok(Format::Text),
+            "fasttext" => Ok(Format::FastText),
+            "snapshot" => Ok(Format::Snapshot),
+            _ => Err(format!("unknown model format: {}", name)),
+        }
+    }
+}
+
+/// Settings applied while reading a model file.
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    pub format: Format,
+    /// Map a binary model into memory instead of copying it onto the heap.
+    /// Lookups are then served from the page cache, which is shared between
+    /// processes serving the same file.
+    pub mmap: bool,
+    /// Threads used to parse a binary model onto the heap. 1 parses on the
+    /// calling thread, 0 uses every available core.
+    pub threads: usize,
+    pub filter: VocabFilter,
+    /// What to do with words that are not valid UTF-8
+    pub utf8: Utf8Policy,
+    /// Scale every vector to unit length once it is loaded and keep only
+    /// those, instead of the vectors as stored plus their norms. Lookups
+    /// then return unit vectors. Only applies to models read onto the heap,
+    /// mapped models always keep their vectors as stored.
+    pub normalize: bool,
+    /// Threads each nearest neighbour query is split across. 1 searches on
+    /// the calling thread, 0 uses every available core. Results are the
+    /// same either way.
+    pub search_threads: usize,
+    /// Keep an HNSW index next to the model, see `Model::hnsw_path`, for
+    /// `Search::Approximate`. One is built and saved first if there is none
+    /// yet or it was built for different words or vectors.
+    pub hnsw: Option<HnswOptions>,
+    /// Keep an IVF-PQ index next to the model, see `Model::ivfpq_path`, for
+    /// `Search::Approximate`, trained and saved first in the same way
+    pub ivfpq: Option<IvfPqOptions>,
+}
+
+impl Default for LoadOptions {
+    fn default() -> LoadOptions {
+        LoadOptions {
+            format: Format::Auto,
+            mmap: false,
+            threads: 1,
+            filter: VocabFilter::default(),
+            utf8: Utf8Policy::default(),
+            normalize: false,
+            search_threads: 1,
+            hnsw: None,
+            ivfpq: None,
+        }
+    }
+}
+
+/// How an HNSW index is built and searched
+#[derive(Debug, Clone)]
+pub struct HnswOptions {
+    /// Links kept per word on every layer but the bottom one, which keeps
+    /// twice as many. More gives better recall for more memory and a slower
+    /// build.
+    pub m: usize,
+    /// Candidates considered while linking each word in. More builds a
+    /// better graph, more slowly.
+    pub ef_construction: usize,
+    /// Candidates considered per query, and never fewer than k. More gives
+    /// better recall for slower queries. Isn't stored with the index, so it
+    /// can be changed whenever one is loaded.
+    pub ef_search: usize,
+    /// Seed for the layers words are put on, so builds on one thread are
+    /// repeatable. With more, the graph depends on how the inserts interleave.
+    pub seed: u64,
+    /// Threads inserting words during a build, 0 for every available core
+    pub threads: usize,
+}
+
+impl Default for HnswOptions {
+    fn default() -> HnswOptions {
+        HnswOptions {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+            seed: 1,
+            threads: 0,
+        }
+    }
+}
+
+/// How an IVF-PQ index is trained and searched. It holds one byte per word
+/// per subspace, plus the centroids, and can answer queries without the
+/// original vectors, which can then stay on disk in a mapped snapshot.
+#[derive(Debug, Clone)]
+pub struct IvfPqOptions {
+    /// Cells the vectors are split into by k-means, roughly the square root
+    /// of the word count or more
+    pub lists: usize,
+    /// Slices each vector is quantized in, so bytes per word. More is more
+    /// accurate and bigger. At most the dimension.
+    pub subspaces: usize,
+    /// Vectors k-means is trained on
+    pub sample: usize,
+    /// Rounds of k-means
+    pub iterations: usize,
+    /// Cells searched per query, nearest first. More gives better recall for
+    /// slower queries.
+    pub probes: usize,
+    /// Re-rank this many candidates by their exact cosine, which reads their
+    /// original vectors. 0 returns cosines estimated from the codes.
+    pub rerank: usize,
+    /// Seed for picking k-means starting points, so training is repeatable
+    pub seed: u64,
+    /// Threads used to train, 0 for every available core
+    pub threads: usize,
+}
+
+impl Default for IvfPqOptions {
+    fn default() -> IvfPqOptions {
+        IvfPqOptions {
+            lists: 1024,
+            subspaces: 32,
+            sample: 100_000,
+            iterations: 20,
+            probes: 16,
+            rerank: 0,
+            seed: 1,
+            threads: 0,
+        }
+    }
+}
+
+/// How a similarity query finds its neighbours
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Search {
+    /// Score every word
+    Exact,
+    /// Walk the HNSW index, or else use the IVF-PQ index, or score every
+    /// word if the model has neither
+    Approximate,
+}
+
+impl FromStr for Search {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Search, String> {
+        match name {
+            "exact" => Ok(Search::Exact),
+            "approximate" | "approx" => Ok(Search::Approximate),
+            _ => Err(format!("unknown search: {}", name)),
+        }
+    }
+}
+
+/// How two vectors are compared. Cosine and dot are similarities, larger
+/// being closer, Euclidean and Manhattan are distances, smaller being
+/// closer, and results always come closest first.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Metric {
+    #[default]
+    Cosine,
+    /// The raw dot product, so longer vectors score higher
+    Dot,
+    Euclidean,
+    /// Sum of absolute differences
+    Manhattan,
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Metric, String> {
+        match name {
+            "cosine" => Ok(Metric::Cosine),
+            "dot" => Ok(Metric::Dot),
+            "euclidean" | "l2" => Ok(Metric::Euclidean),
+            "manhattan" | "l1" => Ok(Metric::Manhattan),
+            _ => Err(format!("unknown metric: {}", name)),
+        }
+    }
+}
+
+impl Metric {
+    /// True when smaller scores are closer
+    pub fn is_distance(self) -> bool {
+        matches!(self, Metric::Euclidean | Metric::Manhattan)
+    }
+
+    // Larger is closer whatever the metric, so scans can keep the largest
+    // keys. Euclidean skips the square root until `score`, which doesn't
+    // change the order. `scale` is 1/|query|, `norm` the candidate's norm,
+    // only cosine uses them.
+    fn key(self, query: &[f32], scale: f32, vector: kernels::Floats, norm: f32) -> f32 {
+        match self {
+            Metric::Cosine => kernels::dot(query, vector)*scale*inverse(norm),
+            Metric::Dot => kernels::dot(query, vector),
+            Metric::Euclidean => -kernels::squared_l2(query, vector),
+            Metric::Manhattan => -kernels::manhattan(query, vector),
+        }
+    }
+
+    // the score reported for a key
+    fn score(self, key: f32) -> f32 {
+        match self {
+            Metric::Cosine | Metric::Dot => key,
+            Metric::Euclidean => (-key).sqrt(),
+            Metric::Manhattan => -key,
+        }
+    }
+
+    // The key of a score, so that key >= key_of(threshold) is score at
+    // least as close as threshold. No distance is negative.
+    fn key_of(self, score: f32) -> f32 {
+        match self {
+            Metric::Cosine | Metric::Dot => score,
+            Metric::Euclidean if score < 0.0 => f32::INFINITY,
+            Metric::Euclidean => -(score*score),
+            Metric::Manhattan => -score,
+        }
+    }
+
+    // closest first
+    fn compare(self, a: f32, b: f32) -> std::cmp::Ordering {
+        if self.is_distance() {
+            a.total_cmp(&b)
+        } else {
+            b.total_cmp(&a)
+        }
+    }
+}
+
+// Batched queries score about this many bytes of vocabulary against every
+// query while they stay in cache, a tile of queries at a time
+const TILE_BYTES: usize = 256*1024;
+const QUERY_TILE: usize = 64;
+
+// a query in a batch, with what its metric needs besides the vector
+struct BatchQuery<'q> {
+    vector: &'q [f32],
+    // 1/|vector|
+    scale: f32,
+    // |vector|^2
+    square: f32,
+}
+
+/// How `Model::analogy` scores a word against the positive and negative
+/// query words
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Analogy {
+    /// 3CosAdd: cosine with the sum of the positive words' unit vectors less
+    /// the negative ones'
+    Add,
+    /// 3CosMul (Levy and Goldberg): the product of the cosines with the
+    /// positive words over the product of those with the negative words,
+    /// each first shifted into [0, 1]. Less swayed by a single query word
+    /// that is much closer than the others.
+    Mul,
+}
+
+impl FromStr for Analogy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Analogy, String> {
+        match name {
+            "add" | "3cosadd" => Ok(Analogy::Add),
+            "mul" | "3cosmul" => Ok(Analogy::Mul),
+            _ => Err(format!("unknown analogy objective: {}", name)),
+        }
+    }
+}
+
+// keeps 3CosMul finite when a negative word is opposite the candidate
+const COSMUL_EPSILON: f32 = 0.001;
+
+/// The words of a list from most to least out of place, from
+/// `Model::doesnt_match`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoesntMatch {
+    /// Each word in the vocabulary with the cosine between its vector and
+    /// the mean of them all, lowest, so the odd one out, first
+    pub ranked: Vec<(String, f32)>,
+    /// Words left out for not being in the vocabulary, in the order given
+    pub unknown: Vec<String>,
+}
+
+/// How alike two sets of words are, from `Model::n_similarity`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetSimilarity {
+    /// Cosine between the means of each set's unit vectors, None when
+    /// either set has no word in the vocabulary
+    pub similarity: Option<f32>,
+    /// Words of the first set left out for not being in the vocabulary
+    pub unknown_a: Vec<String>,
+    /// Words of the second set left out for not being in the vocabulary
+    pub unknown_b: Vec<String>,
+}
+
+/// The cosine between every word of one set and every word of another,
+/// from `Model::similarity_matrix`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimilarityMatrix {
+    /// Words of the first set in the vocabulary, in the order given
+    pub rows: Vec<String>,
+    /// Words of the second set in the vocabulary, in the order given
+    pub columns: Vec<String>,
+    /// `values[row][column]`, one row per word of `rows`
+    pub values: Vec<Vec<f32>>,
+    pub unknown_a: Vec<String>,
+    pub unknown_b: Vec<String>,
+}
+
+/// How often approximate search finds what exact search does, from
+/// `Model::recall`
+#[derive(Debug, Clone)]
+pub struct Recall {
+    pub k: usize,
+    pub queries: usize,
+    /// Share of the exact top k that approximate search also returned,
+    /// averaged over the queries
+    pub recall: f64,
+    /// Mean seconds per query
+    pub exact_seconds: f64,
+    pub approximate_seconds: f64,
+}
+
+/// How words that are not valid UTF-8 are handled while a model is read.
+/// Valid words are always decoded as UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Utf8Policy {
+    /// Fail the load with `W2VError::InvalidUtf8`
+    Reject,
+    /// Replace each invalid sequence with U+FFFD. Distinct words can collapse
+    /// into one, in which case the last of them keeps its vector.
+    #[default]
+    Lossy,
+    /// Keep every byte. Each byte of an invalid sequence becomes the char
+    /// U+10FF00 plus the byte, from a private use plane, so the word stays
+    /// addressable and is written back out unchanged by `Model::save`. A
+    /// valid word that already has chars from that range is written back
+    /// with them as bytes too.
+    Raw,
+}
+
+impl FromStr for Utf8Policy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Utf8Policy, String> {
+        match name {
+            "reject" => Ok(Utf8Policy::Reject),
+            "lossy" => Ok(Utf8Policy::Lossy),
+            "raw" => Ok(Utf8Policy::Raw),
+            _ => Err(format!("unknown UTF-8 policy: {}", name)),
+        }
+    }
+}
+
+/// Restricts the vocabulary kept while a model is read. A word has to pass
+/// every test that is set, anything else is skipped as it is read. When a
+/// filter is set the model's `total_words` counts the words that were kept.
+#[derive(Debug, Clone, Default)]
+pub struct VocabFilter {
+    /// Keep at most this many words. Model files are ordered by frequency,
+    /// so these are the most frequent words that pass the other tests.
+    pub max_words: Option<usize>,
+    /// Keep only words in this set
+    pub allowlist: Option<HashSet<String>>,
+    /// Keep only words matching this pattern
+    pub include: Option<Regex>,
+    /// Skip words matching this pattern
+    pub exclude: Option<Regex>,
+    /// Skip words with fewer characters than this
+    pub min_length: usize,
+}
+
+impl VocabFilter {
+    /// Reads an allowlist file holding one word per line
+    pub fn read_allowlist(path: PathBuf) -> Result<HashSet<String>, W2VError> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(_) => return Err(W2VError::CouldNotOpenFile),
+        };
+        Ok(String::from_utf8_lossy(&contents).lines()
+            .map(|line| line.trim())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_string())
+            .collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max_words.is_none() && self.allowlist.is_none() && self.include.is_none()
+            && self.exclude.is_none() && self.min_length == 0
+    }
+
+    fn keeps(&self, word: &str) -> bool {
+        if self.min_length > 0 && word.chars().count() < self.min_length {
+            return false;
+        }
+        if let Some(allowlist) = &self.allowlist {
+            if !allowlist.contains(word) {
+                return false;
+            }
+        }
+        if let Some(include) = &self.include {
+            if !include.is_match(word) {
+                return false;
+            }
+        }
+        if let Some(exclude) = &self.exclude {
+            if exclude.is_match(word) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // whether `kept` words are all that is wanted, so reading can stop
+    fn is_full(&self, kept: usize) -> bool {
+        matches!(self.max_words, Some(max_words) if kept >= max_words)
+    }
+
+    fn capacity(&self, capacity: usize) -> usize {
+        self.max_words.map_or(capacity, |max_words| capacity.min(max_words))
+    }
+
+    // the header's count, unless words were dropped
+    fn total_words(&self, header_words: usize, kept: usize) -> usize {
+        if self.is_empty() {
+            header_words
+        } else {
+            kept
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -31,80 +946,408 @@ enum ReadMode {
 #[allow(dead_code)]
 impl Model {
     pub fn new(model_path: PathBuf) -> Result<Model, W2VError> {
+        Self::with_options(model_path, &LoadOptions::default())
+    }
+
+    /// Reads a model from `model_path`, or from stdin if the path is `-`.
+    /// Gzip and xz compressed models are decompressed on the fly, but can
+    /// then only be read onto the heap, the same as anything from stdin.
+    pub fn with_options(model_path: PathBuf, options: &LoadOptions) -> Result<Model, W2VError> {
+        let mut model = Self::load(model_path.clone(), options)?;
+        model.set_search_threads(options.search_threads);
+        model.utf8 = options.utf8;
+        if options.normalize {
+            if let Lookup::Owned(matrix) = &mut model.lookup {
+                matrix.normalize();
+            }
+        }
+        if let Some(hnsw) = &options.hnsw {
+            model.open_hnsw(&model_path, hnsw)?;
+        }
+        if let Some(ivfpq) = &options.ivfpq {
+            model.open_ivfpq(&model_path, ivfpq)?;
+        }
+        Ok(model)
+    }
+
+    // Loads the index kept next to the model, or builds and saves one if
+    // that is missing, stale or from an older version
+    fn open_hnsw(&mut self, model_path: &Path, options: &HnswOptions) -> Result<(), W2VError> {
+        if model_path.as_os_str() == "-" {
+            return Err(W2VError::NotIndexable);
+        }
+        let path = Self::hnsw_path(model_path);
+        match self.load_hnsw(path.clone()) {
+            Ok(()) => {
+                self.set_hnsw_ef(options.ef_search);
+                Ok(())
+            }
+            Err(W2VError::NoFileAtPath) | Err(W2VError::IndexMismatch) | Err(W2VError::IndexVersion(_)) => {
+                self.build_hnsw(options)?;
+                self.save_hnsw(path)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Where the HNSW index for the model at `model_path` is kept: next to
+    /// it, with `.hnsw` added to the name
+    pub fn hnsw_path(model_path: &Path) -> PathBuf {
+        let mut name = model_path.as_os_str().to_owned();
+        name.push(".hnsw");
+        PathBuf::from(name)
+    }
+
+    /// Builds an HNSW index over every word, replacing any the model had.
+    /// Models mapped straight from a binary file can't be indexed, read them
+    /// onto the heap or convert them to a snapshot first.
+    pub fn build_hnsw(&mut self, options: &HnswOptions) -> Result<(), W2VError> {
+        let rows = self.lookup.rows().ok_or(W2VError::NotIndexable)?;
+        self.hnsw = Some(hnsw::Hnsw::build(&rows, options));
+        Ok(())
+    }
+
+    pub fn save_hnsw(&self, path: PathBuf) -> Result<(), W2VError> {
+        let hnsw = self.hnsw.as_ref().ok_or(W2VError::NoIndex)?;
+        let f = match fs::File::create(path) {
+            Ok(pointer) => pointer,
+            Err(_) => return Err(W2VError::CouldNotCreateFile),
+        };
+        hnsw.write(BufWriter::with_capacity(1000000, f))
+    }
+
+    /// Loads an index saved by `save_hnsw`, searched with the default
+    /// ef_search until `set_hnsw_ef` says otherwise. Fails with
+    /// IndexMismatch if it was built for different words or vectors.
+    pub fn load_hnsw(&mut self, path: PathBuf) -> Result<(), W2VError> {
+        let rows = self.lookup.rows().ok_or(W2VError::NotIndexable)?;
+        if !path.exists() {
+            return Err(W2VError::NoFileAtPath);
+        }
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(W2VError::CouldNotOpenFile),
+        };
+        let index = hnsw::Hnsw::read(&bytes, HnswOptions::default().ef_search)?;
+        if index.fingerprint() != rows.fingerprint() {
+            return Err(W2VError::IndexMismatch);
+        }
+        self.hnsw = Some(index);
+        Ok(())
+    }
+
+    pub fn set_hnsw_ef(&mut self, ef_search: usize) {
+        if let Some(hnsw) = &mut self.hnsw {
+            hnsw.set_ef_search(ef_search);
+        }
+    }
+
+    pub fn has_hnsw(&self) -> bool {
+        self.hnsw.is_some()
+    }
+
+    // Like open_hnsw, for the IVF-PQ index
+    fn open_ivfpq(&mut self, model_path: &Path, options: &IvfPqOptions) -> Result<(), W2VError> {
+        if model_path.as_os_str() == "-" {
+            return Err(W2VError::NotIndexable);
+        }
+        let path = Self::ivfpq_path(model_path);
+        match self.load_ivfpq(path.clone()) {
+            Ok(()) => {
+                self.set_ivfpq_search(options.probes, options.rerank);
+                Ok(())
+            }
+            Err(W2VError::NoFileAtPath) | Err(W2VError::IndexMismatch) | Err(W2VError::IndexVersion(_)) => {
+                self.train_ivfpq(options)?;
+                self.save_ivfpq(path)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Where the IVF-PQ index for the model at `model_path` is kept: next to
+    /// it, with `.ivfpq` added to the name
+    pub fn ivfpq_path(model_path: &Path) -> PathBuf {
+        let mut name = model_path.as_os_str().to_owned();
+        name.push(".ivfpq");
+        PathBuf::from(name)
+    }
+
+    /// Trains an IVF-PQ index over every word, replacing any the model had.
+    /// As with build_hnsw, models mapped straight from a binary file can't
+    /// be indexed.
+    pub fn train_ivfpq(&mut self, options: &IvfPqOptions) -> Result<(), W2VError> {
+        let rows = self.lookup.rows().ok_or(W2VError::NotIndexable)?;
+        self.ivfpq = Some(ivfpq::IvfPq::train(&rows, options));
+        Ok(())
+    }
+
+    pub fn save_ivfpq(&self, path: PathBuf) -> Result<(), W2VError> {
+        let ivfpq = self.ivfpq.as_ref().ok_or(W2VError::NoIndex)?;
+        let f = match fs::File::create(path) {
+            Ok(pointer) => pointer,
+            Err(_) => return Err(W2VError::CouldNotCreateFile),
+        };
+        ivfpq.write(BufWriter::with_capacity(1000000, f))
+    }
+
+    /// Loads an index saved by `save_ivfpq`, searched with the default
+    /// probes and no re-ranking until `set_ivfpq_search` says otherwise.
+    /// Fails with IndexMismatch if it was built for different words or vectors.
+    pub fn load_ivfpq(&mut self, path: PathBuf) -> Result<(), W2VError> {
+        let rows = self.lookup.rows().ok_or(W2VError::NotIndexable)?;
+        if !path.exists() {
+            return Err(W2VError::NoFileAtPath);
+        }
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(W2VError::CouldNotOpenFile),
+        };
+        let index = ivfpq::IvfPq::read(&bytes, IvfPqOptions::default().probes, 0)?;
+        if index.fingerprint() != rows.fingerprint() {
+            return Err(W2VError::IndexMismatch);
+        }
+        self.ivfpq = Some(index);
+        Ok(())
+    }
+
+    pub fn set_ivfpq_search(&mut self, probes: usize, rerank: usize) {
+        if let Some(ivfpq) = &mut self.ivfpq {
+            ivfpq.set_search(probes, rerank);
+        }
+    }
+
+    pub fn has_ivfpq(&self) -> bool {
+        self.ivfpq.is_some()
+    }
+
+    /// Splits each nearest neighbour query across `threads` threads, 0 for
+    /// every available core
+    pub fn set_search_threads(&mut self, threads: usize) {
+        // more threads than words would have nothing to do
+        self.search = SearchPool::new(resolve_threads(threads).min(self.lookup.len().max(1)));
+    }
+
+    fn load(model_path: PathBuf, options: &LoadOptions) -> Result<Model, W2VError> {
+        if model_path.as_os_str() == "-" {
+            let mut reader = BufReader::with_capacity(1000000, std::io::stdin());
+            let compression = detect_compression(&mut reader)?;
+            return Self::from_stream(decompress(reader, compression), options);
+        }
         if !model_path.exists() {
             return Err(W2VError::NoFileAtPath);
         }
-        // read in binary file
         let f = match fs::File::open(model_path) {
             Ok(pointer) => pointer,
             Err(_) => return Err(W2VError::CouldNotOpenFile),
         };
-        let mut reader = BufReader::with_capacity(100000000, f);
-        let mut first_line: String = String::new();
-        if reader.read_line(&mut first_line).unwrap() == 0 {
+        let file_len = match f.metadata() {
+            Ok(metadata) => metadata.len() as usize,
+            Err(_) => return Err(W2VError::CouldNotOpenFile),
+        };
+        // The start of the file says how to read it. Only the readers that
+        // stream through the file get the big buffer, a mapped one needs none.
+        let mut peek = Vec::with_capacity(FORMAT_PEEK_BYTES);
+        if (&f).take(FORMAT_PEEK_BYTES as u64).read_to_end(&mut peek).is_err() || (&f).seek(SeekFrom::Start(0)).is_err() {
             return Err(W2VError::ReadError(0));
         }
+        match detect_compression(&mut peek.as_slice())? {
+            Compression::None => {}
+            compression => return Self::from_stream(decompress(BufReader::with_capacity(100000000, f), compression), options),
+        }
+        let format = match options.format {
+            Format::Auto => detect_format(&peek),
+            format => format,
+        };
+        match format {
+            Format::Snapshot => {
+                let snapshot = snapshot::Snapshot::open(&f)?;
+                if !options.filter.is_empty() {
+                    return Ok(snapshot::filtered(&snapshot, &options.filter));
+                }
+                Ok(Model {
+                    total_words: snapshot.total_words(),
+                    size: snapshot.size(),
+                    lookup: Lookup::Snapshot(snapshot),
+                    subwords: None,
+                    search: SearchPool::new(1),
+                    hnsw: None,
+                    ivfpq: None,
+                    utf8: Utf8Policy::default(),
+                })
+            }
+            Format::Text | Format::FastText if options.mmap => Err(W2VError::NotMappable),
+            Format::FastText => fasttext::read_fasttext(BufReader::with_capacity(100000000, f), Some(file_len), &options.filter, options.utf8),
+            Format::Text => text::read_text(BufReader::with_capacity(100000000, f), Some(file_len), &options.filter, options.utf8),
+            _ if options.mmap => {
+                let (total_words, vectors) = mapped::MappedVectors::open(&f, &options.filter, options.utf8)?;
+                Ok(Model {
+                    total_words,
+                    size: vectors.size(),
+                    lookup: Lookup::Mapped(vectors),
+                    subwords: None,
+                    search: SearchPool::new(1),
+                    hnsw: None,
+                    ivfpq: None,
+                    utf8: Utf8Policy::default(),
+                })
+            }
+            _ if options.threads != 1 => {
+                let map = match unsafe { Mmap::map(&f) } {
+                    Ok(map) => map,
+                    Err(_) => return Err(W2VError::CouldNotOpenFile),
+                };
+                parallel::read_binary_parallel(&map, resolve_threads(options.threads), &options.filter, options.utf8)
+            }
+            _ => Self::read_binary(BufReader::with_capacity(100000000, f), Some(file_len), &options.filter, options.utf8),
+        }
+    }
 
-        let items: Vec<&str> = first_line.split_ascii_whitespace().collect();
-        let total_words: usize = items[0].parse::<usize>().unwrap();
-        let size: usize = items[1].parse::<usize>().unwrap();
+    // Anything that is not a plain file: stdin or a decompressed archive.
+    // Nothing can be mapped, and the parallel parser needs the whole model
+    // in memory before it can split it up.
+    fn from_stream(stream: Box<dyn Read>, options: &LoadOptions) -> Result<Model, W2VError> {
+        let (peek, stream) = peek_stream(stream)?;
+        let mut reader = BufReader::with_capacity(100000000, stream);
+        let format = match options.format {
+            Format::Auto => detect_format(&peek),
+            format => format,
+        };
+        match format {
+            Format::Snapshot => Err(W2VError::NotMappable),
+            _ if options.mmap => Err(W2VError::NotMappable),
+            Format::FastText => fasttext::read_fasttext(reader, None, &options.filter, options.utf8),
+            Format::Text => text::read_text(reader, None, &options.filter, options.utf8),
+            _ if options.threads != 1 => {
+                let mut bytes: Vec<u8> = Vec::new();
+                if reader.read_to_end(&mut bytes).is_err() {
+                    return Err(W2VError::ReadError(bytes.len()));
+                }
+                parallel::read_binary_parallel(&bytes, resolve_threads(options.threads), &options.filter, options.utf8)
+            }
+            _ => Self::read_binary(reader, None, &options.filter, options.utf8),
+        }
+    }
+
+    fn read_binary<R: BufRead>(mut reader: R, file_len: Option<usize>, filter: &VocabFilter, utf8: Utf8Policy) -> Result<Model, W2VError> {
+        let mut first_line: Vec<u8> = Vec::with_capacity(MAX_HEADER_BYTES);
+        if reader.by_ref().take(MAX_HEADER_BYTES as u64).read_until(b'\n', &mut first_line).is_err() {
+            return Err(W2VError::ReadError(0));
+        }
+        let (total_words, size, mut offset) = mapped::parse_header(&first_line)?;
 
-        let mut lookup: HashMap<String,Vec<f32>> = HashMap::with_capacity(total_words); 
+        let mut lookup = Matrix::with_capacity(filter.capacity(capacity_hint(total_words, size*4, file_len)), size);
         let mut mode: ReadMode = ReadMode::Word;
         let mut current_vector: Vec<f32> = Vec::with_capacity(size);
         let mut current_value: f32;
-        let mut current_value_byte_buffer: Vec<u8> = Vec::with_capacity(size);
-        let mut current_word: String = String::with_capacity(50);
+        let mut current_value_byte_buffer: Vec<u8> = Vec::with_capacity(4);
+        let mut current_word: Vec<u8> = Vec::with_capacity(50);
+        let mut record: usize = 0;
+        let mut record_start: usize = offset;
         for byte_opt in reader.bytes() {
-            match byte_opt {
-                Ok(byte) => {
-                    match mode {
-                        ReadMode::Word => match byte {
-                            b' ' => {
-                                mode = ReadMode::Vector;
-                            }
-                            b'\n' => { // ignore \n's
+            let byte = match byte_opt {
+                Ok(byte) => byte,
+                Err(_) => return Err(W2VError::ReadError(offset)),
+            };
+            match mode {
+                ReadMode::Word => match byte {
+                    b' ' => {
+                        mode = ReadMode::Vector;
+                    }
+                    b'\n' => { // ignore \n's
+                    }
+                    _ => {
+                        if current_word.is_empty() {
+                            record_start = offset;
+                        }
+                        if current_word.len() == MAX_WORD_BYTES {
+                            return Err(W2VError::WordTooLong { record, offset: record_start });
+                        }
+                        current_word.push(byte);
+                    },
+                },
+                ReadMode::Vector => {
+                    current_value_byte_buffer.push(byte);
+                    if current_value_byte_buffer.len() == 4 {
+                        current_value =
+                            LittleEndian::read_f32(&current_value_byte_buffer);
+                        current_vector.push(current_value);
+                        current_value_byte_buffer.clear();
+                        if current_vector.len() == size {
+                            let word = match decode_word(&current_word, utf8) {
+                                Some(word) => word,
+                                None => return Err(W2VError::InvalidUtf8 { record, offset: record_start }),
+                            };
+                            if filter.keeps(&word) {
+                                lookup.insert(word, &current_vector);
                             }
-                            _ => {
-                                current_word.push(byte as char);
-                            },
-                        },
-                        ReadMode::Vector => {
-                            match byte {
-                                _ => {
-                                    current_value_byte_buffer.push(byte);
-                                    if current_value_byte_buffer.len() == 4 {
-                                        current_value =
-                                            LittleEndian::read_f32(&current_value_byte_buffer);
-                                        current_vector.push(current_value);
-                                        current_value_byte_buffer.clear();
-                                        if current_vector.len() == size {
-                                            lookup.insert(current_word.clone(), current_vector.clone());
-                                            current_word.clear();
-                                            current_vector.clear();
-                                            mode = ReadMode::Word;
-                                        }
-                                    }
-                                }
+                            current_word.clear();
+                            current_vector.clear();
+                            record += 1;
+                            mode = ReadMode::Word;
+                            if filter.is_full(lookup.len()) {
+                                // the rest of the file is never read, so can't be checked
+                                return Ok(Model {
+                                    total_words: lookup.len(),
+                                    size,
+                                    lookup: Lookup::Owned(lookup),
+                                    subwords: None,
+                                    search: SearchPool::new(1),
+                                    hnsw: None,
+                                    ivfpq: None,
+                                    utf8: Utf8Policy::default(),
+                                });
                             }
                         }
                     }
                 }
-                Err(_) => {
-                    // EoF in this case
-                    println!("reached end of file");
-                    break;
-                }
             }
+            offset += 1;
+        }
+        if !current_word.is_empty() || !current_vector.is_empty() || !current_value_byte_buffer.is_empty() {
+            return Err(W2VError::TruncatedRecord { record, offset: record_start });
         }
+        check_word_count(total_words, record)?;
         Ok(Model {
-            total_words,
+            total_words: filter.total_words(total_words, lookup.len()),
             size,
-            lookup,
+            lookup: Lookup::Owned(lookup),
+            subwords: None,
+            search: SearchPool::new(1),
+            hnsw: None,
+            ivfpq: None,
+            utf8: Utf8Policy::default(),
         })
     }
 
-    pub fn word2vec(&self, word: &String) -> Option<&Vec<f32>> {
+    /// Writes the model to `model_path`, keeping the vocabulary order of the
+    /// file it was read from. `Format::Auto` picks text for .txt and .vec
+    /// paths, a snapshot for .snap paths and binary for anything else.
+    pub fn save(&self, model_path: PathBuf, format: Format) -> Result<(), W2VError> {
+        let format = match format {
+            Format::Auto => match model_path.extension().and_then(|extension| extension.to_str()) {
+                Some("txt") | Some("vec") => Format::Text,
+                Some("snap") => Format::Snapshot,
+                _ => Format::Binary,
+            },
+            format => format,
+        };
+        let f = match fs::File::create(model_path) {
+            Ok(pointer) => pointer,
+            Err(_) => return Err(W2VError::CouldNotCreateFile),
+        };
+        if format == Format::Snapshot {
+            return snapshot::write_snapshot(self, f);
+        }
+        let writer = BufWriter::with_capacity(1000000, f);
+        match format {
+            Format::Text => save::write_text(self, writer),
+            _ => save::write_binary(self, writer),
+        }
+    }
+
+    /// The vector of a word in the vocabulary, borrowed from the model.
+    pub fn word2vec(&self, word: &str) -> Option<&[f32]> {
         if self.lookup.contains_key(word) {
             self.lookup.get(word)
         } else {
@@ -112,49 +1355,453 @@ impl Model {
         }
     }
 
-    // returns closest word to the given vec, and its error vec
-    pub fn vec2word(&self, ref_vec: &Vec<f32>) -> SortedCosines {
-        let mut cosines: HashMap<String,f32> = HashMap::with_capacity(self.lookup.len());
-        for (key,value) in self.lookup.iter() {
-            cosines.insert(key.clone(), Self::cosine(ref_vec,value));
+    /// Like word2vec, but a model with subword information (fastText .bin)
+    /// builds a vector for an unknown word from its character n-grams. The
+    /// flag is true when the vector was built that way.
+    pub fn word2vec_subwords(&self, word: &str) -> Option<(Cow<'_, [f32]>, bool)> {
+        if let Some(vector) = self.lookup.get(word) {
+            return Some((Cow::Borrowed(vector), false));
+        }
+        let mut vector = self.subwords.as_ref()?.vector(word)?;
+        // match the vocabulary, if that was normalized when it was loaded
+        if let Lookup::Owned(Matrix { normalized: true, .. }) = &self.lookup {
+            let scale = inverse(norm(&vector));
+            vector.iter_mut().for_each(|value| *value *= scale);
+        }
+        Some((Cow::Owned(vector), true))
+    }
+
+    pub fn has_subwords(&self) -> bool {
+        self.subwords.is_some()
+    }
+
+    // returns closest word to the given vec, and its error vec
+    pub fn vec2word(&self, ref_vec: &[f32]) -> SortedCosines {
+        self.vec2word_by(ref_vec, Metric::Cosine)
+    }
+
+    /// Every word scored against `ref_vec` by `metric`, closest first
+    pub fn vec2word_by(&self, ref_vec: &[f32], metric: Metric) -> SortedCosines {
+        let mut cosines: HashMap<String,f32> = HashMap::with_capacity(self.lookup.len());
+        for (key,score) in self.scores(ref_vec, norm(ref_vec), metric) {
+            cosines.insert(key.to_string(), score);
+        }
+        SortedCosines::new(cosines, metric)
+    }
+
+    pub fn get_cosines(&self, word: &str) -> Option<HashMap<String,f32>> {
+        self.get_scores(word, Metric::Cosine)
+    }
+
+    /// Every word scored against `word` by `metric`. None if the word isn't
+    /// in the vocabulary.
+    pub fn get_scores(&self, word: &str, metric: Metric) -> Option<HashMap<String,f32>> {
+        let mut return_map: HashMap<String,f32> = HashMap::with_capacity(self.lookup.len());
+        let (ref_vec, ref_norm) = self.lookup.get_with_norm(word)?;
+        for (key,score) in self.scores(&ref_vec, ref_norm, metric) {
+            return_map.insert(key.to_string(), score);
+        }
+        Some(return_map)
+    }
+
+    pub fn get_sorted_cosines(&self, word: &str) -> Option<SortedCosines> {
+        self.get_sorted_scores(word, Metric::Cosine)
+    }
+
+    /// Like get_scores, closest first
+    pub fn get_sorted_scores(&self, word: &str, metric: Metric) -> Option<SortedCosines> {
+        if let Some(cosines) = self.get_scores(word, metric) {
+            Some(SortedCosines::new(cosines, metric))
+        } else {
+            println!("No cosine result");
+            None
+        }
+    }
+
+    /// The k words closest to `word` by cosine, best first, leaving out the
+    /// word itself. None if the word isn't in the vocabulary. Unlike
+    /// get_sorted_cosines this only ever holds k entries, so it's the one to
+    /// use when just the first few matter.
+    pub fn most_similar(&self, word: &str, k: usize) -> Option<Vec<(String, f32)>> {
+        self.most_similar_by(word, k, Metric::Cosine)
+    }
+
+    /// The k words closest to `vector` by cosine, best first
+    pub fn most_similar_vec(&self, vector: &[f32], k: usize) -> Vec<(String, f32)> {
+        self.most_similar_vec_by(vector, k, Metric::Cosine)
+    }
+
+    /// Like most_similar, closest first by `metric`
+    pub fn most_similar_by(&self, word: &str, k: usize, metric: Metric) -> Option<Vec<(String, f32)>> {
+        let (query, query_norm) = self.lookup.get_with_norm(word)?;
+        Some(to_owned(self.top_k(&query, query_norm, k, &[word], metric)))
+    }
+
+    /// Like most_similar_vec, closest first by `metric`
+    pub fn most_similar_vec_by(&self, vector: &[f32], k: usize, metric: Metric) -> Vec<(String, f32)> {
+        to_owned(self.top_k(vector, norm(vector), k, &[], metric))
+    }
+
+    /// Every word at least as close to `word` as `threshold`, closest first
+    /// and leaving out the word itself: for a similarity those scoring
+    /// `threshold` or more, for a distance those at `threshold` or less.
+    /// With a `limit`, only that many of the closest. None if the word isn't
+    /// in the vocabulary.
+    pub fn within(&self, word: &str, threshold: f32, metric: Metric, limit: Option<usize>) -> Option<Vec<(String, f32)>> {
+        let (query, query_norm) = self.lookup.get_with_norm(word)?;
+        let limit = limit.unwrap_or(usize::MAX);
+        Some(to_owned(self.top_k_within(&query, query_norm, limit, &[word], metric.key_of(threshold), metric)))
+    }
+
+    /// Like within, around a vector
+    pub fn within_vec(&self, vector: &[f32], threshold: f32, metric: Metric, limit: Option<usize>) -> Vec<(String, f32)> {
+        let limit = limit.unwrap_or(usize::MAX);
+        to_owned(self.top_k_within(vector, norm(vector), limit, &[], metric.key_of(threshold), metric))
+    }
+
+    /// The k words closest to each of `words` by `metric`, as most_similar_by
+    /// would find them one at a time, but in a single pass over the
+    /// vocabulary that scores each tile of it against the whole batch while
+    /// it's in cache. None for words that aren't in the vocabulary.
+    pub fn most_similar_batch(&self, words: &[&str], k: usize, metric: Metric) -> Vec<Option<Vec<(String, f32)>>> {
+        let found: Vec<Option<(Cow<'_, [f32]>, f32)>> = words.iter().map(|word| self.lookup.get_with_norm(word)).collect();
+        let queries: Vec<(&[f32], f32)> = found.iter().flatten().map(|(vector, norm)| (vector.as_ref(), *norm)).collect();
+        // one more each, in case the word itself is among them
+        let mut tops = self.top_k_batch(&queries, k.saturating_add(1), metric).into_iter();
+        words.iter().zip(found.iter()).map(|(word, found)| {
+            found.as_ref().map(|_| {
+                let top = tops.next().unwrap();
+                to_owned(top.into_iter().filter(|(other, _)| other != word).take(k).collect())
+            })
+        }).collect()
+    }
+
+    /// The k words closest to each of `vectors` by `metric`, in one pass like
+    /// most_similar_batch. None for vectors that aren't as long as the
+    /// model's.
+    pub fn most_similar_vec_batch(&self, vectors: &[&[f32]], k: usize, metric: Metric) -> Vec<Option<Vec<(String, f32)>>> {
+        let queries: Vec<(&[f32], f32)> = vectors.iter()
+            .filter(|vector| vector.len() == self.size)
+            .map(|vector| (*vector, norm(vector)))
+            .collect();
+        let mut tops = self.top_k_batch(&queries, k, metric).into_iter();
+        vectors.iter().map(|vector| {
+            if vector.len() == self.size {
+                Some(to_owned(tops.next().unwrap()))
+            } else {
+                None
+            }
+        }).collect()
+    }
+
+    /// Which of `words` doesn't belong: every word is scored by the cosine
+    /// between its vector and the mean of all their unit vectors, and the
+    /// lowest scoring comes first. Words not in the vocabulary play no part
+    /// and are listed apart.
+    pub fn doesnt_match(&self, words: &[&str]) -> DoesntMatch {
+        let (units, unknown) = self.units(words);
+        let mean = self.mean(&units);
+        let scale = inverse(norm(&mean));
+        let mut ranked: Vec<(String, f32)> = units.iter()
+            .map(|(word, unit)| (word.to_string(), kernels::dot(unit, &mean)*scale))
+            .collect();
+        // stable, so equal scores keep the order they were given in
+        ranked.sort_by(|a, b| a.1.total_cmp(&b.1));
+        DoesntMatch { ranked, unknown }
+    }
+
+    /// How alike two sets of words are: the cosine between the mean of the
+    /// unit vectors of `set_a` and that of `set_b`. Words not in the
+    /// vocabulary play no part and are listed apart for each set.
+    pub fn n_similarity(&self, set_a: &[&str], set_b: &[&str]) -> SetSimilarity {
+        let (units_a, unknown_a) = self.units(set_a);
+        let (units_b, unknown_b) = self.units(set_b);
+        let similarity = if units_a.is_empty() || units_b.is_empty() {
+            None
+        } else {
+            let (mean_a, mean_b) = (self.mean(&units_a), self.mean(&units_b));
+            Some(kernels::dot(&mean_a, &mean_b)*inverse(norm(&mean_a))*inverse(norm(&mean_b)))
+        };
+        SetSimilarity { similarity, unknown_a, unknown_b }
+    }
+
+    /// The cosine between every word of `set_a` and every word of `set_b`
+    /// in the vocabulary. Words that aren't have neither a row nor a column
+    /// and are listed apart for each set.
+    pub fn similarity_matrix(&self, set_a: &[&str], set_b: &[&str]) -> SimilarityMatrix {
+        let (units_a, unknown_a) = self.units(set_a);
+        let (units_b, unknown_b) = self.units(set_b);
+        let queries: Vec<kernels::Floats> = units_a.iter().map(|(_, unit)| unit.into()).collect();
+        let rows: Vec<kernels::Floats> = units_b.iter().map(|(_, unit)| unit.into()).collect();
+        let mut out = vec![0.0; queries.len()*rows.len()];
+        kernels::dot_tile(&queries, &rows, &mut out);
+        let values = if rows.is_empty() {
+            vec![Vec::new(); queries.len()]
+        } else {
+            out.chunks(rows.len()).map(|row| row.to_vec()).collect()
+        };
+        SimilarityMatrix {
+            rows: units_a.iter().map(|(word, _)| word.to_string()).collect(),
+            columns: units_b.iter().map(|(word, _)| word.to_string()).collect(),
+            values,
+            unknown_a,
+            unknown_b,
+        }
+    }
+
+    // The unit vector of each word in the vocabulary, and the words that
+    // aren't, both in the order given
+    fn units<'a>(&self, words: &[&'a str]) -> (Vec<(&'a str, Vec<f32>)>, Vec<String>) {
+        let mut units = Vec::with_capacity(words.len());
+        let mut unknown = Vec::new();
+        for word in words.iter() {
+            match self.lookup.get_with_norm(word) {
+                Some((vector, norm)) => {
+                    let scale = inverse(norm);
+                    units.push((*word, vector.iter().map(|value| value*scale).collect()));
+                },
+                None => unknown.push(word.to_string()),
+            }
+        }
+        (units, unknown)
+    }
+
+    // Sum of unit vectors, which points the same way as their mean
+    fn mean(&self, units: &[(&str, Vec<f32>)]) -> Vec<f32> {
+        let mut mean = vec![0.0; self.size];
+        for (_, unit) in units.iter() {
+            mean.iter_mut().zip(unit).for_each(|(mean, value)| *mean += value);
+        }
+        mean
+    }
+
+    /// The k words that best complete an analogy, best first, leaving out
+    /// the query words. "man is to king as woman is to ?" has `positive`
+    /// king and woman and `negative` man. Fails with UnknownWords if any
+    /// query word isn't in the vocabulary.
+    pub fn analogy(&self, positive: &[&str], negative: &[&str], k: usize, objective: Analogy) -> Result<Vec<(String, f32)>, W2VError> {
+        let unknown: Vec<String> = positive.iter().chain(negative)
+            .filter(|word| !self.lookup.contains_key(word))
+            .map(|word| word.to_string())
+            .collect();
+        if !unknown.is_empty() {
+            return Err(W2VError::UnknownWords(unknown));
+        }
+        if positive.is_empty() && negative.is_empty() {
+            return Ok(Vec::new());
+        }
+        let unit = |word: &str| {
+            let (vector, norm) = self.lookup.get_with_norm(word).unwrap();
+            let scale = inverse(norm);
+            vector.iter().map(|value| value*scale).collect::<Vec<f32>>()
+        };
+        let skip: Vec<&str> = positive.iter().chain(negative).cloned().collect();
+        let top = match objective {
+            Analogy::Add => {
+                let mut query = vec![0.0; self.size];
+                for (words, sign) in [(positive, 1.0), (negative, -1.0)].iter() {
+                    for word in words.iter() {
+                        query.iter_mut().zip(unit(word)).for_each(|(query, value)| *query += sign*value);
+                    }
+                }
+                self.top_k(&query, norm(&query), k, &skip, Metric::Cosine)
+            },
+            Analogy::Mul => {
+                let positive: Vec<Vec<f32>> = positive.iter().map(|word| unit(word)).collect();
+                let negative: Vec<Vec<f32>> = negative.iter().map(|word| unit(word)).collect();
+                self.top_k_by(k, &skip, f32::NEG_INFINITY, |vector, norm| {
+                    let scale = inverse(norm);
+                    let shifted = |query: &Vec<f32>| (kernels::dot(query, vector)*scale + 1.0)/2.0;
+                    positive.iter().map(shifted).product::<f32>() / (negative.iter().map(shifted).product::<f32>() + COSMUL_EPSILON)
+                })
+            },
+        };
+        Ok(to_owned(top))
+    }
+
+    /// Like most_similar, with the choice of an exact or approximate search
+    pub fn search(&self, word: &str, k: usize, search: Search) -> Option<Vec<(String, f32)>> {
+        let (query, query_norm) = self.lookup.get_with_norm(word)?;
+        Some(to_owned(self.top_k_with(&query, query_norm, k, &[word], search)))
+    }
+
+    /// Like most_similar_vec, with the choice of an exact or approximate
+    /// search
+    pub fn search_vec(&self, vector: &[f32], k: usize, search: Search) -> Vec<(String, f32)> {
+        to_owned(self.top_k_with(vector, norm(vector), k, &[], search))
+    }
+
+    /// Recall@k of approximate search against exact search, over `queries`
+    /// words spread evenly through the vocabulary. None without an index.
+    pub fn recall(&self, k: usize, queries: usize) -> Option<Recall> {
+        if self.hnsw.is_none() && self.ivfpq.is_none() {
+            return None;
         }
+        let words = self.lookup.words();
+        let step = (words.len() / queries.max(1)).max(1);
+        let queries: Vec<&str> = words.iter().cloned().step_by(step).take(queries).collect();
+        let (mut found, mut wanted) = (0, 0);
+        let (mut exact_seconds, mut approximate_seconds) = (0.0, 0.0);
+        for word in queries.iter() {
+            let start = Instant::now();
+            let exact = self.search(word, k, Search::Exact).unwrap_or_default();
+            exact_seconds += start.elapsed().as_secs_f64();
+            let start = Instant::now();
+            let approximate = self.search(word, k, Search::Approximate).unwrap_or_default();
+            approximate_seconds += start.elapsed().as_secs_f64();
 
-        // This could be heavily multi-threaded
-        let mut keys : Vec<String> = cosines.keys().map(|input| (*input).clone()).collect();
-        keys.sort_by(|a,b| (*cosines.get(b).unwrap()).partial_cmp(cosines.get(a).unwrap()).unwrap() );
-
-        SortedCosines {
-            cosines,
-            keys,
+            let approximate: HashSet<&str> = approximate.iter().map(|(word, _)| word.as_str()).collect();
+            found += exact.iter().filter(|(word, _)| approximate.contains(word.as_str())).count();
+            wanted += exact.len();
         }
+        let count = queries.len().max(1) as f64;
+        Some(Recall {
+            k,
+            queries: queries.len(),
+            recall: if wanted == 0 { 1.0 } else { found as f64 / wanted as f64 },
+            exact_seconds: exact_seconds / count,
+            approximate_seconds: approximate_seconds / count,
+        })
     }
 
-    pub fn get_cosines(&self, word: &String) -> Option<HashMap<String,f32>> {
-        let mut return_map: HashMap<String,f32> = HashMap::with_capacity(self.lookup.len());
-        let ref_vec: Vec<f32> = match self.word2vec(word) {
-            Some(val) => val.clone(),
-            None => return None,
+    fn top_k_with<'a>(&'a self, query: &[f32], query_norm: f32, k: usize, skip: &[&str], search: Search) -> Vec<(&'a str, f32)> {
+        let rows = match (search, self.lookup.rows()) {
+            (Search::Approximate, Some(rows)) => rows,
+            _ => return self.top_k(query, query_norm, k, skip, Metric::Cosine),
+        };
+        // more, in case the skipped words are among them
+        let want = k + skip.len();
+        let found = match (&self.hnsw, &self.ivfpq) {
+            (Some(hnsw), _) => hnsw.search(&rows, query, query_norm, want),
+            (None, Some(ivfpq)) => ivfpq.search(&rows, query, query_norm, want),
+            (None, None) => return self.top_k(query, query_norm, k, skip, Metric::Cosine),
+        };
+        found.into_iter()
+            .map(|(row, score)| (rows.word(row as usize), score))
+            .filter(|(word, _)| !skip.contains(word))
+            .take(k)
+            .collect()
+    }
+
+    fn top_k<'a>(&'a self, query: &[f32], query_norm: f32, k: usize, skip: &[&str], metric: Metric) -> Vec<(&'a str, f32)> {
+        self.top_k_within(query, query_norm, k, skip, f32::NEG_INFINITY, metric)
+    }
+
+    // Like top_k, only keeping words whose key is at least `floor`
+    fn top_k_within<'a>(&'a self, query: &[f32], query_norm: f32, k: usize, skip: &[&str], floor: f32, metric: Metric) -> Vec<(&'a str, f32)> {
+        let scale = inverse(query_norm);
+        let top = self.top_k_by(k, skip, floor, |vector, norm| metric.key(query, scale, vector, norm));
+        top.into_iter().map(|(word, key)| (word, metric.score(key))).collect()
+    }
+
+    // The k best words by `score`, given each word's vector and norm, of
+    // those scoring at least `floor`. Each thread keeps the top k of its own
+    // contiguous share of the vocabulary, and those are merged at the end.
+    // Ties are broken on the position in the whole scan, so the answer
+    // doesn't depend on how the work was split.
+    fn top_k_by<'a, F: Fn(kernels::Floats, f32) -> f32 + Sync>(&'a self, k: usize, skip: &[&str], floor: f32, score: F) -> Vec<(&'a str, f32)> {
+        let scan = |start: usize, end: usize| {
+            let mut top = topk::TopK::new(k);
+            for (position, (word, vector, norm)) in self.lookup.iter_range(start, end).enumerate() {
+                if !skip.contains(&word) {
+                    let score = score(vector, norm);
+                    if score >= floor {
+                        top.push(start + position, word, score);
+                    }
+                }
+            }
+            top
         };
-        for (key,value) in self.lookup.iter() {
-            return_map.insert(key.clone(), Self::cosine(&ref_vec,value));
+
+        let mut top = topk::TopK::new(k);
+        for part in self.search.map(self.lookup.len(), scan) {
+            top.merge(part);
         }
-        Some(return_map)
+        top.into_sorted_vec()
     }
 
-    pub fn get_sorted_cosines(&self, word: &String) -> Option<SortedCosines> {
-        if let Some(cosines) = self.get_cosines(word) {
-            // This could be heavily multi-threaded
-            let mut keys : Vec<String> = cosines.keys().map(|input| (*input).clone()).collect();
-            keys.sort_by(|a,b| (*cosines.get(b).unwrap()).partial_cmp(cosines.get(a).unwrap()).unwrap() );
-            
-            return Some(SortedCosines {
-                cosines,
-                keys,
-            });
+    // The top k of each query, (vector, norm), all of the model's dimension.
+    // Dot products come from the tile kernel, and with them cosines and,
+    // through |q - r|^2 = |q|^2 + |r|^2 - 2 q.r, Euclidean distances, which
+    // can differ in the last bits from those worked out directly. Manhattan
+    // has no such shortcut, so it's only tiled for the sake of the cache.
+    fn top_k_batch<'a>(&'a self, queries: &[(&[f32], f32)], k: usize, metric: Metric) -> Vec<Vec<(&'a str, f32)>> {
+        let queries: Vec<BatchQuery> = queries.iter().map(|(vector, norm)| BatchQuery {
+            vector,
+            scale: inverse(*norm),
+            square: kernels::dot(*vector, *vector),
+        }).collect();
+        let tile_rows = (TILE_BYTES / (self.size.max(1)*4)).max(2);
+        let scan = |start: usize, end: usize| {
+            let mut tops: Vec<topk::TopK<'a>> = queries.iter().map(|_| topk::TopK::new(k)).collect();
+            let mut rows = self.lookup.iter_range(start, end);
+            let mut tile = Vec::with_capacity(tile_rows);
+            let mut out = vec![0.0; QUERY_TILE.min(queries.len())*tile_rows];
+            let mut position = start;
+            loop {
+                tile.clear();
+                tile.extend(rows.by_ref().take(tile_rows));
+                if tile.is_empty() {
+                    break;
+                }
+                let vectors: Vec<kernels::Floats> = tile.iter().map(|(_, vector, _)| *vector).collect();
+                let squares: Vec<f32> = match metric {
+                    Metric::Euclidean => vectors.iter().map(|vector| kernels::dot(*vector, *vector)).collect(),
+                    _ => Vec::new(),
+                };
+                for (block, batch) in queries.chunks(QUERY_TILE).enumerate() {
+                    let out = &mut out[..batch.len()*vectors.len()];
+                    if metric == Metric::Manhattan {
+                        for (query, out) in batch.iter().zip(out.chunks_exact_mut(vectors.len())) {
+                            for (out, vector) in out.iter_mut().zip(vectors.iter()) {
+                                *out = kernels::manhattan(query.vector, *vector);
+                            }
+                        }
+                    } else {
+                        let batch: Vec<kernels::Floats> = batch.iter().map(|query| query.vector.into()).collect();
+                        kernels::dot_tile(&batch, &vectors, out);
+                    }
+                    for (index, (query, out)) in batch.iter().zip(out.chunks_exact(vectors.len())).enumerate() {
+                        let top = &mut tops[block*QUERY_TILE + index];
+                        for (row, ((word, _, norm), value)) in tile.iter().zip(out.iter()).enumerate() {
+                            let key = match metric {
+                                Metric::Cosine => value*query.scale*inverse(*norm),
+                                Metric::Dot => *value,
+                                Metric::Euclidean => -(query.square + squares[row] - 2.0*value).max(0.0),
+                                Metric::Manhattan => -value,
+                            };
+                            top.push(position + row, word, key);
+                        }
+                    }
+                }
+                position += tile.len();
+            }
+            tops
+        };
+
+        let mut tops = if queries.is_empty() {
+            Vec::new()
         } else {
-            println!("No cosine result");
-            return None
-        }
+            let mut parts = self.search.map(self.lookup.len(), scan).into_iter();
+            let mut tops = parts.next().unwrap_or_default();
+            for part in parts {
+                for (top, other) in tops.iter_mut().zip(part) {
+                    top.merge(other);
+                }
+            }
+            tops
+        };
+        tops.drain(..).map(|top| {
+            top.into_sorted_vec().into_iter().map(|(word, key)| (word, metric.score(key))).collect()
+        }).collect()
+    }
+
+    /// The score of two words by `metric`, None unless both are in the
+    /// vocabulary
+    pub fn get_score(&self, worda: &str, wordb: &str, metric: Metric) -> Option<f32> {
+        let (vec_a, norm_a) = self.lookup.get_with_norm(worda)?;
+        let (vec_b, norm_b) = self.lookup.get_with_norm(wordb)?;
+        Some(metric.score(metric.key(&vec_a, inverse(norm_a), vec_b.as_ref().into(), norm_b)))
     }
 
     pub fn get_cosine(&self, worda: String, wordb: String) -> Option<f32> {
@@ -166,23 +1813,149 @@ impl Model {
     }
 
     fn get_cosine_unchecked(&self, worda: String, wordb: String) -> f32 {
-        Self::cosine(self.lookup.get(&worda).unwrap(),self.lookup.get(&wordb).unwrap())
+        let (vec_a, norm_a) = self.lookup.get_with_norm(&worda).unwrap();
+        let (vec_b, norm_b) = self.lookup.get_with_norm(&wordb).unwrap();
+        kernels::dot(vec_a.as_ref(), vec_b.as_ref())*inverse(norm_a)*inverse(norm_b)
+    }
+
+    // for vectors whose norms aren't known
+    #[allow(dead_code)]
+    fn cosine(vec_a: &[f32],vec_b: &[f32]) -> f32 {
+        kernels::cosine(vec_a, vec_b)
+    }
+
+    // `query` scored against every word. The norms are all known up front,
+    // so a cosine costs one dot product.
+    fn scores<'a>(&'a self, query: &'a [f32], query_norm: f32, metric: Metric) -> impl Iterator<Item=(&'a str, f32)> + 'a {
+        let scale = inverse(query_norm);
+        self.lookup.iter_with_norms().map(move |(word, vector, norm)| (word, metric.score(metric.key(query, scale, vector, norm))))
+    }
+}
+
+fn to_owned(scores: Vec<(&str, f32)>) -> Vec<(String, f32)> {
+    scores.into_iter().map(|(word, score)| (word.to_string(), score)).collect()
+}
+
+fn norm(vector: &[f32]) -> f32 {
+    kernels::dot(vector, vector).sqrt()
+}
+
+// 1/norm, with zero vectors scoring 0 against everything rather than NaN
+fn inverse(norm: f32) -> f32 {
+    if norm > 0.0 {
+        1.0/norm
+    } else {
+        0.0
+    }
+}
+
+// How much of the start of a model detect_format looks at
+const FORMAT_PEEK_BYTES: usize = 4096;
+
+// a stream with the bytes already read from it put back in front
+type Peeked<R> = std::io::Chain<std::io::Cursor<Vec<u8>>, R>;
+
+// Reads the first FORMAT_PEEK_BYTES of a stream, or all of it if it is
+// shorter, and returns them along with a reader that still starts at the
+// beginning. A single read from a pipe or a decompressor can come up short
+// well before the end of the first record.
+fn peek_stream<R: Read>(mut stream: R) -> Result<(Vec<u8>, Peeked<R>), W2VError> {
+    let mut peeked = Vec::with_capacity(FORMAT_PEEK_BYTES);
+    if stream.by_ref().take(FORMAT_PEEK_BYTES as u64).read_to_end(&mut peeked).is_err() {
+        return Err(W2VError::ReadError(0));
     }
+    Ok((peeked.clone(), std::io::Cursor::new(peeked).chain(stream)))
+}
+
+// Looks at the start of a model, at most FORMAT_PEEK_BYTES. fastText's magic
+// number means a fastText model, the snapshot magic a snapshot, a header
+// line of two integers followed by vector data made only of decimal text
+// means a text model, a first line with more than two fields means a
+// headerless (GloVe) text model, anything else is treated as Google's
+// binary layout.
+fn detect_format(peek: &[u8]) -> Format {
+    let peek = &peek[..peek.len().min(FORMAT_PEEK_BYTES)];
+    if peek.len() >= 4 && LittleEndian::read_i32(peek) == fasttext::FASTTEXT_MAGIC {
+        return Format::FastText;
+    }
+    if peek.starts_with(snapshot::SNAPSHOT_MAGIC) {
+        return Format::Snapshot;
+    }
+    let header_end = match peek.iter().position(|b| *b == b'\n') {
+        Some(index) => index,
+        None => return Format::Binary,
+    };
+    let header = String::from_utf8_lossy(&peek[..header_end]);
+    let fields: Vec<&str> = header.split_ascii_whitespace().collect();
+    if fields.len() > 2 {
+        return Format::Text;
+    }
+    let body = &peek[header_end+1..];
+    let values = match body.iter().position(|b| *b == b' ') {
+        Some(index) => &body[index+1..],
+        None => return Format::Binary,
+    };
+    let values = match values.iter().position(|b| *b == b'\n') {
+        Some(index) => &values[..index],
+        None => values,
+    };
+    let is_text = !values.is_empty() && values.iter().take(64).all(|b| {
+        b.is_ascii_digit() || b" \t\r+-.eEnaifNAIF".contains(b)
+    });
+    if is_text {
+        Format::Text
+    } else {
+        Format::Binary
+    }
+}
+
+// Where Utf8Policy::Raw puts the bytes of invalid sequences
+const RAW_BYTE_BASE: u32 = 0x10FF00;
 
-    fn cosine(vec_a: &Vec<f32>,vec_b: &Vec<f32>) -> f32 {
-        let mut sum: f32 = 0.0;
-        let mut norm_a: f32 = 0.0;
-        let mut norm_b: f32 = 0.0;
-        for (val_a,val_b) in vec_a.iter().zip(vec_b) {
-            sum += val_a*val_b;
-            norm_a += val_a.powi(2);
-            norm_b += val_b.powi(2);
+// Decodes a word read from a model file. None means the word is not valid
+// UTF-8 and the policy is to reject it.
+fn decode_word(bytes: &[u8], policy: Utf8Policy) -> Option<String> {
+    match (std::str::from_utf8(bytes), policy) {
+        (Ok(word), _) => Some(word.to_string()),
+        (Err(_), Utf8Policy::Reject) => None,
+        (Err(_), Utf8Policy::Lossy) => Some(String::from_utf8_lossy(bytes).into_owned()),
+        (Err(_), Utf8Policy::Raw) => {
+            let mut word = String::with_capacity(bytes.len()*2);
+            let mut rest = bytes;
+            while let Err(error) = std::str::from_utf8(rest) {
+                let (valid, invalid) = rest.split_at(error.valid_up_to());
+                word.push_str(std::str::from_utf8(valid).unwrap());
+                // None means the word ends part way through a sequence
+                let length = error.error_len().unwrap_or(invalid.len());
+                for byte in &invalid[..length] {
+                    word.push(std::char::from_u32(RAW_BYTE_BASE + *byte as u32).unwrap());
+                }
+                rest = &invalid[length..];
+            }
+            word.push_str(std::str::from_utf8(rest).unwrap());
+            Some(word)
         }
-        norm_a = norm_a.powf(0.5);
-        norm_b = norm_b.powf(0.5);
+    }
+}
 
-        sum/(norm_a*norm_b)
+// Inverse of decode_word: UTF-8, with any raw bytes put back as they were.
+// Only Utf8Policy::Raw makes raw bytes, so under any other policy the chars
+// they are kept as are ordinary private use chars of the word.
+fn encode_word(word: &str, policy: Utf8Policy) -> Vec<u8> {
+    let is_raw = |c: char| c as u32 >= RAW_BYTE_BASE;
+    if policy != Utf8Policy::Raw || !word.chars().any(is_raw) {
+        return word.as_bytes().to_vec();
     }
+    let mut bytes: Vec<u8> = Vec::with_capacity(word.len());
+    let mut buffer = [0; 4];
+    for c in word.chars() {
+        if is_raw(c) {
+            bytes.push((c as u32 - RAW_BYTE_BASE) as u8);
+        } else {
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+        }
+    }
+    bytes
 }
 
 #[allow(dead_code)]
@@ -193,22 +1966,32 @@ pub struct SortedCosines {
 
 #[allow(dead_code)]
 impl SortedCosines {
+    fn new(cosines: HashMap<String,f32>, metric: Metric) -> SortedCosines {
+        // This could be heavily multi-threaded
+        let mut keys : Vec<String> = cosines.keys().cloned().collect();
+        keys.sort_by(|a,b| metric.compare(cosines[a], cosines[b]));
+        SortedCosines {
+            cosines,
+            keys,
+        }
+    }
+
     pub fn get_nth_top(&self, n: usize) -> (std::string::String, f32) {
         let key = self.keys[n].clone();
-        let res = (*self.cosines.get(&key).unwrap()).clone();
+        let res = *self.cosines.get(&key).unwrap();
         (key,res)
     }
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
     use std::time::Instant;
     #[test]
     fn t01_init_model_false_path() -> Result<(), ()> {
         match Model::new(PathBuf::from("/tmp/nothing")) {
-            Err(_) => return Ok(()),
-            Ok(_) => return Err(()),
+            Err(_) => Ok(()),
+            Ok(_) => Err(()),
         }
     }
 
@@ -216,22 +1999,22 @@ mod test {
     fn t02_init_model_small() -> Result<(), ()> {
         if let Ok(_model) = Model::new(PathBuf::from("./test_material/vectors.bin")) {
             // if let Ok(_model) = Model::new(PathBuf::from("./test_material/GoogleNews-vectors-negative300.bin")) {
-            return Ok(());
+            Ok(())
         } else {
-            return Err(());
+            Err(())
         }
     }
 
     #[test]
     fn t03_do_lookups_small() -> Result<(), String> {
         if let Ok(model) = Model::new(PathBuf::from("./test_material/vectors.bin")) {
-            let words = vec!["the","one","in"];
+            let words = ["the","one","in"];
             let mut sum: f32 = 0.0;
             for word in words.iter() {
-                match model.word2vec(&String::from(*word)) {
+                match model.word2vec(word) {
                     Some(vector) => {
                         let mut local_sum: f32 = 0.0;
-                        for val in vector {
+                        for val in vector.iter() {
                             local_sum += val;
                         }
                         sum += local_sum/(vector.len() as f32);
@@ -241,11 +2024,11 @@ mod test {
                     }
                 }
             }
-            sum = sum/(words.len() as f32);
+            sum /= words.len() as f32;
             println!("sum: {}",sum);
-            return Ok(());
+            Ok(())
         } else {
-            return Err("Could not create model".to_string());
+            Err("Could not create model".to_string())
         }
     }
 
@@ -255,9 +2038,9 @@ mod test {
         if let Ok(_model) = Model::new(PathBuf::from("./test_material/GoogleNews-vectors-negative300.bin")) {
             let finish_time = Instant::now();
             println!("load time: {}",(finish_time-start_time).as_secs());
-            return Ok(());
+            Ok(())
         } else {
-            return Err(());
+            Err(())
         }
     }
 
@@ -265,13 +2048,13 @@ mod test {
     #[ignore]
     fn t05_do_lookups_big() -> Result<(), String> {
         if let Ok(model) = Model::new(PathBuf::from("./test_material/GoogleNews-vectors-negative300.bin")) {
-            let words = vec!["the","one","in"];
+            let words = ["the","one","in"];
             let mut sum: f32 = 0.0;
             for word in words.iter() {
-                match model.word2vec(&String::from(*word)) {
+                match model.word2vec(word) {
                     Some(vector) => {
                         let mut local_sum: f32 = 0.0;
-                        for val in vector {
+                        for val in vector.iter() {
                             local_sum += val;
                         }
                         sum += local_sum/(vector.len() as f32);
@@ -281,50 +2064,50 @@ mod test {
                     }
                 }
             }
-            sum = sum/(words.len() as f32);
+            sum /= words.len() as f32;
             println!("sum: {}",sum);
-            return Ok(());
+            Ok(())
         } else {
-            return Err("Could not create model".to_string());
+            Err("Could not create model".to_string())
         }
     }
 
     #[test]
     fn t06_get_top5_cosine() -> Result<(), String> {
         if let Ok(model) = Model::new(PathBuf::from("./test_material/vectors.bin")) {
-            let words : Vec<String> = vec!["italy","france","paris","rome"].iter().map(|input| (*input).to_string()).collect();
-            for word_i in 0..words.len() {
-                if let Some(result) = model.get_sorted_cosines(&words[word_i]) {
-                    println!("\nMatching against {}",words[word_i]);
+            let words : Vec<String> = ["italy","france","paris","rome"].iter().map(|input| (*input).to_string()).collect();
+            for word in words.iter() {
+                if let Some(result) = model.get_sorted_cosines(word) {
+                    println!("\nMatching against {}",word);
                     // print top 10
                     for i in 1..4 {
                         let nth = result.get_nth_top(i);
                         println!("{}-> {} - {},",i,nth.0,nth.1);
                     }
                 } else {
-                    println!("No match for {}",words[word_i])
+                    println!("No match for {}",word)
                 }
             }
-            return Ok(());
+            Ok(())
         } else {
-            return Err("Could not create model".to_string());
+            Err("Could not create model".to_string())
         }
     }
 
     #[test]
     fn t07_vector_word_maths()-> Result<(), String> {
         if let Ok(model) = Model::new(PathBuf::from("./test_material/GoogleNews-vectors-negative300.bin")) {
-            let paris_vec = model.word2vec(&"king".to_string()).unwrap();
-            let france_vec = model.word2vec(&"man".to_string()).unwrap();
-            let italy_vec = model.word2vec(&"woman".to_string()).unwrap();
+            let paris_vec = model.word2vec("king").unwrap();
+            let france_vec = model.word2vec("man").unwrap();
+            let italy_vec = model.word2vec("woman").unwrap();
 
-            let new_vec = add_vec(&subtract_vec(&paris_vec, &france_vec),&italy_vec);
+            let new_vec = add_vec(&subtract_vec(paris_vec, france_vec),italy_vec);
 
             let res = model.vec2word(&new_vec);
 
-            let rome_vec = model.word2vec(&"queen".to_string()).unwrap();
+            let rome_vec = model.word2vec("queen").unwrap();
 
-            println!("ideal -> queen - {},",Model::cosine(&rome_vec, &new_vec));
+            println!("ideal -> queen - {},",Model::cosine(rome_vec, &new_vec));
 
 
             for i in 0..20 {
@@ -332,17 +2115,843 @@ mod test {
                 println!("{}-> {} - {},",i,nth.0,nth.1);
             }
 
-            return Ok(());
+            Ok(())
         } else {
-            return Err("Could not create model".to_string());
+            Err("Could not create model".to_string())
+        }
+    }
+
+    #[test]
+    fn t08_load_text_with_header() -> Result<(), String> {
+        let path = write_test_file("header.vec", b"3 2\nthe 0.5 -1\none 1e-1 2.25 \nin 0 0\n");
+        let options = LoadOptions { format: Format::Text, ..LoadOptions::default() };
+        let model = Model::with_options(path, &options).map_err(|e| format!("{:?}",e))?;
+        assert_eq!(model.total_words, 3);
+        assert_eq!(model.size, 2);
+        assert_eq!(model.word2vec("the"), Some(&[0.5,-1.0][..]));
+        assert_eq!(model.word2vec("one"), Some(&[0.1,2.25][..]));
+        Ok(())
+    }
+
+    #[test]
+    fn t09_load_glove_without_header() -> Result<(), String> {
+        let path = write_test_file("glove.txt", b"the 0.1 0.2 0.3\n\nof -0.1 -0.2 -0.3\n");
+        let model = Model::new(path).map_err(|e| format!("{:?}",e))?;
+        assert_eq!(model.total_words, 2);
+        assert_eq!(model.size, 3);
+        assert_eq!(model.word2vec("of"), Some(&[-0.1,-0.2,-0.3][..]));
+        Ok(())
+    }
+
+    #[test]
+    fn t10_detect_binary_and_text() -> Result<(), String> {
+        let words = [("the", vec![0.25,-1.5]), ("one", vec![3.0,0.125])];
+        let mut text = format!("{} 2\n", words.len());
+        for (word, vector) in words.iter() {
+            text.push_str(&format!("{} {} {}\n", word, vector[0], vector[1]));
+        }
+        let text_model = Model::new(write_test_file("detect.txt", text.as_bytes())).map_err(|e| format!("{:?}",e))?;
+        let binary_model = Model::new(write_binary_model("detect.bin", &words)).map_err(|e| format!("{:?}",e))?;
+        for (word, vector) in words.iter() {
+            assert_eq!(text_model.word2vec(word), Some(&vector[..]));
+            assert_eq!(binary_model.word2vec(word), Some(&vector[..]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t11_mmap_matches_heap() -> Result<(), String> {
+        // word lengths chosen so vectors land on both aligned and unaligned offsets
+        let words = [("a", vec![1.0,-2.0,0.5]), ("bb", vec![0.0,4.25,-8.0]), ("ccc", vec![3.5,0.125,1.0]), ("dddd", vec![-1.0,-1.0,2.0])];
+        let path = write_binary_model("mmap.bin", &words);
+        let heap = Model::new(path.clone()).map_err(|e| format!("{:?}",e))?;
+        let options = LoadOptions { mmap: true, ..LoadOptions::default() };
+        let mapped = Model::with_options(path, &options).map_err(|e| format!("{:?}",e))?;
+        assert_eq!(mapped.total_words, heap.total_words);
+        assert_eq!(mapped.size, heap.size);
+        for (word, vector) in words.iter() {
+            assert_eq!(mapped.word2vec(word), Some(&vector[..]));
+            // an unaligned row is only decoded the first time
+            assert_eq!(mapped.word2vec(word).map(|vector| vector.as_ptr()), mapped.word2vec(word).map(|vector| vector.as_ptr()));
+            assert_eq!(mapped.get_cosine(word.to_string(), "a".to_string()), heap.get_cosine(word.to_string(), "a".to_string()));
+        }
+        assert!(mapped.word2vec("e").is_none());
+
+        let text = write_test_file("mmap.txt", b"1 2\nthe 0.5 -1\n");
+        match Model::with_options(text, &options) {
+            Err(W2VError::NotMappable) => Ok(()),
+            other => Err(format!("expected NotMappable, got {:?}", other)),
+        }
+    }
+
+    #[test]
+    fn t12_parallel_matches_sequential() -> Result<(), String> {
+        let names: Vec<String> = (0..500).map(|i| format!("w{}", "x".repeat(i % 7) + &i.to_string())).collect();
+        let mut words: Vec<(&str, Vec<f32>)> = names.iter().enumerate()
+            .map(|(i, name)| (name.as_str(), vec![i as f32, -(i as f32)/3.0, 0.5]))
+            .collect();
+        // a repeated word keeps its last vector in both loaders
+        words.push(("w0", vec![9.0,9.0,9.0]));
+        let path = write_binary_model("parallel.bin", &words);
+        let sequential = Model::new(path.clone()).map_err(|e| format!("{:?}",e))?;
+        for threads in [3, 0, 1000].iter() {
+            let options = LoadOptions { threads: *threads, ..LoadOptions::default() };
+            let parallel = Model::with_options(path.clone(), &options).map_err(|e| format!("{:?}",e))?;
+            assert_eq!(parallel.total_words, sequential.total_words);
+            assert_eq!(parallel.size, sequential.size);
+            assert_eq!(parallel.lookup.len(), sequential.lookup.len());
+            assert_eq!(parallel.lookup.words(), sequential.lookup.words());
+            for (word, vector, _) in sequential.lookup.iter_with_norms() {
+                assert_eq!(parallel.word2vec(word).map(|vector| vector.to_vec()), Some(vector.to_vec()));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t13_reject_malformed_binary() -> Result<(), String> {
+        let mut good = write_binary_bytes(&[("the", vec![1.0,2.0]), ("one", vec![3.0,4.0])]);
+        // header is 4 bytes, each record is word + space + 8 bytes + \n
+        let mut long_word = b"1 2\n".to_vec();
+        long_word.extend(vec![b'x'; MAX_WORD_BYTES + 1]);
+        long_word.extend_from_slice(b" 12345678\n");
+        let cases: Vec<(&str, Vec<u8>, &str)> = vec![
+            ("garbage", b"not a header\n".to_vec(), "BadHeader(0)"),
+            ("no_newline", b"2 2".to_vec(), "BadHeader(3)"),
+            ("bad_count", b"2 x\nthe 12345678".to_vec(), "BadHeader(2)"),
+            ("zero_dim", b"2 0\n".to_vec(), "BadDimension(0)"),
+            ("huge_dim", b"2 100000000\n".to_vec(), "BadDimension(100000000)"),
+            ("truncated", good[..good.len() - 3].to_vec(), "TruncatedRecord { record: 1, offset: 17 }"),
+            ("long_word", long_word, "WordTooLong { record: 0, offset: 4 }"),
+        ];
+        good[0] = b'5';
+        let cases = cases.into_iter().chain(vec![("mismatch", good, "WordCountMismatch { expected: 5, found: 2 }")]);
+        for (name, contents, expected) in cases {
+            let path = write_test_file(&format!("malformed_{}.bin", name), &contents);
+            for options in [LoadOptions::default(), LoadOptions { mmap: true, ..LoadOptions::default() }, LoadOptions { threads: 2, ..LoadOptions::default() }].iter() {
+                let options = LoadOptions { format: Format::Binary, ..options.clone() };
+                match Model::with_options(path.clone(), &options) {
+                    Err(error) => assert_eq!(format!("{:?}", error), expected, "{} {:?}", name, options),
+                    Ok(_) => return Err(format!("{} loaded with {:?}", name, options)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t14_save_round_trip() -> Result<(), String> {
+        let words = [("zeta", vec![0.1,-2.5e-8,3.0]), ("alpha", vec![f32::MAX,1.0/3.0,-0.0]), ("caf\u{e9}", vec![7.0,8.0,9.0])];
+        let original = Model::new(write_binary_model("save_source.bin", &words)).map_err(|e| format!("{:?}",e))?;
+        for (name, format) in [("save.bin", Format::Binary), ("save.txt", Format::Auto), ("save.vec", Format::Text)].iter() {
+            let path = write_test_file(name, b"");
+            original.save(path.clone(), *format).map_err(|e| format!("{:?}",e))?;
+            let loaded = Model::new(path).map_err(|e| format!("{:?}",e))?;
+            assert_eq!(loaded.total_words, words.len());
+            assert_eq!(loaded.size, original.size);
+            assert_eq!(loaded.lookup.words(), original.lookup.words());
+            for word in original.lookup.words() {
+                assert_eq!(loaded.word2vec(word), original.word2vec(word), "{} in {}", word, name);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t15_fasttext_subwords() -> Result<(), String> {
+        // one bucket, so every n-gram of every word lands on the same row
+        let path = write_fasttext_model("subwords.bin", &["</s>", "ab"], &[1.0,1.0, 3.0,0.0, 0.0,3.0]);
+        let model = Model::new(path.clone()).map_err(|e| format!("{:?}",e))?;
+        assert!(model.has_subwords());
+        assert_eq!(model.total_words, 2);
+        assert_eq!(model.size, 2);
+        // </s> has no n-grams, "ab" averages its row with "<ab" and "ab>"
+        assert_eq!(model.word2vec("</s>"), Some(&[1.0,1.0][..]));
+        assert_eq!(model.word2vec("ab"), Some(&[1.0,2.0][..]));
+        assert_eq!(model.word2vec_subwords("ab").map(|(vector, synthesized)| (vector.into_owned(), synthesized)), Some((vec![1.0,2.0], false)));
+        assert!(model.word2vec("xyz").is_none());
+        assert_eq!(model.word2vec_subwords("xyz").map(|(vector, synthesized)| (vector.into_owned(), synthesized)), Some((vec![0.0,3.0], true)));
+
+        let options = LoadOptions { mmap: true, ..LoadOptions::default() };
+        match Model::with_options(path, &options) {
+            Err(W2VError::NotMappable) => Ok(()),
+            other => Err(format!("expected NotMappable, got {:?}", other)),
+        }
+    }
+
+    #[test]
+    fn t16_load_compressed() -> Result<(), String> {
+        let words = [("the", vec![0.25,-1.5]), ("one", vec![3.0,0.125])];
+        let binary = write_binary_bytes(&words);
+        let text = b"2 2\nthe 0.25 -1.5\none 3 0.125\n";
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&binary).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(text).unwrap();
+        let files = [("compressed.bin.gz", gzip.finish().unwrap()), ("compressed.txt.xz", xz.finish().unwrap())];
+
+        for (name, contents) in files.iter() {
+            let path = write_test_file(name, contents);
+            for threads in [1, 2].iter() {
+                let options = LoadOptions { threads: *threads, ..LoadOptions::default() };
+                let model = Model::with_options(path.clone(), &options).map_err(|e| format!("{:?}",e))?;
+                assert_eq!(model.total_words, 2);
+                for (word, vector) in words.iter() {
+                    assert_eq!(model.word2vec(word), Some(&vector[..]), "{}", name);
+                }
+            }
+            let options = LoadOptions { mmap: true, ..LoadOptions::default() };
+            assert!(matches!(Model::with_options(path, &options), Err(W2VError::NotMappable)));
+        }
+
+        // a pipe can hand over less than the header line in one read
+        struct Trickle(std::io::Cursor<Vec<u8>>);
+        impl Read for Trickle {
+            fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+                let length = buffer.len().min(3);
+                self.0.read(&mut buffer[..length])
+            }
+        }
+        let model = Model::from_stream(Box::new(Trickle(std::io::Cursor::new(text.to_vec()))), &LoadOptions::default())
+            .map_err(|e| format!("{:?}",e))?;
+        assert_eq!(model.word2vec("one"), Some(&[3.0, 0.125][..]));
+        Ok(())
+    }
+
+    #[test]
+    fn t17_filter_vocabulary() -> Result<(), String> {
+        let names = ["the", "##ab", "http://x", "a", "one", "two"];
+        let words: Vec<(&str, Vec<f32>)> = names.iter().enumerate().map(|(i, name)| (*name, vec![i as f32, 1.0])).collect();
+        let binary = write_binary_model("filter.bin", &words);
+        let text = write_test_file("filter.txt", names.iter().enumerate()
+            .map(|(i, name)| format!("{} {} 1\n", name, i)).collect::<String>().as_bytes());
+
+        let noise = VocabFilter {
+            max_words: Some(2),
+            exclude: Some(Regex::new("^##|^http").unwrap()),
+            min_length: 2,
+            ..VocabFilter::default()
+        };
+        let allowed = VocabFilter {
+            allowlist: Some(["a", "two", "missing"].iter().map(|word| word.to_string()).collect()),
+            include: Some(Regex::new("^[a-z]+$").unwrap()),
+            ..VocabFilter::default()
+        };
+        for (filter, expected) in [(noise, vec!["the", "one"]), (allowed, vec!["a", "two"])].iter() {
+            let loads = [
+                (binary.clone(), LoadOptions { filter: filter.clone(), ..LoadOptions::default() }),
+                (binary.clone(), LoadOptions { filter: filter.clone(), mmap: true, ..LoadOptions::default() }),
+                (binary.clone(), LoadOptions { filter: filter.clone(), threads: 2, ..LoadOptions::default() }),
+                (text.clone(), LoadOptions { filter: filter.clone(), ..LoadOptions::default() }),
+            ];
+            for (path, options) in loads.iter() {
+                let model = Model::with_options(path.clone(), options).map_err(|e| format!("{:?}",e))?;
+                assert_eq!(model.total_words, expected.len());
+                assert_eq!(&model.lookup.words(), expected);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t18_snapshot() -> Result<(), String> {
+        let words = [("zeta", vec![3.0,4.0,0.0]), ("caf\u{e9}", vec![0.5,-1.0,2.0]), ("a", vec![0.0,0.0,1.0])];
+        let original = Model::new(write_binary_model("snapshot_source.bin", &words)).map_err(|e| format!("{:?}",e))?;
+        let path = write_test_file("model.snap", b"");
+        original.save(path.clone(), Format::Auto).map_err(|e| format!("{:?}",e))?;
+
+        let loaded = Model::new(path.clone()).map_err(|e| format!("{:?}",e))?;
+        assert!(matches!(loaded.lookup, Lookup::Snapshot(_)));
+        assert_eq!(loaded.total_words, words.len());
+        assert_eq!(loaded.size, 3);
+        assert_eq!(loaded.lookup.words(), original.lookup.words());
+        for word in original.lookup.words() {
+            assert_eq!(loaded.word2vec(word), original.word2vec(word), "{}", word);
+        }
+        assert!(loaded.word2vec("missing").is_none());
+        if let Lookup::Snapshot(snapshot) = &loaded.lookup {
+            assert_eq!(snapshot.norm(0), 5.0);
+        }
+
+        let options = LoadOptions { filter: VocabFilter { min_length: 2, ..VocabFilter::default() }, ..LoadOptions::default() };
+        let filtered = Model::with_options(path.clone(), &options).map_err(|e| format!("{:?}",e))?;
+        assert_eq!(filtered.lookup.words(), &original.lookup.words()[..2]);
+
+        let bytes = fs::read(&path).unwrap();
+        let mut corrupt = bytes.clone();
+        let last = corrupt.len() - 200;
+        corrupt[last] ^= 1;
+        let mut stale = bytes.clone();
+        stale[8] += 1;
+        // a well formed file whose index has no empty slot would leave
+        // lookups of missing words probing forever
+        let mut full = bytes;
+        let slots = LittleEndian::read_u64(&full[48..]) as usize;
+        let index_start = full.len() - (slots*4).div_ceil(64)*64;
+        for slot in 0..slots {
+            LittleEndian::write_u32(&mut full[index_start + slot*4..], 1);
+        }
+        let mut checksum = snapshot::Checksum::new();
+        checksum.update(&full[..56]);
+        checksum.update(&full[64..]);
+        LittleEndian::write_u64(&mut full[56..], checksum.finish());
+        for (name, contents) in [("corrupt.snap", corrupt), ("stale.snap", stale), ("full.snap", full)].iter() {
+            match Model::new(write_test_file(name, contents)) {
+                Err(W2VError::ChecksumMismatch) if *name == "corrupt.snap" => {}
+                Err(W2VError::SnapshotVersion(2)) if *name == "stale.snap" => {}
+                Err(W2VError::BadHeader(_)) if *name == "full.snap" => {}
+                other => return Err(format!("{}: unexpected {:?}", name, other.map(|model| model.total_words))),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t19_utf8_words() -> Result<(), String> {
+        let words = [("caf\u{e9}", vec![1.0,0.0]), ("\u{6771}\u{4eac}", vec![0.0,1.0]), ("\u{1f642}", vec![1.0,1.0]), ("na\u{ef}ve", vec![2.0,0.0])];
+        let mut binary = write_binary_bytes(&words);
+        // "na\xefve": a lone Latin-1 byte where UTF-8 wants a sequence
+        let bad = binary.windows(2).position(|pair| pair == "\u{ef}".as_bytes()).unwrap();
+        binary.splice(bad..bad + 2, [0xef]);
+        binary[0] = b'4';
+        let path = write_test_file("utf8.bin", &binary);
+        let text = write_test_file("utf8.txt", "3 2\ncaf\u{e9} 1 0\n\u{6771}\u{4eac} 0 1\n\u{1f642} 1 1\n".as_bytes());
+
+        let loads = [
+            LoadOptions { utf8: Utf8Policy::Raw, ..LoadOptions::default() },
+            LoadOptions { utf8: Utf8Policy::Raw, mmap: true, ..LoadOptions::default() },
+            LoadOptions { utf8: Utf8Policy::Raw, threads: 2, ..LoadOptions::default() },
+        ];
+        for options in loads.iter() {
+            let model = Model::with_options(path.clone(), options).map_err(|e| format!("{:?}",e))?;
+            for (word, vector) in words[..3].iter() {
+                assert_eq!(model.word2vec(word), Some(&vector[..]), "{}", word);
+            }
+            assert_eq!(model.word2vec("na\u{10ffef}ve"), Some(&[2.0,0.0][..]));
+
+            // raw bytes are written back exactly as they were read
+            let saved = write_test_file("utf8_saved.bin", b"");
+            model.save(saved.clone(), Format::Binary).map_err(|e| format!("{:?}",e))?;
+            assert_eq!(fs::read(&saved).unwrap(), binary);
+        }
+
+        let model = Model::new(path.clone()).map_err(|e| format!("{:?}",e))?;
+        assert_eq!(model.word2vec("na\u{fffd}ve"), Some(&[2.0,0.0][..]));
+        let model = Model::new(text).map_err(|e| format!("{:?}",e))?;
+        assert_eq!(model.word2vec("\u{6771}\u{4eac}"), Some(&[0.0,1.0][..]));
+
+        for threads in [1, 2].iter() {
+            let options = LoadOptions { utf8: Utf8Policy::Reject, threads: *threads, ..LoadOptions::default() };
+            match Model::with_options(path.clone(), &options) {
+                Err(W2VError::InvalidUtf8 { record: 3, offset }) if offset == bad - 2 => {}
+                other => return Err(format!("expected InvalidUtf8, got {:?}", other.map(|model| model.total_words))),
+            }
+        }
+
+        // a valid word using the chars Raw keeps bytes as is just a word
+        let private = [("a\u{10ff41}", vec![1.0,2.0])];
+        let model = Model::new(write_binary_model("utf8_private.bin", &private)).map_err(|e| format!("{:?}",e))?;
+        let saved = write_test_file("utf8_private_saved.bin", b"");
+        model.save(saved.clone(), Format::Binary).map_err(|e| format!("{:?}",e))?;
+        assert_eq!(fs::read(&saved).unwrap(), write_binary_bytes(&private));
+        Ok(())
+    }
+
+    #[test]
+    fn t20_precomputed_norms() -> Result<(), String> {
+        let words = [("a", vec![3.0,4.0]), ("b", vec![1.0,0.0]), ("c", vec![-2.0,2.0]), ("zero", vec![0.0,0.0])];
+        let path = write_binary_model("norms.bin", &words);
+        let snapshot = write_test_file("norms.snap", b"");
+        Model::new(path.clone()).map_err(|e| format!("{:?}",e))?.save(snapshot.clone(), Format::Snapshot).map_err(|e| format!("{:?}",e))?;
+
+        let loads = [
+            (path.clone(), LoadOptions::default()),
+            (path.clone(), LoadOptions { normalize: true, ..LoadOptions::default() }),
+            (path.clone(), LoadOptions { mmap: true, ..LoadOptions::default() }),
+            (snapshot, LoadOptions::default()),
+        ];
+        for (path, options) in loads.iter() {
+            let model = Model::with_options(path.clone(), options).map_err(|e| format!("{:?}",e))?;
+            let cosines = model.get_cosines("a").unwrap();
+            for (word, vector) in words.iter() {
+                let expected = if *word == "zero" { 0.0 } else { Model::cosine(&words[0].1, vector) };
+                assert!((cosines[*word] - expected).abs() < 1e-6, "{} {:?}: {} != {}", word, options, cosines[*word], expected);
+            }
+            assert!((model.get_cosine("a".to_string(), "c".to_string()).unwrap() - 2.0/(5.0*8f32.sqrt())).abs() < 1e-6);
+            assert_eq!(model.vec2word(&[0.0,0.0]).get_nth_top(0).1, 0.0);
+        }
+
+        let normalized = Model::with_options(path, &LoadOptions { normalize: true, ..LoadOptions::default() }).map_err(|e| format!("{:?}",e))?;
+        assert_eq!(normalized.word2vec("a"), Some(&[0.6,0.8][..]));
+        assert_eq!(normalized.word2vec("zero"), Some(&[0.0,0.0][..]));
+        Ok(())
+    }
+
+    #[test]
+    fn t21_most_similar() -> Result<(), String> {
+        let words = [("a", vec![1.0,0.0]), ("b", vec![0.0,1.0]), ("c", vec![1.0,1.0]), ("d", vec![2.0,0.1]), ("e", vec![-1.0,0.0]), ("f", vec![4.0,4.0])];
+        let path = write_binary_model("most_similar.bin", &words);
+        for options in [LoadOptions::default(), LoadOptions { mmap: true, ..LoadOptions::default() }].iter() {
+            let model = Model::with_options(path.clone(), options).map_err(|e| format!("{:?}",e))?;
+            let top = model.most_similar("a", 3).unwrap();
+            let names: Vec<&str> = top.iter().map(|(word, _)| word.as_str()).collect();
+            // c and f point the same way, so tie, and come out in file order
+            // where the file order is known
+            assert_eq!(names[0], "d");
+            assert_eq!(top.len(), 3);
+            assert!((top[1].1 - 0.5f32.sqrt()).abs() < 1e-6 && (top[2].1 - 0.5f32.sqrt()).abs() < 1e-6);
+            assert!(top.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+
+            // the same as sorting every score
+            let sorted = model.get_sorted_cosines("a").unwrap();
+            assert_eq!(sorted.get_nth_top(1).1, top[0].1);
+            assert_eq!(model.most_similar("a", 100).unwrap().len(), words.len() - 1);
+            assert!(model.most_similar("a", 0).unwrap().is_empty());
+            assert!(model.most_similar("missing", 3).is_none());
+
+            let top = model.most_similar_vec(&[-1.0,0.0], 2);
+            assert_eq!(top[0], ("e".to_string(), 1.0));
+            assert_eq!(top.len(), 2);
+        }
+        let model = Model::new(path).map_err(|e| format!("{:?}",e))?;
+        let names: Vec<String> = model.most_similar("a", 3).unwrap().into_iter().map(|(word, _)| word).collect();
+        assert_eq!(names, ["d", "c", "f"]);
+        Ok(())
+    }
+
+    #[test]
+    fn t22_parallel_search() -> Result<(), String> {
+        // plenty of exact ties, so a merge that ignored scan order would show
+        let words: Vec<(String, Vec<f32>)> = (0..1000u32).map(|i| {
+            (format!("w{}", i), vec![(i % 7) as f32 - 3.0, (i % 11) as f32, (i % 5) as f32 * 0.5])
+        }).collect();
+        let words: Vec<(&str, Vec<f32>)> = words.iter().map(|(word, vector)| (word.as_str(), vector.clone())).collect();
+        let path = write_binary_model("parallel_search.bin", &words);
+        let snapshot = write_test_file("parallel_search.snap", b"");
+        Model::new(path.clone()).map_err(|e| format!("{:?}",e))?.save(snapshot.clone(), Format::Snapshot).map_err(|e| format!("{:?}",e))?;
+
+        for (path, mmap) in [(&path, false), (&path, true), (&snapshot, false)].iter() {
+            let mut model = Model::with_options((*path).clone(), &LoadOptions { mmap: *mmap, ..LoadOptions::default() }).map_err(|e| format!("{:?}",e))?;
+            let expected = model.most_similar("w3", 50).unwrap();
+            let expected_vec = model.most_similar_vec(&[1.0,-2.0,0.5], 1000);
+            for threads in [2, 3, 8, 0, 5000].iter() {
+                model.set_search_threads(*threads);
+                assert_eq!(model.most_similar("w3", 50).unwrap(), expected, "{} threads", threads);
+                assert_eq!(model.most_similar_vec(&[1.0,-2.0,0.5], 1000), expected_vec, "{} threads", threads);
+                assert!(model.most_similar("w3", 0).unwrap().is_empty());
+            }
+        }
+        let options = LoadOptions { search_threads: 4, ..LoadOptions::default() };
+        let model = Model::with_options(path, &options).map_err(|e| format!("{:?}",e))?;
+        assert_eq!(model.search.threads, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn t23_hnsw_index() -> Result<(), String> {
+        // real structure for the graph to find
+        let words = clustered_words(7, 3000, 24);
+        let words: Vec<(&str, Vec<f32>)> = words.iter().map(|(word, vector)| (word.as_str(), vector.clone())).collect();
+        let path = write_binary_model("hnsw.bin", &words);
+        let index = Model::hnsw_path(&path);
+        let _ = fs::remove_file(&index);
+
+        let mut model = Model::new(path.clone()).map_err(|e| format!("{:?}",e))?;
+        // without an index, approximate is exact
+        assert_eq!(model.search("w1", 10, Search::Approximate), model.most_similar("w1", 10));
+        assert!(model.recall(10, 100).is_none());
+        assert!(matches!(model.save_hnsw(index.clone()), Err(W2VError::NoIndex)));
+
+        // one thread builds the same graph every time, several build one
+        // that is just as good
+        let mut searches = Vec::new();
+        for threads in [1, 1, 4].iter() {
+            model.build_hnsw(&HnswOptions { threads: *threads, ..HnswOptions::default() }).map_err(|e| format!("{:?}",e))?;
+            let recall = model.recall(10, 200).unwrap();
+            assert_eq!((recall.k, recall.queries), (10, 200));
+            assert!(recall.recall > 0.95, "{} threads: {:?}", threads, recall);
+            searches.push(model.search("w7", 10, Search::Approximate));
+        }
+        assert_eq!(searches[0], searches[1]);
+        let found = model.search("w1", 10, Search::Approximate).unwrap();
+        assert_eq!(found.len(), 10);
+        assert!(found.iter().all(|(word, _)| word != "w1"));
+        assert_eq!(found[0], model.most_similar("w1", 1).unwrap()[0]);
+        let query = model.word2vec("w2").unwrap().to_vec();
+        assert_eq!(model.search_vec(&query, 1, Search::Approximate)[0].0, "w2");
+
+        // saved, and loaded back by a model from the same file or a snapshot
+        model.save_hnsw(index.clone()).map_err(|e| format!("{:?}",e))?;
+        let snapshot = write_test_file("hnsw.snap", b"");
+        model.save(snapshot.clone(), Format::Snapshot).map_err(|e| format!("{:?}",e))?;
+        for path in [&path, &snapshot].iter() {
+            let mut loaded = Model::new((*path).clone()).map_err(|e| format!("{:?}",e))?;
+            loaded.load_hnsw(index.clone()).map_err(|e| format!("{:?}",e))?;
+            assert_eq!(loaded.search("w1", 10, Search::Approximate), Some(found.clone()));
+        }
+
+        // with LoadOptions the index next to the model is used, or made
+        let options = LoadOptions { hnsw: Some(HnswOptions { ef_search: 100, ..HnswOptions::default() }), ..LoadOptions::default() };
+        let loaded = Model::with_options(path.clone(), &options).map_err(|e| format!("{:?}",e))?;
+        assert_eq!(loaded.search("w1", 10, Search::Approximate), Some(found.clone()));
+        let other = write_binary_model("hnsw_other.bin", &words[..100]);
+        let other_index = Model::hnsw_path(&other);
+        let _ = fs::remove_file(&other_index);
+        assert!(Model::with_options(other.clone(), &options).map_err(|e| format!("{:?}",e))?.has_hnsw());
+        assert!(other_index.exists());
+
+        // an index for another vocabulary, a damaged one, and a model that
+        // can't take one
+        let mut small = Model::new(other.clone()).map_err(|e| format!("{:?}",e))?;
+        assert!(matches!(small.load_hnsw(index.clone()), Err(W2VError::IndexMismatch)));
+        let retrained: Vec<(&str, Vec<f32>)> = words.iter().map(|(word, vector)| (*word, vector.iter().map(|value| -value).collect())).collect();
+        let mut retrained = Model::new(write_binary_model("hnsw_retrained.bin", &retrained)).map_err(|e| format!("{:?}",e))?;
+        assert!(matches!(retrained.load_hnsw(index.clone()), Err(W2VError::IndexMismatch)));
+        let mut bytes = fs::read(&index).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let damaged = write_test_file("hnsw_damaged.hnsw", &bytes);
+        assert!(matches!(model.load_hnsw(damaged), Err(W2VError::ChecksumMismatch)));
+        let mut mapped = Model::with_options(path, &LoadOptions { mmap: true, ..LoadOptions::default() }).map_err(|e| format!("{:?}",e))?;
+        assert!(matches!(mapped.build_hnsw(&HnswOptions::default()), Err(W2VError::NotIndexable)));
+        Ok(())
+    }
+
+    #[test]
+    fn t24_ivfpq_index() -> Result<(), String> {
+        let words = clustered_words(11, 3000, 24);
+        let words: Vec<(&str, Vec<f32>)> = words.iter().map(|(word, vector)| (word.as_str(), vector.clone())).collect();
+        let path = write_binary_model("ivfpq.bin", &words);
+        let index = Model::ivfpq_path(&path);
+        let _ = fs::remove_file(&index);
+
+        let options = IvfPqOptions { lists: 40, subspaces: 8, probes: 8, iterations: 10, ..IvfPqOptions::default() };
+        let mut model = Model::new(path.clone()).map_err(|e| format!("{:?}",e))?;
+        assert!(matches!(model.save_ivfpq(index.clone()), Err(W2VError::NoIndex)));
+        model.train_ivfpq(&options).map_err(|e| format!("{:?}",e))?;
+        let estimated = model.recall(10, 200).unwrap();
+        model.set_ivfpq_search(8, 50);
+        let reranked = model.recall(10, 200).unwrap();
+        assert!(estimated.recall > 0.5, "{:?}", estimated);
+        assert!(reranked.recall > 0.9 && reranked.recall > estimated.recall, "{:?}", reranked);
+
+        // re-ranked scores are exact, estimated ones close
+        let exact = model.most_similar("w5", 5).unwrap();
+        let found = model.search("w5", 5, Search::Approximate).unwrap();
+        assert_eq!(found[0], exact[0]);
+        assert!(found.iter().all(|(word, _)| word != "w5"));
+        model.set_ivfpq_search(8, 0);
+        let estimate = model.search("w5", 1, Search::Approximate).unwrap();
+        assert!((estimate[0].1 - model.get_cosine("w5".to_string(), estimate[0].0.clone()).unwrap()).abs() < 0.1);
+
+        // saved, and loaded back by a mapped snapshot of the same model
+        model.save_ivfpq(index.clone()).map_err(|e| format!("{:?}",e))?;
+        let snapshot = write_test_file("ivfpq.snap", b"");
+        model.save(snapshot.clone(), Format::Snapshot).map_err(|e| format!("{:?}",e))?;
+        let mut loaded = Model::new(snapshot).map_err(|e| format!("{:?}",e))?;
+        loaded.load_ivfpq(index.clone()).map_err(|e| format!("{:?}",e))?;
+        loaded.set_ivfpq_search(8, 0);
+        assert_eq!(loaded.search("w9", 10, Search::Approximate), model.search("w9", 10, Search::Approximate));
+
+        // LoadOptions loads the index next to the model, or trains one
+        let load = LoadOptions { ivfpq: Some(IvfPqOptions { rerank: 50, ..options.clone() }), ..LoadOptions::default() };
+        let loaded = Model::with_options(path.clone(), &load).map_err(|e| format!("{:?}",e))?;
+        assert_eq!(loaded.search("w5", 5, Search::Approximate).unwrap(), found);
+        let other = write_binary_model("ivfpq_other.bin", &words[..500]);
+        let _ = fs::remove_file(Model::ivfpq_path(&other));
+        assert!(Model::with_options(other.clone(), &load).map_err(|e| format!("{:?}",e))?.has_ivfpq());
+        assert!(Model::ivfpq_path(&other).exists());
+
+        let mut small = Model::new(other).map_err(|e| format!("{:?}",e))?;
+        assert!(matches!(small.load_ivfpq(index.clone()), Err(W2VError::IndexMismatch)));
+        let mut bytes = fs::read(&index).unwrap();
+        bytes[100] ^= 1;
+        let damaged = write_test_file("ivfpq_damaged.ivfpq", &bytes);
+        assert!(matches!(model.load_ivfpq(damaged), Err(W2VError::ChecksumMismatch)));
+        let mut mapped = Model::with_options(path, &LoadOptions { mmap: true, ..LoadOptions::default() }).map_err(|e| format!("{:?}",e))?;
+        assert!(matches!(mapped.train_ivfpq(&options), Err(W2VError::NotIndexable)));
+        Ok(())
+    }
+
+    #[test]
+    fn t25_analogy() -> Result<(), String> {
+        let words = [("man", vec![1.0,0.0,0.0]), ("woman", vec![0.0,1.0,0.0]), ("king", vec![2.0,0.0,2.0]), ("queen", vec![0.0,1.0,1.0]), ("apple", vec![0.2,-1.0,0.1])];
+        let path = write_binary_model("analogy.bin", &words);
+        for options in [LoadOptions::default(), LoadOptions { mmap: true, search_threads: 2, ..LoadOptions::default() }].iter() {
+            let model = Model::with_options(path.clone(), options).map_err(|e| format!("{:?}",e))?;
+            for objective in [Analogy::Add, Analogy::Mul].iter() {
+                let top = model.analogy(&["king", "woman"], &["man"], 10, *objective).map_err(|e| format!("{:?}",e))?;
+                let names: Vec<&str> = top.iter().map(|(word, _)| word.as_str()).collect();
+                // king and woman are closer than queen, but are left out
+                assert_eq!(names, ["queen", "apple"], "{:?}", objective);
+                assert!(top[0].1 > top[1].1);
+            }
+            // 3CosAdd is a cosine against the combined query
+            let top = model.analogy(&["king", "woman"], &["man"], 1, Analogy::Add).unwrap();
+            let query = [0.5f32.sqrt() - 1.0, 1.0, 0.5f32.sqrt()];
+            assert!((top[0].1 - kernels::cosine(&query, &[0.0, 1.0, 1.0])).abs() < 1e-6);
+            // 3CosMul is (1 + cos)/2 of queen with king times with woman,
+            // over with man
+            let top = model.analogy(&["king", "woman"], &["man"], 1, Analogy::Mul).unwrap();
+            let expected = 0.75 * (1.0 + 0.5f32.sqrt())/2.0 / (0.5 + COSMUL_EPSILON);
+            assert!((top[0].1 - expected).abs() < 1e-5);
+
+            match model.analogy(&["king", "prince"], &["duke"], 3, Analogy::Add) {
+                Err(W2VError::UnknownWords(unknown)) => assert_eq!(unknown, ["prince", "duke"]),
+                other => return Err(format!("{:?}", other)),
+            }
+            assert!(model.analogy(&[], &[], 3, Analogy::Mul).unwrap().is_empty());
+        }
+        assert_eq!("3cosmul".parse::<Analogy>(), Ok(Analogy::Mul));
+        assert!("sub".parse::<Analogy>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn t26_metrics() -> Result<(), String> {
+        let words = [("a", vec![1.0,0.0]), ("near", vec![1.5,0.5]), ("long", vec![10.0,1.0]), ("side", vec![0.0,1.0]), ("back", vec![-1.0,0.2])];
+        let path = write_binary_model("metrics.bin", &words);
+        for options in [LoadOptions::default(), LoadOptions { mmap: true, search_threads: 2, ..LoadOptions::default() }].iter() {
+            let model = Model::with_options(path.clone(), options).map_err(|e| format!("{:?}",e))?;
+            let names = |top: Vec<(String, f32)>| top.into_iter().map(|(word, _)| word).collect::<Vec<String>>();
+            assert_eq!(names(model.most_similar_by("a", 4, Metric::Cosine).unwrap()), ["long", "near", "side", "back"]);
+            // long wins on dot for its length, and loses on distance
+            assert_eq!(names(model.most_similar_by("a", 4, Metric::Dot).unwrap()), ["long", "near", "side", "back"]);
+            assert_eq!(names(model.most_similar_by("a", 4, Metric::Euclidean).unwrap()), ["near", "side", "back", "long"]);
+            assert_eq!(names(model.most_similar_by("a", 4, Metric::Manhattan).unwrap()), ["near", "side", "back", "long"]);
+
+            // distances come back as distances, not negated or squared
+            let top = model.most_similar_by("a", 1, Metric::Euclidean).unwrap();
+            assert!((top[0].1 - 0.5f32.sqrt()).abs() < 1e-6);
+            let top = model.most_similar_vec_by(&[0.75,0.0], 1, Metric::Manhattan);
+            assert_eq!(top[0], ("a".to_string(), 0.25));
+            assert_eq!(model.get_score("a", "long", Metric::Dot), Some(10.0));
+            assert_eq!(model.get_score("a", "side", Metric::Euclidean), Some(2.0f32.sqrt()));
+            assert_eq!(model.get_score("a", "missing", Metric::Dot), None);
+            assert_eq!(model.get_score("a", "side", Metric::Manhattan), Some(2.0));
+
+            for metric in [Metric::Cosine, Metric::Dot, Metric::Euclidean, Metric::Manhattan].iter() {
+                // every API agrees on the scores and the order
+                let scores = model.get_scores("a", *metric).unwrap();
+                let sorted = model.get_sorted_scores("a", *metric).unwrap();
+                let sorted: Vec<(String, f32)> = (0..words.len()).map(|n| sorted.get_nth_top(n)).filter(|(word, _)| word != "a").collect();
+                let top = model.most_similar_by("a", 4, *metric).unwrap();
+                assert_eq!(top, sorted, "{:?}", metric);
+                for (word, score) in top.iter() {
+                    assert!((scores[word] - score).abs() < 1e-6);
+                }
+                if *metric != Metric::Dot {
+                    assert_eq!(model.vec2word_by(&[1.0,0.0], *metric).get_nth_top(0).0, "a");
+                }
+            }
+        }
+        assert_eq!("l2".parse::<Metric>(), Ok(Metric::Euclidean));
+        assert!("hamming".parse::<Metric>().is_err());
+        assert!(Metric::Manhattan.is_distance() && !Metric::Dot.is_distance());
+        Ok(())
+    }
+
+    #[test]
+    fn t27_batch_search() -> Result<(), String> {
+        // enough words for several tiles, and a dimension with a ragged tail
+        let words = clustered_words(5, 3000, 37);
+        let words: Vec<(&str, Vec<f32>)> = words.iter().map(|(word, vector)| (word.as_str(), vector.clone())).collect();
+        let path = write_binary_model("batch.bin", &words);
+        let queries: Vec<&str> = (0..70).map(|i| words[i*41].0).chain(vec!["missing"]).collect();
+        for options in [LoadOptions::default(), LoadOptions { search_threads: 3, ..LoadOptions::default() }, LoadOptions { mmap: true, ..LoadOptions::default() }].iter() {
+            let model = Model::with_options(path.clone(), options).map_err(|e| format!("{:?}",e))?;
+            for metric in [Metric::Cosine, Metric::Dot, Metric::Euclidean, Metric::Manhattan].iter() {
+                let batch = model.most_similar_batch(&queries, 5, *metric);
+                assert_eq!(batch.len(), queries.len());
+                assert!(batch[70].is_none());
+                for (word, top) in queries.iter().zip(batch.iter()).take(70) {
+                    // the same words as one query at a time, in the same order
+                    let top = top.as_ref().unwrap();
+                    let single = model.most_similar_by(word, 5, *metric).unwrap();
+                    let names = |top: &Vec<(String, f32)>| top.iter().map(|(word, _)| word.clone()).collect::<Vec<String>>();
+                    assert_eq!(names(top), names(&single), "{:?} {}", metric, word);
+                    for ((_, batch), (_, single)) in top.iter().zip(single.iter()) {
+                        assert!((batch - single).abs() < 1e-4*single.abs().max(1.0), "{:?} {} {}", metric, batch, single);
+                    }
+                }
+            }
+            let vectors: Vec<&[f32]> = vec![&words[7].1, &[1.0, 2.0]];
+            let batch = model.most_similar_vec_batch(&vectors, 3, Metric::Cosine);
+            assert_eq!(batch[0].as_ref().unwrap()[0].0, "w7");
+            assert!(batch[1].is_none());
+            assert!(model.most_similar_batch(&[], 3, Metric::Cosine).is_empty());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t28_within() -> Result<(), String> {
+        let words = [("a", vec![1.0,0.0]), ("near", vec![2.0,0.2]), ("half", vec![1.0,1.0]), ("side", vec![0.0,1.0]), ("back", vec![-1.0,0.1])];
+        let path = write_binary_model("within.bin", &words);
+        for options in [LoadOptions::default(), LoadOptions { mmap: true, search_threads: 2, ..LoadOptions::default() }].iter() {
+            let model = Model::with_options(path.clone(), options).map_err(|e| format!("{:?}",e))?;
+            let names = |found: Vec<(String, f32)>| found.into_iter().map(|(word, _)| word).collect::<Vec<String>>();
+            assert_eq!(names(model.within("a", 0.5, Metric::Cosine, None).unwrap()), ["near", "half"]);
+            // the threshold itself is in
+            assert_eq!(names(model.within("a", 0.5f32.sqrt(), Metric::Cosine, None).unwrap()), ["near", "half"]);
+            assert_eq!(names(model.within("a", 0.5, Metric::Cosine, Some(1)).unwrap()), ["near"]);
+            assert!(model.within("a", 1.5, Metric::Cosine, None).unwrap().is_empty());
+            assert_eq!(model.within("a", -1.0, Metric::Cosine, None).unwrap().len(), words.len() - 1);
+
+            // distances count down from the threshold, nearest first
+            let found = model.within("a", 1.1, Metric::Euclidean, None).unwrap();
+            assert_eq!(names(found.clone()), ["half", "near"]);
+            assert!(found[0].1 == 1.0 && (found[1].1 - 1.04f32.sqrt()).abs() < 1e-6);
+            assert_eq!(names(model.within("a", 2.0, Metric::Manhattan, None).unwrap()), ["half", "near", "side"]);
+            assert!(model.within("a", -1.0, Metric::Euclidean, None).unwrap().is_empty());
+
+            assert_eq!(names(model.within_vec(&[0.0,2.0], 1.0, Metric::Dot, None)), ["half", "side"]);
+            assert!(model.within("missing", 0.5, Metric::Cosine, None).is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t29_doesnt_match() -> Result<(), String> {
+        let words = [("breakfast", vec![1.0,0.1,0.0]), ("lunch", vec![0.9,0.2,0.0]), ("dinner", vec![3.0,0.3,0.3]), ("cereal", vec![0.8,0.0,0.3]), ("car", vec![0.0,0.2,1.0])];
+        let path = write_binary_model("doesnt_match.bin", &words);
+        for options in [LoadOptions::default(), LoadOptions { mmap: true, ..LoadOptions::default() }].iter() {
+            let model = Model::with_options(path.clone(), options).map_err(|e| format!("{:?}",e))?;
+            let result = model.doesnt_match(&["breakfast", "lunch", "car", "brunch", "dinner"]);
+            let ranked: Vec<&str> = result.ranked.iter().map(|(word, _)| word.as_str()).collect();
+            assert_eq!(ranked[0], "car");
+            assert_eq!(ranked.len(), 4);
+            assert_eq!(result.unknown, ["brunch"]);
+            assert!(result.ranked.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+
+            // dinner's length doesn't make it count for more in the mean
+            let units: Vec<Vec<f32>> = ["breakfast", "lunch", "car", "dinner"].iter().map(|word| {
+                let vector = model.word2vec(word).unwrap();
+                let scale = 1.0/norm(vector);
+                vector.iter().map(|value| value*scale).collect()
+            }).collect();
+            let mean: Vec<f32> = (0..3).map(|i| units.iter().map(|unit| unit[i]).sum()).collect();
+            let car = kernels::cosine(&units[2], &mean);
+            assert!((result.ranked[0].1 - car).abs() < 1e-6);
+
+            let empty = model.doesnt_match(&["brunch"]);
+            assert!(empty.ranked.is_empty() && empty.unknown == ["brunch"]);
+            assert_eq!(model.doesnt_match(&["car"]).ranked, vec![("car".to_string(), 1.0)]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn t30_set_similarity() -> Result<(), String> {
+        let words = [("king", vec![1.0,0.2,0.0]), ("queen", vec![0.9,0.4,0.0]), ("crown", vec![2.0,0.0,0.4]), ("car", vec![0.0,0.1,1.0]), ("road", vec![0.1,0.0,3.0])];
+        let path = write_binary_model("set_similarity.bin", &words);
+        for options in [LoadOptions::default(), LoadOptions { mmap: true, ..LoadOptions::default() }].iter() {
+            let model = Model::with_options(path.clone(), options).map_err(|e| format!("{:?}",e))?;
+            let royal = model.n_similarity(&["king", "queen", "throne"], &["crown", "king"]);
+            let roads = model.n_similarity(&["king", "queen"], &["car", "road", "lane"]);
+            assert!(royal.similarity.unwrap() > roads.similarity.unwrap());
+            assert_eq!(royal.unknown_a, ["throne"]);
+            assert!(royal.unknown_b.is_empty());
+            assert_eq!(roads.unknown_b, ["lane"]);
+
+            // one word each is the plain cosine, and the sets' order doesn't matter
+            let single = model.n_similarity(&["king"], &["crown"]).similarity.unwrap();
+            assert!((single - model.get_cosine("king".to_string(), "crown".to_string()).unwrap()).abs() < 1e-6);
+            let swapped = model.n_similarity(&["crown", "king"], &["king", "queen", "throne"]);
+            assert!((swapped.similarity.unwrap() - royal.similarity.unwrap()).abs() < 1e-6);
+            assert_eq!(model.n_similarity(&["throne"], &["king"]).similarity, None);
+            assert_eq!(model.n_similarity(&[], &["king"]).similarity, None);
+
+            let matrix = model.similarity_matrix(&["king", "throne", "car", "queen"], &["road", "crown", "lane"]);
+            assert_eq!(matrix.rows, ["king", "car", "queen"]);
+            assert_eq!(matrix.columns, ["road", "crown"]);
+            assert_eq!((matrix.unknown_a.as_slice(), matrix.unknown_b.as_slice()), (&["throne".to_string()][..], &["lane".to_string()][..]));
+            for (row, worda) in matrix.rows.iter().enumerate() {
+                for (column, wordb) in matrix.columns.iter().enumerate() {
+                    let cosine = model.get_cosine(worda.clone(), wordb.clone()).unwrap();
+                    assert!((matrix.values[row][column] - cosine).abs() < 1e-6);
+                }
+            }
+            let no_columns = model.similarity_matrix(&["king", "car"], &["lane"]);
+            assert_eq!(no_columns.values, vec![Vec::<f32>::new(); 2]);
+        }
+        Ok(())
+    }
+
+    fn write_test_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("w2v_test_{}_{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    // n words "w0", "w1", ... spread around 20 random centres, the same for
+    // the same seed
+    fn clustered_words(seed: u32, n: usize, dim: usize) -> Vec<(String, Vec<f32>)> {
+        let mut state = seed;
+        let mut random = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+        };
+        let centres: Vec<Vec<f32>> = (0..20).map(|_| (0..dim).map(|_| random()).collect()).collect();
+        (0..n).map(|i| {
+            let vector = centres[i % 20].iter().map(|value| value + random()*0.6).collect();
+            (format!("w{}", i), vector)
+        }).collect()
+    }
+
+    fn write_binary_model(name: &str, words: &[(&str, Vec<f32>)]) -> PathBuf {
+        write_test_file(name, &write_binary_bytes(words))
+    }
+
+    // also used by the server tests
+    pub(crate) fn write_binary_bytes(words: &[(&str, Vec<f32>)]) -> Vec<u8> {
+        let mut contents = format!("{} {}\n", words.len(), words[0].1.len()).into_bytes();
+        for (word, vector) in words.iter() {
+            contents.extend_from_slice(word.as_bytes());
+            contents.push(b' ');
+            for value in vector.iter() {
+                contents.extend_from_slice(&value.to_le_bytes());
+            }
+            contents.push(b'\n');
+        }
+        contents
+    }
+
+    // fastText .bin with dim 2, minn = maxn = 3 and a single bucket, followed
+    // by the input matrix: one row per word, then the bucket row
+    fn write_fasttext_model(name: &str, words: &[&str], matrix: &[f32]) -> PathBuf {
+        let mut contents: Vec<u8> = Vec::new();
+        // magic, version, then dim ws epoch minCount neg wordNgrams loss model bucket minn maxn lrUpdateRate
+        for value in [fasttext::FASTTEXT_MAGIC, 12, 2, 5, 5, 1, 5, 1, 1, 2, 1, 3, 3, 100].iter() {
+            contents.extend_from_slice(&value.to_le_bytes());
+        }
+        contents.extend_from_slice(&1e-4f64.to_le_bytes());
+        for value in [words.len() as i32, words.len() as i32, 0].iter() {
+            contents.extend_from_slice(&value.to_le_bytes());
+        }
+        contents.extend_from_slice(&100i64.to_le_bytes());
+        contents.extend_from_slice(&(-1i64).to_le_bytes());
+        for word in words.iter() {
+            contents.extend_from_slice(word.as_bytes());
+            contents.push(0);
+            contents.extend_from_slice(&10i64.to_le_bytes());
+            contents.push(0);
+        }
+        contents.push(0);
+        contents.extend_from_slice(&((matrix.len() / 2) as i64).to_le_bytes());
+        contents.extend_from_slice(&2i64.to_le_bytes());
+        for value in matrix.iter() {
+            contents.extend_from_slice(&value.to_le_bytes());
         }
+        write_test_file(name, &contents)
     }
 
-    fn subtract_vec(a:&Vec<f32>,b:&Vec<f32>) -> Vec<f32> {
+    fn subtract_vec(a:&[f32],b:&[f32]) -> Vec<f32> {
         a.iter().zip(b.iter()).map(|(a,b)|a-b).collect()
     }
 
-    fn add_vec(a:&Vec<f32>,b:&Vec<f32>) -> Vec<f32> {
+    fn add_vec(a:&[f32],b:&[f32]) -> Vec<f32> {
         a.iter().zip(b.iter()).map(|(a,b)|a+b).collect()
     }
 
@@ -0,0 +1,314 @@
+use super::{capacity_hint, check_dimension, decode_word, Lookup, Model, Matrix, SearchPool, Utf8Policy, VocabFilter, W2VError};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::HashMap;
+use std::io::prelude::*;
+
+pub(super) const FASTTEXT_MAGIC: i32 = 793712314;
+const SUPERVISED: i32 = 3;
+const EOS: &str = "</s>";
+
+// The character n-gram buckets of a fastText model, kept after loading so
+// vectors can be built for words that are not in the vocabulary.
+#[derive(Debug)]
+pub(super) struct Subwords {
+    minn: usize,
+    maxn: usize,
+    bucket: u32,
+    // None when the model was never pruned, otherwise maps a bucket to the
+    // row that survived pruning
+    pruneidx: Option<HashMap<i32,i32>>,
+    size: usize,
+    rows: Vec<f32>,
+}
+
+impl Subwords {
+    // Same as fastText's getWordVector for an unknown word: the mean of the
+    // rows of every n-gram of "<word>". None if the word has no n-grams in
+    // the model.
+    pub(super) fn vector(&self, word: &str) -> Option<Vec<f32>> {
+        let rows = self.row_ids(format!("<{}>", word).as_bytes());
+        if rows.is_empty() {
+            return None;
+        }
+        let mut vector: Vec<f32> = vec![0.0; self.size];
+        self.add_rows(&mut vector, &rows);
+        let scale = 1.0 / rows.len() as f32;
+        vector.iter_mut().for_each(|value| *value *= scale);
+        Some(vector)
+    }
+
+    fn add_rows(&self, vector: &mut [f32], rows: &[usize]) {
+        for row in rows {
+            let values = &self.rows[row*self.size..(row + 1)*self.size];
+            for (sum, value) in vector.iter_mut().zip(values) {
+                *sum += value;
+            }
+        }
+    }
+
+    // Mirrors fastText's computeSubwords: n-grams are counted in UTF-8
+    // characters, and the lone "<" and ">" are skipped.
+    fn row_ids(&self, word: &[u8]) -> Vec<usize> {
+        let mut rows: Vec<usize> = Vec::new();
+        let row_count = self.rows.len() / self.size;
+        if self.maxn == 0 || row_count == 0 {
+            return rows;
+        }
+        for i in 0..word.len() {
+            if word[i] & 0xC0 == 0x80 {
+                continue;
+            }
+            let mut j = i;
+            let mut n = 1;
+            while j < word.len() && n <= self.maxn {
+                j += 1;
+                while j < word.len() && word[j] & 0xC0 == 0x80 {
+                    j += 1;
+                }
+                if n >= self.minn && !(n == 1 && (i == 0 || j == word.len())) {
+                    let id = (hash(&word[i..j]) % self.bucket) as i32;
+                    let row = match &self.pruneidx {
+                        None => Some(id as usize),
+                        Some(pruneidx) => pruneidx.get(&id).map(|row| *row as usize),
+                    };
+                    // a pruned index from the file is not trusted to stay in range
+                    if let Some(row) = row.filter(|row| *row < row_count) {
+                        rows.push(row);
+                    }
+                }
+                n += 1;
+            }
+        }
+        rows
+    }
+}
+
+// fastText's FNV-1a variant, which sign extends each byte before mixing it in
+fn hash(bytes: &[u8]) -> u32 {
+    let mut h: u32 = 2166136261;
+    for byte in bytes {
+        h ^= *byte as i8 as u32;
+        h = h.wrapping_mul(16777619);
+    }
+    h
+}
+
+// Counts bytes read so errors can say where the file went wrong
+struct Cursor<R> {
+    reader: R,
+    offset: usize,
+}
+
+impl<R: BufRead> Cursor<R> {
+    fn i8(&mut self) -> Result<i8, W2VError> {
+        let value = self.reader.read_i8().map_err(|_| self.truncated())?;
+        self.offset += 1;
+        Ok(value)
+    }
+
+    fn i32(&mut self) -> Result<i32, W2VError> {
+        let value = self.reader.read_i32::<LittleEndian>().map_err(|_| self.truncated())?;
+        self.offset += 4;
+        Ok(value)
+    }
+
+    fn i64(&mut self) -> Result<i64, W2VError> {
+        let value = self.reader.read_i64::<LittleEndian>().map_err(|_| self.truncated())?;
+        self.offset += 8;
+        Ok(value)
+    }
+
+    fn f32s(&mut self, values: &mut [f32]) -> Result<(), W2VError> {
+        self.reader.read_f32_into::<LittleEndian>(values).map_err(|_| self.truncated())?;
+        self.offset += values.len()*4;
+        Ok(())
+    }
+
+    // a null terminated word
+    fn word(&mut self, record: usize) -> Result<Vec<u8>, W2VError> {
+        let start = self.offset;
+        let mut word: Vec<u8> = Vec::new();
+        match self.reader.by_ref().take(super::MAX_WORD_BYTES as u64 + 1).read_until(0, &mut word) {
+            Ok(_) if word.last() == Some(&0) => {
+                word.pop();
+                self.offset += word.len() + 1;
+                Ok(word)
+            }
+            Ok(length) if length > super::MAX_WORD_BYTES => Err(W2VError::WordTooLong { record, offset: start }),
+            _ => Err(W2VError::TruncatedRecord { record, offset: start }),
+        }
+    }
+
+    fn truncated(&self) -> W2VError {
+        W2VError::TruncatedRecord { record: 0, offset: self.offset }
+    }
+}
+
+fn non_negative(value: i64, offset: usize) -> Result<usize, W2VError> {
+    if value < 0 {
+        Err(W2VError::BadHeader(offset))
+    } else {
+        Ok(value as usize)
+    }
+}
+
+// Reads a fastText .bin model: its arguments, the dictionary and the input
+// matrix. Each vocabulary word gets the vector fastText itself reports for
+// it, the mean of its own row and its n-gram rows, and the n-gram rows are
+// kept for unknown words. The output matrix is never needed and is not read.
+pub(super) fn read_fasttext<R: BufRead>(reader: R, file_len: Option<usize>, filter: &VocabFilter, utf8: Utf8Policy) -> Result<Model, W2VError> {
+    let mut cursor = Cursor { reader, offset: 0 };
+    if cursor.i32()? != FASTTEXT_MAGIC {
+        return Err(W2VError::BadHeader(0));
+    }
+    let version = cursor.i32()?;
+    if version != 11 && version != 12 {
+        return Err(W2VError::BadHeader(4));
+    }
+
+    // args: dim ws epoch minCount neg wordNgrams loss model bucket minn maxn lrUpdateRate t
+    let mut args: [i32; 12] = [0; 12];
+    for arg in args.iter_mut() {
+        *arg = cursor.i32()?;
+    }
+    let _sampling_threshold = cursor.i64()?;
+    let size = non_negative(args[0] as i64, 8)?;
+    check_dimension(size)?;
+    let bucket = non_negative(args[8] as i64, 40)?;
+    let minn = non_negative(args[9] as i64, 44)?;
+    // backward compatibility: old supervised models do not use char ngrams
+    let maxn = if version == 11 && args[7] == SUPERVISED { 0 } else { non_negative(args[10] as i64, 48)? };
+
+    let dictionary_offset = cursor.offset;
+    let entries = non_negative(cursor.i32()? as i64, dictionary_offset)?;
+    let nwords = non_negative(cursor.i32()? as i64, dictionary_offset + 4)?;
+    let _nlabels = cursor.i32()?;
+    let _ntokens = cursor.i64()?;
+    let pruneidx_size = cursor.i64()?;
+    if nwords > entries {
+        return Err(W2VError::WordCountMismatch { expected: entries, found: nwords });
+    }
+
+    // n-grams are hashed from the raw bytes, so keep them next to the text
+    let mut words: Vec<(Vec<u8>, String)> = Vec::with_capacity(capacity_hint(entries, 9, file_len));
+    for record in 0..entries {
+        let offset = cursor.offset;
+        let word = cursor.word(record)?;
+        let _count = cursor.i64()?;
+        let _entry_type = cursor.i8()?;
+        if record < nwords {
+            match decode_word(&word, utf8) {
+                Some(text) => words.push((word, text)),
+                None => return Err(W2VError::InvalidUtf8 { record, offset }),
+            }
+        }
+    }
+    let pruneidx = if pruneidx_size < 0 {
+        None
+    } else {
+        let mut pruneidx: HashMap<i32,i32> = HashMap::with_capacity(capacity_hint(pruneidx_size as usize, 6, file_len));
+        for _ in 0..pruneidx_size {
+            let first = cursor.i32()?;
+            let second = cursor.i32()?;
+            pruneidx.insert(first, second);
+        }
+        Some(pruneidx)
+    };
+
+    if cursor.i8()? != 0 {
+        return Err(W2VError::QuantizedModel);
+    }
+    let matrix_offset = cursor.offset;
+    let rows = non_negative(cursor.i64()?, matrix_offset)?;
+    let columns = non_negative(cursor.i64()?, matrix_offset + 8)?;
+    if columns != size {
+        return Err(W2VError::BadDimension(columns));
+    }
+    let expected_rows = nwords + pruneidx.as_ref().map(|pruneidx| pruneidx.len()).unwrap_or(bucket);
+    if rows != expected_rows {
+        return Err(W2VError::WordCountMismatch { expected: expected_rows, found: rows });
+    }
+    if let Some(file_len) = file_len {
+        if rows.saturating_mul(size*4) > file_len.saturating_sub(cursor.offset) {
+            return Err(W2VError::TruncatedRecord { record: 0, offset: file_len });
+        }
+    }
+    // read a row at a time, so a stream that ends early never gets the
+    // whole matrix it promised allocated up front
+    let mut matrix: Vec<f32> = Vec::with_capacity(capacity_hint(rows, size*4, file_len)*size);
+    let mut row: Vec<f32> = vec![0.0; size];
+    for _ in 0..rows {
+        cursor.f32s(&mut row)?;
+        matrix.extend_from_slice(&row);
+    }
+
+    let subwords = Subwords {
+        minn,
+        maxn,
+        bucket: bucket.max(1) as u32,
+        pruneidx,
+        size,
+        rows: matrix.split_off(nwords*size),
+    };
+    let mut lookup = Matrix::with_capacity(filter.capacity(nwords), size);
+    for (index, (word, text)) in words.into_iter().enumerate() {
+        if filter.is_full(lookup.len()) {
+            break;
+        }
+        if !filter.keeps(&text) {
+            continue;
+        }
+        let mut vector: Vec<f32> = matrix[index*size..(index + 1)*size].to_vec();
+        if word != EOS.as_bytes() {
+            let mut bracketed: Vec<u8> = Vec::with_capacity(word.len() + 2);
+            bracketed.push(b'<');
+            bracketed.extend_from_slice(&word);
+            bracketed.push(b'>');
+            let rows = subwords.row_ids(&bracketed);
+            subwords.add_rows(&mut vector, &rows);
+            let scale = 1.0 / (rows.len() + 1) as f32;
+            vector.iter_mut().for_each(|value| *value *= scale);
+        }
+        lookup.insert(text, &vector);
+    }
+
+    Ok(Model {
+        total_words: filter.total_words(nwords, lookup.len()),
+        size,
+        lookup: Lookup::Owned(lookup),
+        subwords: Some(subwords),
+        search: SearchPool::new(1),
+        hnsw: None,
+        ivfpq: None,
+        utf8: Utf8Policy::default(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash_matches_fasttext() {
+        // FNV-1a offset basis, then bytes above 0x7f sign extended
+        assert_eq!(hash(b""), 2166136261);
+        assert_eq!(hash(b"<wh"), 1048167652);
+        assert_eq!(hash("<é".as_bytes()), 672036627);
+    }
+
+    #[test]
+    fn ngrams_count_characters() {
+        let subwords = Subwords {
+            minn: 1,
+            maxn: 2,
+            bucket: 1000,
+            pruneidx: None,
+            size: 1,
+            rows: vec![0.0; 1000],
+        };
+        // <, é, > and their pairs, minus the lone brackets
+        let rows = subwords.row_ids("<é>".as_bytes());
+        let expected: Vec<usize> = ["<é", "é", "é>"].iter().map(|ngram| (hash(ngram.as_bytes()) % 1000) as usize).collect();
+        assert_eq!(rows, expected);
+    }
+}
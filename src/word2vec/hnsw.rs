@@ -0,0 +1,536 @@
+use super::snapshot::Checksum;
+use super::{inverse, kernels, resolve_threads, HnswOptions, Random, Rows, W2VError};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::io::prelude::*;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering::Relaxed};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// A hierarchical navigable small world graph over a model's rows (Malkov and
+// Yashunin). Every word sits on layer 0 and, with geometrically falling
+// odds, on the layers above it. A query walks greedily down from the single
+// entry point on the top layer, then does a best-first search of layer 0.
+//
+// On disk, all little-endian:
+//
+//   header     magic, version, max level, rows, m, ef_construction, entry,
+//              fingerprint of the words and vectors, then a checksum over
+//              everything else in the file
+//   levels     u8 x rows, the top layer each row is on
+//   links      u32 x rows x (2m + level*m), each row's neighbours on layer
+//              0 then on each layer above it, unused slots are NONE
+//
+// Rows are numbered in file order, so an index only fits a model with the
+// same words in the same order, which the fingerprint checks along with
+// the vectors.
+//
+// Builds insert rows from several threads at once, as hnswlib does. Each
+// row's links are only changed under that row's lock, while searches read
+// them without one, so a search may see a neighbour list part way through
+// being rewritten. Every slot always holds a real row or NONE, so that only
+// costs the odd worse path through the graph.
+const HNSW_MAGIC: &[u8; 8] = b"W2VHNSW\0";
+const HNSW_VERSION: u32 = 1;
+const HEADER_BYTES: usize = 64;
+const CHECKSUM_OFFSET: usize = 56;
+const NONE: u32 = u32::MAX;
+// beyond any layer a real build reaches, and keeps a corrupt file from
+// claiming absurd ones
+const MAX_LEVEL: usize = 32;
+const MAX_M: usize = 1024;
+
+#[derive(Debug)]
+pub(super) struct Hnsw {
+    m: usize,
+    ef_construction: usize,
+    ef_search: usize,
+    // NONE while the graph is empty
+    entry: u32,
+    max_level: usize,
+    levels: Vec<u8>,
+    // where each row's links start, worked out from the levels
+    offsets: Vec<usize>,
+    // atomic for the build, and only ever read once it's done
+    links: Vec<AtomicU32>,
+    fingerprint: u64,
+}
+
+// A row and its similarity to whatever is being searched for. Greater means
+// more similar, then an earlier row.
+#[derive(Debug, Clone, Copy)]
+struct Scored {
+    score: f32,
+    row: u32,
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score).then_with(|| other.row.cmp(&self.row))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+// A vector being searched for, with 1/its norm
+#[derive(Clone, Copy)]
+struct Query<'q> {
+    vector: &'q [f32],
+    scale: f32,
+}
+
+impl<'q> Query<'q> {
+    fn new(vector: &'q [f32], norm: f32) -> Query<'q> {
+        Query {
+            vector,
+            scale: inverse(norm),
+        }
+    }
+
+    fn row(rows: &Rows<'q>, row: u32) -> Query<'q> {
+        Query::new(rows.vector(row as usize), rows.norm(row as usize))
+    }
+}
+
+// Cosine of a query against a row. NaN, from vectors holding NaN, counts as
+// least similar so it can't attract a search.
+fn similarity(rows: &Rows, query: Query, row: u32) -> f32 {
+    let score = kernels::dot(query.vector, rows.vector(row as usize))*query.scale*inverse(rows.norm(row as usize));
+    if score.is_nan() {
+        f32::NEG_INFINITY
+    } else {
+        score
+    }
+}
+
+// Rows a search has already scored
+trait Visited {
+    // true the first time a row is seen
+    fn insert(&mut self, row: u32) -> bool;
+}
+
+// For builds, which visit rows from all over the graph many times over: one
+// stamp per row, where a new search just moves on to the next stamp
+struct Stamps {
+    stamps: Vec<u32>,
+    current: u32,
+}
+
+impl Stamps {
+    fn new(rows: usize) -> Stamps {
+        Stamps {
+            stamps: vec![0; rows],
+            current: 0,
+        }
+    }
+
+    fn next(&mut self) -> &mut Stamps {
+        self.current = self.current.wrapping_add(1);
+        if self.current == 0 {
+            self.stamps.iter_mut().for_each(|stamp| *stamp = 0);
+            self.current = 1;
+        }
+        self
+    }
+}
+
+impl Visited for Stamps {
+    fn insert(&mut self, row: u32) -> bool {
+        let stamp = &mut self.stamps[row as usize];
+        let first = *stamp != self.current;
+        *stamp = self.current;
+        first
+    }
+}
+
+// For queries, which only see a few thousand rows and can run side by side
+type RowSet = HashSet<u32, BuildHasherDefault<RowHasher>>;
+
+impl Visited for RowSet {
+    fn insert(&mut self, row: u32) -> bool {
+        HashSet::insert(self, row)
+    }
+}
+
+// Rows are already well spread integers, they only need mixing into the high
+// bits the table looks at
+#[derive(Default)]
+struct RowHasher(u64);
+
+impl Hasher for RowHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 << 8 | *byte as u64).wrapping_mul(0x9e3779b97f4a7c15);
+        }
+    }
+
+    fn write_u32(&mut self, row: u32) {
+        self.0 = (row as u64).wrapping_mul(0x9e3779b97f4a7c15);
+    }
+}
+
+impl Hnsw {
+    fn empty(m: usize, ef_construction: usize, ef_search: usize, levels: Vec<u8>, fingerprint: u64) -> Hnsw {
+        let mut offsets: Vec<usize> = Vec::with_capacity(levels.len() + 1);
+        let mut offset = 0;
+        for level in levels.iter() {
+            offsets.push(offset);
+            offset += 2*m + *level as usize*m;
+        }
+        offsets.push(offset);
+        Hnsw {
+            m,
+            ef_construction,
+            ef_search,
+            entry: NONE,
+            max_level: 0,
+            levels,
+            offsets,
+            links: (0..offset).map(|_| AtomicU32::new(NONE)).collect(),
+            fingerprint,
+        }
+    }
+
+    pub(super) fn build(rows: &Rows, options: &HnswOptions) -> Hnsw {
+        let start_time = Instant::now();
+        let m = options.m.clamp(2, MAX_M);
+        let level_scale = 1.0/(m as f64).ln();
+        let mut random = Random(options.seed);
+        let levels: Vec<u8> = (0..rows.len()).map(|_| {
+            ((-random.unit().ln()*level_scale) as usize).min(MAX_LEVEL) as u8
+        }).collect();
+
+        let mut hnsw = Hnsw::empty(m, options.ef_construction.max(1), options.ef_search, levels, rows.fingerprint());
+        if rows.len() == 0 {
+            return hnsw;
+        }
+        let threads = resolve_threads(options.threads).min(rows.len());
+        // the first row is the entry point every other insert starts from
+        let build = Build {
+            hnsw: &hnsw,
+            rows,
+            top: Mutex::new((0, hnsw.levels[0] as usize)),
+            locks: (0..rows.len()).map(|_| Mutex::new(())).collect(),
+        };
+        let next = AtomicUsize::new(1);
+        crossbeam::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|_| {
+                    let mut visited = Stamps::new(rows.len());
+                    loop {
+                        let row = next.fetch_add(1, Relaxed);
+                        if row >= rows.len() {
+                            break;
+                        }
+                        build.insert(row as u32, &mut visited);
+                    }
+                });
+            }
+        }).unwrap();
+        let (entry, max_level) = build.top.into_inner().unwrap();
+        hnsw.entry = entry;
+        hnsw.max_level = max_level;
+
+        let seconds = start_time.elapsed().as_secs_f64();
+        println!("built HNSW index over {} words on {} threads in {:.2}s ({:.0} words/s)",
+            rows.len(), threads, seconds, rows.len() as f64 / seconds);
+        hnsw
+    }
+
+    pub(super) fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    pub(super) fn set_ef_search(&mut self, ef_search: usize) {
+        self.ef_search = ef_search;
+    }
+
+    fn capacity(&self, layer: usize) -> usize {
+        if layer == 0 {
+            2*self.m
+        } else {
+            self.m
+        }
+    }
+
+    fn slots(&self, row: u32, layer: usize) -> std::ops::Range<usize> {
+        let start = self.offsets[row as usize] + if layer == 0 { 0 } else { 2*self.m + (layer - 1)*self.m };
+        start..start + self.capacity(layer)
+    }
+
+    fn neighbours(&self, row: u32, layer: usize) -> impl Iterator<Item=u32> + '_ {
+        self.links[self.slots(row, layer)].iter().map(|link| link.load(Relaxed)).take_while(|link| *link != NONE)
+    }
+
+    // Best first search of one layer, returning up to `ef` rows, best first
+    fn search_layer<V: Visited>(&self, rows: &Rows, query: Query, entries: &[Scored], ef: usize, layer: usize, visited: &mut V) -> Vec<Scored> {
+        for entry in entries {
+            visited.insert(entry.row);
+        }
+        let mut candidates: BinaryHeap<Scored> = entries.iter().cloned().collect();
+        // worst on top, so it's the one dropped
+        let mut results: BinaryHeap<Reverse<Scored>> = entries.iter().cloned().map(Reverse).collect();
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(candidate) = candidates.pop() {
+            if let Some(Reverse(worst)) = results.peek() {
+                if candidate < *worst && results.len() >= ef {
+                    break;
+                }
+            }
+            for neighbour in self.neighbours(candidate.row, layer) {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let scored = Scored { score: similarity(rows, query, neighbour), row: neighbour };
+                let better = match results.peek() {
+                    Some(Reverse(worst)) => results.len() < ef || scored > *worst,
+                    None => true,
+                };
+                if better {
+                    candidates.push(scored);
+                    results.push(Reverse(scored));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        results.into_sorted_vec().into_iter().map(|Reverse(scored)| scored).collect()
+    }
+
+    // Up to `k` rows closest to `query`, best first. Looks at no fewer than
+    // ef_search candidates.
+    pub(super) fn search(&self, rows: &Rows, query: &[f32], query_norm: f32, k: usize) -> Vec<(u32, f32)> {
+        if self.entry == NONE || k == 0 {
+            return Vec::new();
+        }
+        let query = Query::new(query, query_norm);
+        let mut entries = vec![Scored { score: similarity(rows, query, self.entry), row: self.entry }];
+        for layer in (1..=self.max_level).rev() {
+            entries = self.search_layer(rows, query, &entries, 1, layer, &mut RowSet::default());
+        }
+        let found = self.search_layer(rows, query, &entries, self.ef_search.max(k), 0, &mut RowSet::default());
+        found.into_iter().take(k).map(|scored| (scored.row, scored.score)).collect()
+    }
+
+    pub(super) fn write<W: Write>(&self, mut writer: W) -> Result<(), W2VError> {
+        let rows = self.levels.len();
+        let mut header = [0u8; HEADER_BYTES];
+        header[..8].copy_from_slice(HNSW_MAGIC);
+        header[8..12].copy_from_slice(&HNSW_VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(self.max_level as u32).to_le_bytes());
+        for (offset, value) in [(16, rows as u64), (24, self.m as u64), (32, self.ef_construction as u64), (40, self.entry as u64), (48, self.fingerprint)].iter() {
+            header[*offset..*offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+
+        // one pass for the checksum, which goes in the header, and one to
+        // write, rather than holding a second copy of the links
+        let mut checksum = Checksum::new();
+        checksum.update(&header[..CHECKSUM_OFFSET]);
+        checksum.update(&self.levels);
+        let mut buffer: Vec<u8> = Vec::with_capacity(LINKS_PER_WRITE*4);
+        for chunk in self.links.chunks(LINKS_PER_WRITE) {
+            encode_links(chunk, &mut buffer);
+            checksum.update(&buffer);
+        }
+        header[CHECKSUM_OFFSET..].copy_from_slice(&checksum.finish().to_le_bytes());
+
+        let mut written = 0;
+        let mut write = |bytes: &[u8]| match writer.write_all(bytes) {
+            Ok(_) => {
+                written += bytes.len();
+                Ok(())
+            }
+            Err(_) => Err(W2VError::WriteError(written)),
+        };
+        write(&header)?;
+        write(&self.levels)?;
+        for chunk in self.links.chunks(LINKS_PER_WRITE) {
+            encode_links(chunk, &mut buffer);
+            write(&buffer)?;
+        }
+        match writer.flush() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(W2VError::WriteError(written)),
+        }
+    }
+
+    // Checks everything a search relies on, so a damaged file is an error
+    // rather than a panic or a search that never ends
+    pub(super) fn read(bytes: &[u8], ef_search: usize) -> Result<Hnsw, W2VError> {
+        if bytes.len() < HEADER_BYTES || &bytes[..8] != HNSW_MAGIC {
+            return Err(W2VError::BadHeader(0));
+        }
+        let version = read_u32(bytes, 8);
+        if version != HNSW_VERSION {
+            return Err(W2VError::IndexVersion(version));
+        }
+        let mut checksum = Checksum::new();
+        checksum.update(&bytes[..CHECKSUM_OFFSET]);
+        checksum.update(&bytes[HEADER_BYTES..]);
+        if checksum.finish() != read_u64(bytes, CHECKSUM_OFFSET) {
+            return Err(W2VError::ChecksumMismatch);
+        }
+
+        let max_level = read_u32(bytes, 12) as usize;
+        let field = |offset: usize| read_u64(bytes, offset);
+        let (rows, m, ef_construction, entry, fingerprint) = (field(16) as usize, field(24) as usize, field(32) as usize, field(40), field(48));
+        if !(2..=MAX_M).contains(&m) {
+            return Err(W2VError::BadHeader(24));
+        }
+        if rows > NONE as usize || bytes.len() < HEADER_BYTES + rows {
+            return Err(W2VError::BadHeader(16));
+        }
+        let levels = bytes[HEADER_BYTES..HEADER_BYTES + rows].to_vec();
+        if let Some(row) = levels.iter().position(|level| *level as usize > MAX_LEVEL) {
+            return Err(W2VError::BadHeader(HEADER_BYTES + row));
+        }
+        let mut hnsw = Hnsw::empty(m, ef_construction, ef_search, levels, fingerprint);
+        let links = &bytes[HEADER_BYTES + rows..];
+        if links.len() != hnsw.links.len()*4 {
+            return Err(W2VError::TruncatedRecord { record: 0, offset: bytes.len() });
+        }
+        for (index, (link, value)) in hnsw.links.iter_mut().zip(links.chunks_exact(4)).enumerate() {
+            let value = read_u32(value, 0);
+            if value != NONE && value as usize >= rows {
+                return Err(W2VError::BadHeader(HEADER_BYTES + rows + index*4));
+            }
+            *link.get_mut() = value;
+        }
+        let entry_fits = match entry {
+            entry if entry == NONE as u64 => rows == 0,
+            entry => (entry as usize) < rows && hnsw.levels[entry as usize] as usize == max_level,
+        };
+        if !entry_fits {
+            return Err(W2VError::BadHeader(40));
+        }
+        hnsw.entry = entry as u32;
+        hnsw.max_level = max_level;
+        Ok(hnsw)
+    }
+}
+
+// What the threads of a build share
+struct Build<'a> {
+    hnsw: &'a Hnsw,
+    rows: &'a Rows<'a>,
+    // entry point and top layer
+    top: Mutex<(u32, usize)>,
+    // held while a row's links change
+    locks: Vec<Mutex<()>>,
+}
+
+impl Build<'_> {
+    fn insert(&self, row: u32, visited: &mut Stamps) {
+        let (hnsw, rows) = (self.hnsw, self.rows);
+        let level = hnsw.levels[row as usize] as usize;
+        let (entry, max_level) = *self.top.lock().unwrap();
+        let query = Query::row(rows, row);
+        let mut entries = vec![Scored { score: similarity(rows, query, entry), row: entry }];
+        for layer in (level + 1..=max_level).rev() {
+            entries = hnsw.search_layer(rows, query, &entries, 1, layer, visited.next());
+        }
+        for layer in (0..=level.min(max_level)).rev() {
+            let found = hnsw.search_layer(rows, query, &entries, hnsw.ef_construction, layer, visited.next());
+            let neighbours = select(rows, &found, hnsw.m);
+            {
+                let _lock = self.locks[row as usize].lock().unwrap();
+                for (slot, neighbour) in hnsw.links[hnsw.slots(row, layer)].iter().zip(neighbours.iter()) {
+                    slot.store(neighbour.row, Relaxed);
+                }
+            }
+            for neighbour in neighbours.iter() {
+                self.link(neighbour.row, row, layer);
+            }
+            entries = found;
+        }
+        if level > max_level {
+            let mut top = self.top.lock().unwrap();
+            // another thread may have gone higher in the meantime
+            if level > top.1 {
+                *top = (row, level);
+            }
+        }
+    }
+
+    // Adds `to` to the links of `from`. A full row keeps whichever of its
+    // links and the new one the selection heuristic prefers.
+    fn link(&self, from: u32, to: u32, layer: usize) {
+        let (hnsw, rows) = (self.hnsw, self.rows);
+        let _lock = self.locks[from as usize].lock().unwrap();
+        let slots = &hnsw.links[hnsw.slots(from, layer)];
+        if let Some(slot) = slots.iter().find(|slot| slot.load(Relaxed) == NONE) {
+            slot.store(to, Relaxed);
+            return;
+        }
+        let query = Query::row(rows, from);
+        let mut candidates: Vec<Scored> = slots.iter()
+            .map(|slot| slot.load(Relaxed))
+            .chain(std::iter::once(to))
+            .map(|row| Scored { score: similarity(rows, query, row), row })
+            .collect();
+        candidates.sort_by(|a, b| b.cmp(a));
+        let kept = select(rows, &candidates, slots.len());
+        for (index, slot) in slots.iter().enumerate() {
+            slot.store(kept.get(index).map(|scored| scored.row).unwrap_or(NONE), Relaxed);
+        }
+    }
+}
+
+// The selection heuristic: walking `candidates` best first, keep one only if
+// it is closer to the base than to anything already kept. Links then spread
+// out in different directions rather than bunching up in one cluster.
+fn select(rows: &Rows, candidates: &[Scored], k: usize) -> Vec<Scored> {
+    let mut kept: Vec<Scored> = Vec::with_capacity(k);
+    for candidate in candidates {
+        if kept.len() >= k {
+            break;
+        }
+        let query = Query::row(rows, candidate.row);
+        if kept.iter().all(|other| similarity(rows, query, other.row) < candidate.score) {
+            kept.push(*candidate);
+        }
+    }
+    kept
+}
+
+const LINKS_PER_WRITE: usize = 16384;
+
+fn encode_links(links: &[AtomicU32], buffer: &mut Vec<u8>) {
+    buffer.clear();
+    for link in links {
+        buffer.extend_from_slice(&link.load(Relaxed).to_le_bytes());
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
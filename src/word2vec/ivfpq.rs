@@ -0,0 +1,428 @@
+use super::snapshot::Checksum;
+use super::{inverse, kernels, resolve_threads, topk, IvfPqOptions, Random, Rows, W2VError};
+use std::io::prelude::*;
+use std::time::Instant;
+
+// An inverted file over product quantized codes (Jégou, Douze and Schmid).
+// Vectors are scaled to unit length, so squared distance is 2 - 2 cosine.
+// k-means splits them into `lists` cells around coarse centroids, and what
+// is left of each vector once its centroid is taken away is split into
+// `subspaces` slices, each replaced by the nearest of 256 centroids trained
+// for that slice. A word then costs one byte per subspace. A query scores
+// the words in the `probes` cells nearest it by adding up, per subspace, a
+// distance looked up in a table worked out once per cell.
+//
+// On disk, all little-endian:
+//
+//   header     magic, version, subspaces, rows, size, lists, fingerprint
+//              of the words and vectors, then a checksum over everything
+//              else
+//   centroids  f32 x lists x size
+//   codebooks  f32 x 256 x size, subspace by subspace, each 256 centroids
+//              as wide as its slice
+//   starts     u64 x (lists + 1), where each cell starts in the rows below
+//   rows       u32 x rows, grouped by cell
+//   codes      u8 x rows x subspaces, in the same order
+//
+// The original vectors are only read again to re-rank, so with a mapped
+// snapshot a query keeps little more than the codes in memory.
+const IVFPQ_MAGIC: &[u8; 8] = b"W2VIVFPQ";
+const IVFPQ_VERSION: u32 = 1;
+const HEADER_BYTES: usize = 64;
+const CHECKSUM_OFFSET: usize = 56;
+// centroids per subspace, so that a code fits in a byte
+const CODES: usize = 256;
+
+#[derive(Debug)]
+pub(super) struct IvfPq {
+    size: usize,
+    lists: usize,
+    subspaces: usize,
+    // search settings, which aren't saved
+    probes: usize,
+    rerank: usize,
+    centroids: Vec<f32>,
+    codebooks: Vec<f32>,
+    starts: Vec<usize>,
+    rows: Vec<u32>,
+    codes: Vec<u8>,
+    fingerprint: u64,
+}
+
+// Row `row` scaled to unit length, into `unit`
+fn unit_row(rows: &Rows, row: usize, unit: &mut [f32]) {
+    let scale = inverse(rows.norm(row));
+    for (unit, value) in unit.iter_mut().zip(rows.vector(row)) {
+        *unit = value*scale;
+    }
+}
+
+// index of the centroid nearest `point`, the first of any tied
+fn nearest(centroids: &[f32], point: &[f32]) -> usize {
+    let mut best = (0, f32::INFINITY);
+    for (index, centroid) in centroids.chunks_exact(point.len()).enumerate() {
+        let distance = squared_l2(point, centroid);
+        if distance < best.1 {
+            best = (index, distance);
+        }
+    }
+    best.0
+}
+
+// Subspaces are only a few dimensions wide, which a plain loop the compiler
+// can inline handles faster than a call out to a kernel
+fn squared_l2(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() < 16 {
+        a.iter().zip(b).map(|(a, b)| (a - b)*(a - b)).sum()
+    } else {
+        kernels::squared_l2(a, b)
+    }
+}
+
+// Lloyd's algorithm over `points`, row-major and `dim` wide, returning `k`
+// centroids. Starts from k distinct points picked at random, as far as there
+// are that many, and restarts any cluster that empties on a random point.
+fn kmeans(points: &[f32], dim: usize, k: usize, iterations: usize, random: &mut Random, threads: usize) -> Vec<f32> {
+    let count = points.len() / dim;
+    let point = |index: usize| &points[index*dim..(index + 1)*dim];
+    let mut indices: Vec<usize> = (0..count).collect();
+    let mut centroids: Vec<f32> = Vec::with_capacity(k*dim);
+    for cluster in 0..k {
+        if cluster < count {
+            let pick = cluster + random.below(count - cluster);
+            indices.swap(cluster, pick);
+        }
+        centroids.extend_from_slice(point(indices[cluster % count]));
+    }
+
+    let share = count.div_ceil(threads.max(1)).max(1);
+    for _ in 0..iterations {
+        // each thread sums its share of the points into its own clusters
+        let parts: Vec<(Vec<f64>, Vec<usize>)> = crossbeam::scope(|scope| {
+            let handles: Vec<_> = points.chunks(share*dim).map(|chunk| {
+                let centroids = &centroids;
+                scope.spawn(move |_| {
+                    let mut sums = vec![0f64; k*dim];
+                    let mut counts = vec![0usize; k];
+                    for point in chunk.chunks_exact(dim) {
+                        let cluster = nearest(centroids, point);
+                        counts[cluster] += 1;
+                        for (sum, value) in sums[cluster*dim..(cluster + 1)*dim].iter_mut().zip(point) {
+                            *sum += *value as f64;
+                        }
+                    }
+                    (sums, counts)
+                })
+            }).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        }).unwrap();
+
+        let mut sums = vec![0f64; k*dim];
+        let mut counts = vec![0usize; k];
+        for (part_sums, part_counts) in parts {
+            sums.iter_mut().zip(part_sums).for_each(|(sum, part)| *sum += part);
+            counts.iter_mut().zip(part_counts).for_each(|(count, part)| *count += part);
+        }
+        for (cluster, centroid) in centroids.chunks_exact_mut(dim).enumerate() {
+            if counts[cluster] == 0 {
+                centroid.copy_from_slice(point(random.below(count)));
+            } else {
+                for (value, sum) in centroid.iter_mut().zip(&sums[cluster*dim..(cluster + 1)*dim]) {
+                    *value = (*sum / counts[cluster] as f64) as f32;
+                }
+            }
+        }
+    }
+    centroids
+}
+
+impl IvfPq {
+    // first dimension of each subspace, and the end of the last
+    fn bounds(&self, subspace: usize) -> (usize, usize) {
+        (subspace*self.size/self.subspaces, (subspace + 1)*self.size/self.subspaces)
+    }
+
+    fn centroid(&self, list: usize) -> &[f32] {
+        &self.centroids[list*self.size..(list + 1)*self.size]
+    }
+
+    // the 256 centroids of one subspace, row-major
+    fn codebook(&self, subspace: usize) -> &[f32] {
+        let (start, end) = self.bounds(subspace);
+        &self.codebooks[CODES*start..CODES*end]
+    }
+
+    // the cell and codes for one unit vector
+    fn encode(&self, unit: &[f32], residual: &mut [f32], codes: &mut [u8]) -> u32 {
+        let list = nearest(&self.centroids, unit);
+        for ((residual, value), centroid) in residual.iter_mut().zip(unit).zip(self.centroid(list)) {
+            *residual = value - centroid;
+        }
+        for (subspace, code) in codes.iter_mut().enumerate() {
+            let (start, end) = self.bounds(subspace);
+            *code = nearest(self.codebook(subspace), &residual[start..end]) as u8;
+        }
+        list as u32
+    }
+
+    pub(super) fn train(rows: &Rows, options: &IvfPqOptions) -> IvfPq {
+        let start_time = Instant::now();
+        let (count, size) = (rows.len(), rows.size());
+        let threads = resolve_threads(options.threads);
+        let mut random = Random(options.seed);
+
+        // an even spread through the vocabulary, which runs from the most
+        // frequent words to the least
+        let samples = options.sample.clamp(1, count.max(1));
+        let mut points: Vec<f32> = vec![0.0; samples*size];
+        for (sample, point) in points.chunks_exact_mut(size).enumerate() {
+            if count > 0 {
+                unit_row(rows, sample*count/samples, point);
+            }
+        }
+
+        let lists = options.lists.clamp(1, samples);
+        let mut index = IvfPq {
+            size,
+            lists,
+            subspaces: options.subspaces.clamp(1, size),
+            probes: options.probes,
+            rerank: options.rerank,
+            centroids: kmeans(&points, size, lists, options.iterations, &mut random, threads),
+            codebooks: vec![0.0; CODES*size],
+            starts: Vec::new(),
+            rows: Vec::new(),
+            codes: Vec::new(),
+            fingerprint: rows.fingerprint(),
+        };
+
+        // codebooks are trained on what the coarse centroids leave over
+        for point in points.chunks_exact_mut(size) {
+            let list = nearest(&index.centroids, point);
+            for (value, centroid) in point.iter_mut().zip(index.centroid(list)) {
+                *value -= centroid;
+            }
+        }
+        for subspace in 0..index.subspaces {
+            let (start, end) = index.bounds(subspace);
+            let slices: Vec<f32> = points.chunks_exact(size).flat_map(|point| point[start..end].iter().cloned()).collect();
+            let codebook = kmeans(&slices, end - start, CODES, options.iterations, &mut random, threads);
+            index.codebooks[CODES*start..CODES*end].copy_from_slice(&codebook);
+        }
+
+        // every row, encoded a share per thread
+        let subspaces = index.subspaces;
+        let mut lists_of: Vec<u32> = vec![0; count];
+        let mut codes: Vec<u8> = vec![0; count*subspaces];
+        let share = count.div_ceil(threads).max(1);
+        crossbeam::scope(|scope| {
+            for (part, (lists_of, codes)) in lists_of.chunks_mut(share).zip(codes.chunks_mut(share*subspaces)).enumerate() {
+                let index = &index;
+                scope.spawn(move |_| {
+                    let (mut unit, mut residual) = (vec![0.0; size], vec![0.0; size]);
+                    for (offset, (list, codes)) in lists_of.iter_mut().zip(codes.chunks_exact_mut(subspaces)).enumerate() {
+                        unit_row(rows, part*share + offset, &mut unit);
+                        *list = index.encode(&unit, &mut residual, codes);
+                    }
+                });
+            }
+        }).unwrap();
+
+        // grouped by cell, rows in file order within each
+        let mut starts: Vec<usize> = vec![0; lists + 1];
+        for list in lists_of.iter() {
+            starts[*list as usize + 1] += 1;
+        }
+        for list in 0..lists {
+            starts[list + 1] += starts[list];
+        }
+        let mut next = starts.clone();
+        index.rows = vec![0; count];
+        index.codes = vec![0; count*subspaces];
+        for (row, list) in lists_of.iter().enumerate() {
+            let slot = next[*list as usize];
+            next[*list as usize] += 1;
+            index.rows[slot] = row as u32;
+            index.codes[slot*subspaces..(slot + 1)*subspaces].copy_from_slice(&codes[row*subspaces..(row + 1)*subspaces]);
+        }
+        index.starts = starts;
+
+        let seconds = start_time.elapsed().as_secs_f64();
+        println!("trained IVF-PQ index over {} words ({} lists, {} bytes a word) on {} threads in {:.2}s",
+            count, lists, subspaces, threads, seconds);
+        index
+    }
+
+    pub(super) fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    pub(super) fn set_search(&mut self, probes: usize, rerank: usize) {
+        self.probes = probes;
+        self.rerank = rerank;
+    }
+
+    // Up to `k` rows closest to `query`, best first, with cosines estimated
+    // from the codes, or exact ones when re-ranking
+    pub(super) fn search(&self, rows: &Rows, query: &[f32], query_norm: f32, k: usize) -> Vec<(u32, f32)> {
+        if k == 0 || self.rows.is_empty() || query.len() != self.size {
+            return Vec::new();
+        }
+        let scale = inverse(query_norm);
+        let unit: Vec<f32> = query.iter().map(|value| value*scale).collect();
+
+        let mut nearest: Vec<(f32, usize)> = (0..self.lists).map(|list| (kernels::squared_l2(&unit, self.centroid(list)), list)).collect();
+        let probes = self.probes.clamp(1, self.lists);
+        nearest.select_nth_unstable_by(probes - 1, |a, b| a.0.total_cmp(&b.0));
+        nearest.truncate(probes);
+
+        // re-ranking picks its k out of more candidates
+        let mut top = topk::TopK::new(k.max(self.rerank));
+        let mut residual: Vec<f32> = vec![0.0; self.size];
+        let mut table: Vec<f32> = vec![0.0; self.subspaces*CODES];
+        for (_, list) in nearest {
+            for ((residual, value), centroid) in residual.iter_mut().zip(&unit).zip(self.centroid(list)) {
+                *residual = value - centroid;
+            }
+            for subspace in 0..self.subspaces {
+                let (start, end) = self.bounds(subspace);
+                let slice = &residual[start..end];
+                for (distance, centroid) in table[subspace*CODES..(subspace + 1)*CODES].iter_mut().zip(self.codebook(subspace).chunks_exact(end - start)) {
+                    *distance = squared_l2(slice, centroid);
+                }
+            }
+            for slot in self.starts[list]..self.starts[list + 1] {
+                let codes = &self.codes[slot*self.subspaces..(slot + 1)*self.subspaces];
+                let distance: f32 = codes.iter().enumerate().map(|(subspace, code)| table[subspace*CODES + *code as usize]).sum();
+                let row = self.rows[slot] as usize;
+                top.push(row, rows.word(row), 1.0 - distance/2.0);
+            }
+        }
+
+        let candidates = top.into_sorted_entries();
+        if self.rerank == 0 {
+            return candidates.into_iter().take(k).map(|(row, _, score)| (row as u32, score)).collect();
+        }
+        let mut top = topk::TopK::new(k);
+        for (row, word, _) in candidates {
+            top.push(row, word, kernels::dot(query, rows.vector(row))*scale*inverse(rows.norm(row)));
+        }
+        top.into_sorted_entries().into_iter().map(|(row, _, score)| (row as u32, score)).collect()
+    }
+
+    pub(super) fn write<W: Write>(&self, mut writer: W) -> Result<(), W2VError> {
+        let mut header = [0u8; HEADER_BYTES];
+        header[..8].copy_from_slice(IVFPQ_MAGIC);
+        header[8..12].copy_from_slice(&IVFPQ_VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(self.subspaces as u32).to_le_bytes());
+        for (offset, value) in [(16, self.rows.len() as u64), (24, self.size as u64), (32, self.lists as u64), (48, self.fingerprint)].iter() {
+            header[*offset..*offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+
+        // the index is small next to the model, so is put together whole
+        let mut body: Vec<u8> = Vec::with_capacity((self.centroids.len() + self.codebooks.len())*4 + self.starts.len()*8 + self.rows.len()*4 + self.codes.len());
+        for value in self.centroids.iter().chain(self.codebooks.iter()) {
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+        for start in self.starts.iter() {
+            body.extend_from_slice(&(*start as u64).to_le_bytes());
+        }
+        for row in self.rows.iter() {
+            body.extend_from_slice(&row.to_le_bytes());
+        }
+        body.extend_from_slice(&self.codes);
+
+        let mut checksum = Checksum::new();
+        checksum.update(&header[..CHECKSUM_OFFSET]);
+        checksum.update(&body);
+        header[CHECKSUM_OFFSET..].copy_from_slice(&checksum.finish().to_le_bytes());
+        if writer.write_all(&header).is_err() {
+            return Err(W2VError::WriteError(0));
+        }
+        match writer.write_all(&body).and_then(|_| writer.flush()) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(W2VError::WriteError(HEADER_BYTES)),
+        }
+    }
+
+    // Checks everything a search relies on, so a damaged file is an error
+    // rather than a panic
+    pub(super) fn read(bytes: &[u8], probes: usize, rerank: usize) -> Result<IvfPq, W2VError> {
+        if bytes.len() < HEADER_BYTES || &bytes[..8] != IVFPQ_MAGIC {
+            return Err(W2VError::BadHeader(0));
+        }
+        let version = read_u32(bytes, 8);
+        if version != IVFPQ_VERSION {
+            return Err(W2VError::IndexVersion(version));
+        }
+        let mut checksum = Checksum::new();
+        checksum.update(&bytes[..CHECKSUM_OFFSET]);
+        checksum.update(&bytes[HEADER_BYTES..]);
+        if checksum.finish() != read_u64(bytes, CHECKSUM_OFFSET) {
+            return Err(W2VError::ChecksumMismatch);
+        }
+
+        let subspaces = read_u32(bytes, 12) as usize;
+        let (rows, size, lists, fingerprint) = (read_u64(bytes, 16) as usize, read_u64(bytes, 24) as usize, read_u64(bytes, 32) as usize, read_u64(bytes, 48));
+        super::check_dimension(size)?;
+        if subspaces == 0 || subspaces > size {
+            return Err(W2VError::BadHeader(12));
+        }
+        if rows > u32::MAX as usize {
+            return Err(W2VError::BadHeader(16));
+        }
+        let expected = lists.checked_mul(size)
+            .and_then(|floats| floats.checked_add(CODES*size))
+            .and_then(|floats| floats.checked_mul(4))
+            .and_then(|length| lists.checked_add(1)?.checked_mul(8)?.checked_add(length))
+            .and_then(|length| rows.checked_mul(4 + subspaces)?.checked_add(length))
+            .and_then(|length| length.checked_add(HEADER_BYTES));
+        if lists == 0 || expected != Some(bytes.len()) {
+            return Err(W2VError::TruncatedRecord { record: 0, offset: bytes.len() });
+        }
+
+        let mut position = HEADER_BYTES;
+        let mut floats = |count: usize| {
+            let values: Vec<f32> = bytes[position..position + count*4].chunks_exact(4).map(|value| f32::from_bits(read_u32(value, 0))).collect();
+            position += count*4;
+            values
+        };
+        let centroids = floats(lists*size);
+        let codebooks = floats(CODES*size);
+        let starts_at = HEADER_BYTES + (lists*size + CODES*size)*4;
+        let starts: Vec<usize> = (0..=lists).map(|list| read_u64(bytes, starts_at + list*8) as usize).collect();
+        if starts[0] != 0 || starts[lists] != rows || starts.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(W2VError::BadHeader(starts_at));
+        }
+        let rows_at = starts_at + (lists + 1)*8;
+        let row_ids: Vec<u32> = bytes[rows_at..rows_at + rows*4].chunks_exact(4).map(|row| read_u32(row, 0)).collect();
+        if let Some(slot) = row_ids.iter().position(|row| *row as usize >= rows) {
+            return Err(W2VError::BadHeader(rows_at + slot*4));
+        }
+        Ok(IvfPq {
+            size,
+            lists,
+            subspaces,
+            probes,
+            rerank,
+            centroids,
+            codebooks,
+            starts,
+            rows: row_ids,
+            codes: bytes[rows_at + rows*4..].to_vec(),
+            fingerprint,
+        })
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
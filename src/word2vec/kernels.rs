@@ -0,0 +1,769 @@
+// Vector kernels behind every similarity scan. On x86_64 the widest
+// instruction set the CPU supports is picked once at runtime, anything else
+// gets a portable version written so the compiler can vectorize it.
+// Lanes are summed in a different order than a plain loop would, so results
+// can differ from one in the last few bits.
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// f32s as the kernels read them, in place and at any alignment. Rows of a
+// mapped binary model follow variable length words, so most of them aren't
+// 4 byte aligned and can't be lent out as slices, but every load here is an
+// unaligned one anyway.
+#[derive(Clone, Copy)]
+pub(super) struct Floats<'a> {
+    start: *const f32,
+    len: usize,
+    values: PhantomData<&'a [u8]>,
+}
+
+// only ever read, like the slice or bytes it was made from
+unsafe impl Send for Floats<'_> {}
+unsafe impl Sync for Floats<'_> {}
+
+impl<'a> Floats<'a> {
+    // Little-endian f32s, which on a little-endian host need no decoding
+    pub(super) fn from_le_bytes(bytes: &'a [u8]) -> Floats<'a> {
+        if cfg!(target_endian = "big") {
+            panic!("f32s can only be read in place on little-endian targets");
+        }
+        Floats {
+            start: bytes.as_ptr() as *const f32,
+            len: bytes.len() / 4,
+            values: PhantomData,
+        }
+    }
+
+    pub(super) fn len(self) -> usize {
+        self.len
+    }
+
+    pub(super) fn get(self, index: usize) -> f32 {
+        assert!(index < self.len);
+        unsafe { self.start.add(index).read_unaligned() }
+    }
+
+    pub(super) fn iter(self) -> impl Iterator<Item=f32> + 'a {
+        (0..self.len).map(move |index| self.get(index))
+    }
+
+    pub(super) fn to_vec(self) -> Vec<f32> {
+        self.iter().collect()
+    }
+
+    fn as_ptr(self) -> *const f32 {
+        self.start
+    }
+
+    // everything from `start` on
+    fn skip(self, start: usize) -> Floats<'a> {
+        let start = start.min(self.len);
+        Floats {
+            start: unsafe { self.start.add(start) },
+            len: self.len - start,
+            values: PhantomData,
+        }
+    }
+
+    // the first `len` values
+    fn take(self, len: usize) -> Floats<'a> {
+        Floats {
+            len: len.min(self.len),
+            ..self
+        }
+    }
+}
+
+impl<'a> From<&'a [f32]> for Floats<'a> {
+    fn from(values: &'a [f32]) -> Floats<'a> {
+        Floats {
+            start: values.as_ptr(),
+            len: values.len(),
+            values: PhantomData,
+        }
+    }
+}
+
+impl<'a, const N: usize> From<&'a [f32; N]> for Floats<'a> {
+    fn from(values: &'a [f32; N]) -> Floats<'a> {
+        Floats::from(&values[..])
+    }
+}
+
+impl<'a> From<&'a Vec<f32>> for Floats<'a> {
+    fn from(values: &'a Vec<f32>) -> Floats<'a> {
+        Floats::from(values.as_slice())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Level {
+    Portable,
+    Sse,
+    Avx2,
+    Avx512,
+}
+
+// 0 until the CPU has been checked, then Level as u8 + 1
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+
+pub(super) fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => {
+            let level = detect();
+            LEVEL.store(level as u8 + 1, Ordering::Relaxed);
+            level
+        }
+        1 => Level::Portable,
+        2 => Level::Sse,
+        3 => Level::Avx2,
+        _ => Level::Avx512,
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn detect() -> Level {
+    if is_x86_feature_detected!("avx512f") {
+        Level::Avx512
+    } else if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+        Level::Avx2
+    } else {
+        // SSE2 is part of x86_64
+        Level::Sse
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn detect() -> Level {
+    Level::Portable
+}
+
+// every level this CPU can run, for tests and benchmarks
+#[allow(dead_code)]
+pub(super) fn available() -> Vec<Level> {
+    [Level::Portable, Level::Sse, Level::Avx2, Level::Avx512].iter()
+        .cloned()
+        .filter(|level| *level as u8 <= detect() as u8)
+        .collect()
+}
+
+pub(super) fn dot<'a, 'b>(a: impl Into<Floats<'a>>, b: impl Into<Floats<'b>>) -> f32 {
+    dot_with(level(), a, b)
+}
+
+pub(super) fn squared_l2<'a, 'b>(a: impl Into<Floats<'a>>, b: impl Into<Floats<'b>>) -> f32 {
+    squared_l2_with(level(), a, b)
+}
+
+// sum of absolute differences
+pub(super) fn manhattan<'a, 'b>(a: impl Into<Floats<'a>>, b: impl Into<Floats<'b>>) -> f32 {
+    manhattan_with(level(), a, b)
+}
+
+// Every query dotted with every row, into out[query*rows.len() + row]. Like
+// the other kernels, vectors of different lengths are only dotted as far as
+// the shorter one goes.
+pub(super) fn dot_tile(queries: &[Floats], rows: &[Floats], out: &mut [f32]) {
+    dot_tile_with(level(), queries, rows, out)
+}
+
+// cosine of two vectors whose norms aren't known, in one pass over both
+pub(super) fn cosine<'a, 'b>(a: impl Into<Floats<'a>>, b: impl Into<Floats<'b>>) -> f32 {
+    cosine_with(level(), a, b)
+}
+
+// The *_with kernels take the level to run at, for tests and benchmarks. A
+// level the CPU can't run is lowered to the widest one it can, so nothing
+// executes instructions the CPU doesn't have.
+fn supported(level: Level) -> Level {
+    let widest = self::level();
+    if level as u8 <= widest as u8 {
+        level
+    } else {
+        widest
+    }
+}
+
+pub(super) fn dot_with<'a, 'b>(level: Level, a: impl Into<Floats<'a>>, b: impl Into<Floats<'b>>) -> f32 {
+    let level = supported(level);
+    let (a, b) = (a.into(), b.into());
+    let length = a.len().min(b.len());
+    let (a, b) = (a.take(length), b.take(length));
+    match level {
+        #[cfg(target_arch = "x86_64")]
+        Level::Avx512 => unsafe { x86::dot_avx512(a, b) },
+        #[cfg(target_arch = "x86_64")]
+        Level::Avx2 => unsafe { x86::dot_avx2(a, b) },
+        #[cfg(target_arch = "x86_64")]
+        Level::Sse => unsafe { x86::dot_sse(a, b) },
+        _ => portable::dot(a, b),
+    }
+}
+
+pub(super) fn squared_l2_with<'a, 'b>(level: Level, a: impl Into<Floats<'a>>, b: impl Into<Floats<'b>>) -> f32 {
+    let level = supported(level);
+    let (a, b) = (a.into(), b.into());
+    let length = a.len().min(b.len());
+    let (a, b) = (a.take(length), b.take(length));
+    match level {
+        #[cfg(target_arch = "x86_64")]
+        Level::Avx512 => unsafe { x86::squared_l2_avx512(a, b) },
+        #[cfg(target_arch = "x86_64")]
+        Level::Avx2 => unsafe { x86::squared_l2_avx2(a, b) },
+        #[cfg(target_arch = "x86_64")]
+        Level::Sse => unsafe { x86::squared_l2_sse(a, b) },
+        _ => portable::squared_l2(a, b),
+    }
+}
+
+pub(super) fn manhattan_with<'a, 'b>(level: Level, a: impl Into<Floats<'a>>, b: impl Into<Floats<'b>>) -> f32 {
+    let level = supported(level);
+    let (a, b) = (a.into(), b.into());
+    let length = a.len().min(b.len());
+    let (a, b) = (a.take(length), b.take(length));
+    match level {
+        #[cfg(target_arch = "x86_64")]
+        Level::Avx512 => unsafe { x86::manhattan_avx512(a, b) },
+        #[cfg(target_arch = "x86_64")]
+        Level::Avx2 => unsafe { x86::manhattan_avx2(a, b) },
+        #[cfg(target_arch = "x86_64")]
+        Level::Sse => unsafe { x86::manhattan_sse(a, b) },
+        _ => portable::manhattan(a, b),
+    }
+}
+
+// Blocks of four queries by two rows share their loads: each step loads six
+// registers for eight multiply-adds, where one dot product at a time loads
+// two for one. What doesn't fill a block is done a pair at a time.
+pub(super) fn dot_tile_with(level: Level, queries: &[Floats], rows: &[Floats], out: &mut [f32]) {
+    let level = supported(level);
+    let width = rows.len();
+    let (full_queries, full_rows) = (queries.len() / 4 * 4, width / 2 * 2);
+    for query in (0..full_queries).step_by(4) {
+        let block = [queries[query], queries[query + 1], queries[query + 2], queries[query + 3]];
+        for row in (0..full_rows).step_by(2) {
+            let pair = [rows[row], rows[row + 1]];
+            // the micro-kernels read every vector as far as the first row, so
+            // a block with a shorter vector in it goes a pair at a time
+            let length = pair[0].len();
+            let dots = if block.iter().chain(pair.iter()).any(|vector| vector.len() != length) {
+                let mut dots = [[0.0f32; 2]; 4];
+                for (dots, query) in dots.iter_mut().zip(block.iter()) {
+                    for (dot, other) in dots.iter_mut().zip(pair.iter()) {
+                        *dot = dot_with(level, *query, *other);
+                    }
+                }
+                dots
+            } else {
+                match level {
+                    #[cfg(target_arch = "x86_64")]
+                    Level::Avx512 => unsafe { x86::dot_4x2_avx512(block, pair) },
+                    #[cfg(target_arch = "x86_64")]
+                    Level::Avx2 => unsafe { x86::dot_4x2_avx2(block, pair) },
+                    _ => portable::dot_4x2(block, pair),
+                }
+            };
+            for (offset, dots) in dots.iter().enumerate() {
+                out[(query + offset)*width + row..(query + offset)*width + row + 2].copy_from_slice(dots);
+            }
+        }
+    }
+    for (query, vector) in queries.iter().enumerate() {
+        let start = if query < full_queries { full_rows } else { 0 };
+        for row in start..width {
+            out[query*width + row] = dot_with(level, *vector, rows[row]);
+        }
+    }
+}
+
+pub(super) fn cosine_with<'a, 'b>(level: Level, a: impl Into<Floats<'a>>, b: impl Into<Floats<'b>>) -> f32 {
+    let level = supported(level);
+    let (a, b) = (a.into(), b.into());
+    let length = a.len().min(b.len());
+    let (a, b) = (a.take(length), b.take(length));
+    let (ab, aa, bb) = match level {
+        #[cfg(target_arch = "x86_64")]
+        Level::Avx512 => unsafe { x86::dots_avx512(a, b) },
+        #[cfg(target_arch = "x86_64")]
+        Level::Avx2 => unsafe { x86::dots_avx2(a, b) },
+        #[cfg(target_arch = "x86_64")]
+        Level::Sse => unsafe { x86::dots_sse(a, b) },
+        _ => portable::dots(a, b),
+    };
+    if aa > 0.0 && bb > 0.0 {
+        ab / (aa.sqrt()*bb.sqrt())
+    } else {
+        0.0
+    }
+}
+
+mod portable {
+    use super::Floats;
+
+    const LANES: usize = 8;
+
+    // Eight independent sums, which the compiler turns into vector adds
+    // wherever the target has them
+    pub(super) fn dot(a: Floats, b: Floats) -> f32 {
+        let mut sums = [0.0f32; LANES];
+        let n = a.len() / LANES * LANES;
+        for i in (0..n).step_by(LANES) {
+            for (lane, sum) in sums.iter_mut().enumerate() {
+                *sum += a.get(i + lane)*b.get(i + lane);
+            }
+        }
+        let tail: f32 = a.skip(n).iter().zip(b.skip(n).iter()).map(|(a, b)| a*b).sum();
+        sums.iter().sum::<f32>() + tail
+    }
+
+    pub(super) fn squared_l2(a: Floats, b: Floats) -> f32 {
+        let mut sums = [0.0f32; LANES];
+        let n = a.len() / LANES * LANES;
+        for i in (0..n).step_by(LANES) {
+            for (lane, sum) in sums.iter_mut().enumerate() {
+                let difference = a.get(i + lane) - b.get(i + lane);
+                *sum += difference*difference;
+            }
+        }
+        let tail: f32 = a.skip(n).iter().zip(b.skip(n).iter()).map(|(a, b)| (a - b)*(a - b)).sum();
+        sums.iter().sum::<f32>() + tail
+    }
+
+    pub(super) fn manhattan(a: Floats, b: Floats) -> f32 {
+        let mut sums = [0.0f32; LANES];
+        let n = a.len() / LANES * LANES;
+        for i in (0..n).step_by(LANES) {
+            for (lane, sum) in sums.iter_mut().enumerate() {
+                *sum += (a.get(i + lane) - b.get(i + lane)).abs();
+            }
+        }
+        let tail: f32 = a.skip(n).iter().zip(b.skip(n).iter()).map(|(a, b)| (a - b).abs()).sum();
+        sums.iter().sum::<f32>() + tail
+    }
+
+    pub(super) fn dot_4x2(queries: [Floats; 4], rows: [Floats; 2]) -> [[f32; 2]; 4] {
+        let mut dots = [[0.0f32; 2]; 4];
+        for (dots, query) in dots.iter_mut().zip(queries.iter()) {
+            for (dot, row) in dots.iter_mut().zip(rows.iter()) {
+                *dot = self::dot(*query, *row);
+            }
+        }
+        dots
+    }
+
+    // (a.b, a.a, b.b)
+    pub(super) fn dots(a: Floats, b: Floats) -> (f32, f32, f32) {
+        (dot(a, b), dot(a, a), dot(b, b))
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use super::Floats;
+    use std::arch::x86_64::*;
+
+    // The slices passed in are always the same length. Each function handles
+    // whole registers with intrinsics and the leftovers with the next size
+    // down, or a scalar loop.
+
+    #[target_feature(enable = "avx512f")]
+    pub(super) unsafe fn dot_avx512(a: Floats, b: Floats) -> f32 {
+        // two sums, so one FMA doesn't have to wait for the last
+        let n = a.len() / 32 * 32;
+        let (mut first, mut second) = (_mm512_setzero_ps(), _mm512_setzero_ps());
+        for i in (0..n).step_by(32) {
+            first = _mm512_fmadd_ps(_mm512_loadu_ps(a.as_ptr().add(i)), _mm512_loadu_ps(b.as_ptr().add(i)), first);
+            second = _mm512_fmadd_ps(_mm512_loadu_ps(a.as_ptr().add(i + 16)), _mm512_loadu_ps(b.as_ptr().add(i + 16)), second);
+        }
+        _mm512_reduce_add_ps(_mm512_add_ps(first, second)) + dot_avx2(a.skip(n), b.skip(n))
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub(super) unsafe fn squared_l2_avx512(a: Floats, b: Floats) -> f32 {
+        let n = a.len() / 32 * 32;
+        let (mut first, mut second) = (_mm512_setzero_ps(), _mm512_setzero_ps());
+        for i in (0..n).step_by(32) {
+            let difference = _mm512_sub_ps(_mm512_loadu_ps(a.as_ptr().add(i)), _mm512_loadu_ps(b.as_ptr().add(i)));
+            first = _mm512_fmadd_ps(difference, difference, first);
+            let difference = _mm512_sub_ps(_mm512_loadu_ps(a.as_ptr().add(i + 16)), _mm512_loadu_ps(b.as_ptr().add(i + 16)));
+            second = _mm512_fmadd_ps(difference, difference, second);
+        }
+        _mm512_reduce_add_ps(_mm512_add_ps(first, second)) + squared_l2_avx2(a.skip(n), b.skip(n))
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub(super) unsafe fn dot_4x2_avx512(queries: [Floats; 4], rows: [Floats; 2]) -> [[f32; 2]; 4] {
+        let length = rows[0].len();
+        let n = length / 16 * 16;
+        let mut sums = [[_mm512_setzero_ps(); 2]; 4];
+        for i in (0..n).step_by(16) {
+            let row = [_mm512_loadu_ps(rows[0].as_ptr().add(i)), _mm512_loadu_ps(rows[1].as_ptr().add(i))];
+            for (sums, query) in sums.iter_mut().zip(queries.iter()) {
+                let query = _mm512_loadu_ps(query.as_ptr().add(i));
+                sums[0] = _mm512_fmadd_ps(query, row[0], sums[0]);
+                sums[1] = _mm512_fmadd_ps(query, row[1], sums[1]);
+            }
+        }
+        // the last few lanes through a mask, rather than eight scalar loops
+        if n < length {
+            let mask: __mmask16 = (1 << (length - n)) - 1;
+            let row = [_mm512_maskz_loadu_ps(mask, rows[0].as_ptr().add(n)), _mm512_maskz_loadu_ps(mask, rows[1].as_ptr().add(n))];
+            for (sums, query) in sums.iter_mut().zip(queries.iter()) {
+                let query = _mm512_maskz_loadu_ps(mask, query.as_ptr().add(n));
+                sums[0] = _mm512_fmadd_ps(query, row[0], sums[0]);
+                sums[1] = _mm512_fmadd_ps(query, row[1], sums[1]);
+            }
+        }
+        let mut dots = [[0.0f32; 2]; 4];
+        for (dots, sums) in dots.iter_mut().zip(sums.iter()) {
+            for (dot, sum) in dots.iter_mut().zip(sums.iter()) {
+                *dot = _mm512_reduce_add_ps(*sum);
+            }
+        }
+        dots
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub(super) unsafe fn manhattan_avx512(a: Floats, b: Floats) -> f32 {
+        let n = a.len() / 32 * 32;
+        let (mut first, mut second) = (_mm512_setzero_ps(), _mm512_setzero_ps());
+        for i in (0..n).step_by(32) {
+            first = _mm512_add_ps(first, _mm512_abs_ps(_mm512_sub_ps(_mm512_loadu_ps(a.as_ptr().add(i)), _mm512_loadu_ps(b.as_ptr().add(i)))));
+            second = _mm512_add_ps(second, _mm512_abs_ps(_mm512_sub_ps(_mm512_loadu_ps(a.as_ptr().add(i + 16)), _mm512_loadu_ps(b.as_ptr().add(i + 16)))));
+        }
+        _mm512_reduce_add_ps(_mm512_add_ps(first, second)) + manhattan_avx2(a.skip(n), b.skip(n))
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub(super) unsafe fn dots_avx512(a: Floats, b: Floats) -> (f32, f32, f32) {
+        let n = a.len() / 16 * 16;
+        let (mut ab, mut aa, mut bb) = (_mm512_setzero_ps(), _mm512_setzero_ps(), _mm512_setzero_ps());
+        for i in (0..n).step_by(16) {
+            let x = _mm512_loadu_ps(a.as_ptr().add(i));
+            let y = _mm512_loadu_ps(b.as_ptr().add(i));
+            ab = _mm512_fmadd_ps(x, y, ab);
+            aa = _mm512_fmadd_ps(x, x, aa);
+            bb = _mm512_fmadd_ps(y, y, bb);
+        }
+        let (tail_ab, tail_aa, tail_bb) = dots_avx2(a.skip(n), b.skip(n));
+        (_mm512_reduce_add_ps(ab) + tail_ab, _mm512_reduce_add_ps(aa) + tail_aa, _mm512_reduce_add_ps(bb) + tail_bb)
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn dot_avx2(a: Floats, b: Floats) -> f32 {
+        let n = a.len() / 16 * 16;
+        let (mut first, mut second) = (_mm256_setzero_ps(), _mm256_setzero_ps());
+        for i in (0..n).step_by(16) {
+            first = _mm256_fmadd_ps(_mm256_loadu_ps(a.as_ptr().add(i)), _mm256_loadu_ps(b.as_ptr().add(i)), first);
+            second = _mm256_fmadd_ps(_mm256_loadu_ps(a.as_ptr().add(i + 8)), _mm256_loadu_ps(b.as_ptr().add(i + 8)), second);
+        }
+        sum_avx(_mm256_add_ps(first, second)) + tail_dot(a.skip(n), b.skip(n))
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn squared_l2_avx2(a: Floats, b: Floats) -> f32 {
+        let n = a.len() / 16 * 16;
+        let (mut first, mut second) = (_mm256_setzero_ps(), _mm256_setzero_ps());
+        for i in (0..n).step_by(16) {
+            let difference = _mm256_sub_ps(_mm256_loadu_ps(a.as_ptr().add(i)), _mm256_loadu_ps(b.as_ptr().add(i)));
+            first = _mm256_fmadd_ps(difference, difference, first);
+            let difference = _mm256_sub_ps(_mm256_loadu_ps(a.as_ptr().add(i + 8)), _mm256_loadu_ps(b.as_ptr().add(i + 8)));
+            second = _mm256_fmadd_ps(difference, difference, second);
+        }
+        sum_avx(_mm256_add_ps(first, second)) + tail_squared_l2(a.skip(n), b.skip(n))
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn dot_4x2_avx2(queries: [Floats; 4], rows: [Floats; 2]) -> [[f32; 2]; 4] {
+        let length = rows[0].len();
+        let n = length / 8 * 8;
+        let mut sums = [[_mm256_setzero_ps(); 2]; 4];
+        for i in (0..n).step_by(8) {
+            let row = [_mm256_loadu_ps(rows[0].as_ptr().add(i)), _mm256_loadu_ps(rows[1].as_ptr().add(i))];
+            for (sums, query) in sums.iter_mut().zip(queries.iter()) {
+                let query = _mm256_loadu_ps(query.as_ptr().add(i));
+                sums[0] = _mm256_fmadd_ps(query, row[0], sums[0]);
+                sums[1] = _mm256_fmadd_ps(query, row[1], sums[1]);
+            }
+        }
+        let mut dots = [[0.0f32; 2]; 4];
+        for ((dots, sums), query) in dots.iter_mut().zip(sums.iter()).zip(queries.iter()) {
+            for ((dot, sum), row) in dots.iter_mut().zip(sums.iter()).zip(rows.iter()) {
+                *dot = sum_avx(*sum) + tail_dot(query.skip(n), row.skip(n));
+            }
+        }
+        dots
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn manhattan_avx2(a: Floats, b: Floats) -> f32 {
+        // clearing the sign bit is the absolute value
+        let sign = _mm256_set1_ps(-0.0);
+        let n = a.len() / 16 * 16;
+        let (mut first, mut second) = (_mm256_setzero_ps(), _mm256_setzero_ps());
+        for i in (0..n).step_by(16) {
+            first = _mm256_add_ps(first, _mm256_andnot_ps(sign, _mm256_sub_ps(_mm256_loadu_ps(a.as_ptr().add(i)), _mm256_loadu_ps(b.as_ptr().add(i)))));
+            second = _mm256_add_ps(second, _mm256_andnot_ps(sign, _mm256_sub_ps(_mm256_loadu_ps(a.as_ptr().add(i + 8)), _mm256_loadu_ps(b.as_ptr().add(i + 8)))));
+        }
+        sum_avx(_mm256_add_ps(first, second)) + tail_manhattan(a.skip(n), b.skip(n))
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn dots_avx2(a: Floats, b: Floats) -> (f32, f32, f32) {
+        let n = a.len() / 8 * 8;
+        let (mut ab, mut aa, mut bb) = (_mm256_setzero_ps(), _mm256_setzero_ps(), _mm256_setzero_ps());
+        for i in (0..n).step_by(8) {
+            let x = _mm256_loadu_ps(a.as_ptr().add(i));
+            let y = _mm256_loadu_ps(b.as_ptr().add(i));
+            ab = _mm256_fmadd_ps(x, y, ab);
+            aa = _mm256_fmadd_ps(x, x, aa);
+            bb = _mm256_fmadd_ps(y, y, bb);
+        }
+        let (a, b) = (a.skip(n), b.skip(n));
+        (sum_avx(ab) + tail_dot(a, b), sum_avx(aa) + tail_dot(a, a), sum_avx(bb) + tail_dot(b, b))
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn sum_avx(sum: __m256) -> f32 {
+        sum_sse(_mm_add_ps(_mm256_castps256_ps128(sum), _mm256_extractf128_ps(sum, 1)))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn dot_sse(a: Floats, b: Floats) -> f32 {
+        let n = a.len() / 4 * 4;
+        let mut sum = _mm_setzero_ps();
+        for i in (0..n).step_by(4) {
+            sum = _mm_add_ps(sum, _mm_mul_ps(_mm_loadu_ps(a.as_ptr().add(i)), _mm_loadu_ps(b.as_ptr().add(i))));
+        }
+        sum_sse(sum) + tail_dot(a.skip(n), b.skip(n))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn squared_l2_sse(a: Floats, b: Floats) -> f32 {
+        let n = a.len() / 4 * 4;
+        let mut sum = _mm_setzero_ps();
+        for i in (0..n).step_by(4) {
+            let difference = _mm_sub_ps(_mm_loadu_ps(a.as_ptr().add(i)), _mm_loadu_ps(b.as_ptr().add(i)));
+            sum = _mm_add_ps(sum, _mm_mul_ps(difference, difference));
+        }
+        sum_sse(sum) + tail_squared_l2(a.skip(n), b.skip(n))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn manhattan_sse(a: Floats, b: Floats) -> f32 {
+        let sign = _mm_set1_ps(-0.0);
+        let n = a.len() / 4 * 4;
+        let mut sum = _mm_setzero_ps();
+        for i in (0..n).step_by(4) {
+            sum = _mm_add_ps(sum, _mm_andnot_ps(sign, _mm_sub_ps(_mm_loadu_ps(a.as_ptr().add(i)), _mm_loadu_ps(b.as_ptr().add(i)))));
+        }
+        sum_sse(sum) + tail_manhattan(a.skip(n), b.skip(n))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn dots_sse(a: Floats, b: Floats) -> (f32, f32, f32) {
+        let n = a.len() / 4 * 4;
+        let (mut ab, mut aa, mut bb) = (_mm_setzero_ps(), _mm_setzero_ps(), _mm_setzero_ps());
+        for i in (0..n).step_by(4) {
+            let x = _mm_loadu_ps(a.as_ptr().add(i));
+            let y = _mm_loadu_ps(b.as_ptr().add(i));
+            ab = _mm_add_ps(ab, _mm_mul_ps(x, y));
+            aa = _mm_add_ps(aa, _mm_mul_ps(x, x));
+            bb = _mm_add_ps(bb, _mm_mul_ps(y, y));
+        }
+        let (a, b) = (a.skip(n), b.skip(n));
+        (sum_sse(ab) + tail_dot(a, b), sum_sse(aa) + tail_dot(a, a), sum_sse(bb) + tail_dot(b, b))
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn sum_sse(sum: __m128) -> f32 {
+        let mut lanes = [0.0f32; 4];
+        _mm_storeu_ps(lanes.as_mut_ptr(), sum);
+        (lanes[0] + lanes[1]) + (lanes[2] + lanes[3])
+    }
+
+    fn tail_dot(a: Floats, b: Floats) -> f32 {
+        a.iter().zip(b.iter()).map(|(a, b)| a*b).sum()
+    }
+
+    fn tail_squared_l2(a: Floats, b: Floats) -> f32 {
+        a.iter().zip(b.iter()).map(|(a, b)| (a - b)*(a - b)).sum()
+    }
+
+    fn tail_manhattan(a: Floats, b: Floats) -> f32 {
+        a.iter().zip(b.iter()).map(|(a, b)| (a - b).abs()).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    // the loop Model::cosine used to run, kept as the reference
+    fn reference_cosine(vec_a: &[f32], vec_b: &[f32]) -> f32 {
+        let mut sum: f32 = 0.0;
+        let mut norm_a: f32 = 0.0;
+        let mut norm_b: f32 = 0.0;
+        for (val_a,val_b) in vec_a.iter().zip(vec_b) {
+            sum += val_a*val_b;
+            norm_a += val_a.powi(2);
+            norm_b += val_b.powi(2);
+        }
+        norm_a = norm_a.powf(0.5);
+        norm_b = norm_b.powf(0.5);
+
+        sum/(norm_a*norm_b)
+    }
+
+    // deterministic values in [-1, 1)
+    fn vector(seed: u32, length: usize) -> Vec<f32> {
+        let mut state = seed.wrapping_mul(2654435761).wrapping_add(1);
+        (0..length).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 8) as f32 / (1 << 23) as f32 - 1.0
+        }).collect()
+    }
+
+    fn close(a: f32, b: f32, scale: f32) -> bool {
+        (a - b).abs() <= 1e-5*scale.max(1.0)
+    }
+
+    #[test]
+    fn kernels_match_scalar() {
+        // lengths either side of every register width, and typical dimensions
+        for length in [0, 1, 3, 4, 5, 7, 8, 9, 15, 16, 17, 31, 33, 100, 300, 301].iter() {
+            let a = vector(*length as u32, *length);
+            let b = vector(*length as u32 + 1000, *length);
+            let dot: f32 = a.iter().zip(&b).map(|(a, b)| a*b).sum();
+            let squared_l2: f32 = a.iter().zip(&b).map(|(a, b)| (a - b)*(a - b)).sum();
+            let manhattan: f32 = a.iter().zip(&b).map(|(a, b)| (a - b).abs()).sum();
+            let magnitude = *length as f32;
+            for level in available() {
+                assert!(close(dot_with(level, &a, &b), dot, magnitude), "dot {:?} {}", level, length);
+                assert!(close(squared_l2_with(level, &a, &b), squared_l2, magnitude), "squared_l2 {:?} {}", level, length);
+                assert!(close(manhattan_with(level, &a, &b), manhattan, magnitude), "manhattan {:?} {}", level, length);
+                if *length > 0 {
+                    assert!(close(cosine_with(level, &a, &b), reference_cosine(&a, &b), 1.0), "cosine {:?} {}", level, length);
+                }
+            }
+            // a level this CPU lacks runs at the widest one it has
+            assert!(close(dot_with(Level::Avx512, &a, &b), dot, magnitude), "dot Avx512 {}", length);
+        }
+        assert_eq!(cosine(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn unaligned_bytes_match_slices() {
+        // a row of a mapped model can start at any byte
+        let (a, b) = (vector(1, 37), vector(2, 37));
+        for shift in 0..4 {
+            let mut bytes = vec![0u8; shift];
+            b.iter().for_each(|value| bytes.extend_from_slice(&value.to_le_bytes()));
+            let row = Floats::from_le_bytes(&bytes[shift..]);
+            assert_eq!(row.to_vec(), b);
+            for level in available() {
+                assert_eq!(dot_with(level, &a, row), dot_with(level, &a, &b), "dot {:?} {}", level, shift);
+                assert_eq!(squared_l2_with(level, &a, row), squared_l2_with(level, &a, &b), "squared_l2 {:?} {}", level, shift);
+                assert_eq!(manhattan_with(level, &a, row), manhattan_with(level, &a, &b), "manhattan {:?} {}", level, shift);
+                assert_eq!(cosine_with(level, &a, row), cosine_with(level, &a, &b), "cosine {:?} {}", level, shift);
+            }
+        }
+    }
+
+    #[test]
+    fn dot_tile_matches_dot() {
+        // blocks, ragged edges and typical dimensions
+        for (queries, rows, length) in [(0, 3, 8), (1, 1, 5), (4, 2, 16), (7, 5, 33), (9, 8, 300)].iter() {
+            let queries: Vec<Vec<f32>> = (0..*queries).map(|query| vector(query, *length)).collect();
+            let rows: Vec<Vec<f32>> = (0..*rows).map(|row| vector(row + 500, *length)).collect();
+            let queries: Vec<Floats> = queries.iter().map(Floats::from).collect();
+            let rows: Vec<Floats> = rows.iter().map(Floats::from).collect();
+            for level in available() {
+                let mut out = vec![f32::NAN; queries.len()*rows.len()];
+                dot_tile_with(level, &queries, &rows, &mut out);
+                for (query, vector) in queries.iter().enumerate() {
+                    for (row, other) in rows.iter().enumerate() {
+                        let dot: f32 = vector.iter().zip(other.iter()).map(|(a, b)| a*b).sum();
+                        assert!(close(out[query*rows.len() + row], dot, *length as f32), "{:?} {} {}", level, query, row);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dot_tile_ragged_lengths() {
+        // every block has one vector shorter than the rest
+        let queries: Vec<Vec<f32>> = (0..5).map(|query| vector(query, if query == 2 { 9 } else { 40 })).collect();
+        let rows: Vec<Vec<f32>> = (0..4).map(|row| vector(row + 500, if row == 3 { 17 } else { 40 })).collect();
+        let queries: Vec<Floats> = queries.iter().map(Floats::from).collect();
+        let rows: Vec<Floats> = rows.iter().map(Floats::from).collect();
+        for level in available() {
+            let mut out = vec![f32::NAN; queries.len()*rows.len()];
+            dot_tile_with(level, &queries, &rows, &mut out);
+            for (query, vector) in queries.iter().enumerate() {
+                for (row, other) in rows.iter().enumerate() {
+                    let length = vector.len().min(other.len());
+                    let dot: f32 = vector.iter().zip(other.iter()).map(|(a, b)| a*b).sum();
+                    assert!(close(out[query*rows.len() + row], dot, length as f32), "{:?} {} {}", level, query, row);
+                }
+            }
+        }
+    }
+
+    // cargo test --release kernel_benchmark -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn kernel_benchmark() {
+        // small enough to stay in cache, so this times the arithmetic rather
+        // than memory bandwidth
+        let (size, rows, passes) = (300, 1000, 1000);
+        let query = vector(0, size);
+        let matrix: Vec<f32> = (1..=rows).flat_map(|row| vector(row as u32, size)).collect();
+
+        let time = |name: &str, kernel: &dyn Fn(&[f32], &[f32]) -> f32| {
+            let start = Instant::now();
+            let mut total: f32 = 0.0;
+            for _ in 0..passes {
+                total += matrix.chunks_exact(size).map(|row| kernel(&query, row)).sum::<f32>();
+            }
+            let seconds = start.elapsed().as_secs_f64();
+            println!("{:<24} {:>8.1} ns/vector ({})", name, seconds*1e9 / (rows*passes) as f64, total);
+        };
+        time("cosine, old loop", &reference_cosine);
+        for level in available() {
+            time(&format!("dot, {:?}", level), &|a, b| dot_with(level, a, b));
+            time(&format!("squared_l2, {:?}", level), &|a, b| squared_l2_with(level, a, b));
+            time(&format!("manhattan, {:?}", level), &|a, b| manhattan_with(level, a, b));
+            time(&format!("cosine, {:?}", level), &|a, b| cosine_with(level, a, b));
+        }
+
+        // the same matrix against a batch of queries, one dot at a time and
+        // a tile at a time
+        let queries: Vec<Vec<f32>> = (0..64).map(|query| vector(query + 5000, size)).collect();
+        let queries: Vec<Floats> = queries.iter().map(Floats::from).collect();
+        let rows: Vec<Floats> = matrix.chunks_exact(size).map(Floats::from).collect();
+        let mut out = vec![0.0; queries.len()*rows.len()];
+        for level in available() {
+            let start = Instant::now();
+            for _ in 0..passes / 64 {
+                for (query, vector) in queries.iter().enumerate() {
+                    for (row, other) in rows.iter().enumerate() {
+                        out[query*rows.len() + row] = dot_with(level, *vector, *other);
+                    }
+                }
+            }
+            let single = start.elapsed().as_secs_f64();
+            let start = Instant::now();
+            for _ in 0..passes / 64 {
+                dot_tile_with(level, &queries, &rows, &mut out);
+            }
+            let tiled = start.elapsed().as_secs_f64();
+            let count = (rows.len()*queries.len()*(passes / 64)) as f64;
+            println!("{:<24} {:>8.1} ns/vector one at a time, {:.1} tiled", format!("dot batch, {:?}", level), single*1e9 / count, tiled*1e9 / count);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use super::{capacity_hint, check_dimension, check_word_count, decode_word, norm, Utf8Policy, VocabFilter, W2VError, MAX_HEADER_BYTES, MAX_WORD_BYTES};
+use super::kernels::Floats;
+use byteorder::{ByteOrder, LittleEndian};
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+// A binary model left in the page cache. Only the word -> offset index lives
+// on the heap, vectors are read out of the mapping on demand, so several
+// processes serving the same file share one copy of it. Scans read every row
+// in place, aligned or not.
+#[derive(Debug)]
+pub(super) struct MappedVectors {
+    map: Mmap,
+    // words in the order their vectors sit in the file
+    words: Vec<Arc<str>>,
+    // (offset of each word's vector, the vector's L2 norm), in the same order
+    rows: Vec<(usize,f32)>,
+    // word -> row
+    index: HashMap<Arc<str>,usize>,
+    size: usize,
+    // Rows that aren't 4 byte aligned, decoded the first time `get` lends
+    // them out as a slice. Entries are never replaced or removed, so each
+    // one stays where it is for as long as the mapping does.
+    decoded: Mutex<HashMap<usize,Box<[f32]>>>,
+}
+
+impl MappedVectors {
+    // returns the word count alongside the mapping
+    pub(super) fn open(file: &fs::File, filter: &VocabFilter, utf8: Utf8Policy) -> Result<(usize, MappedVectors), W2VError> {
+        // rows are read in place as little-endian f32s
+        if cfg!(target_endian = "big") {
+            return Err(W2VError::NotMappable);
+        }
+        // The mapping is only ever read. As with any mmap, the file must not be
+        // truncated while the model is alive.
+        let map = match unsafe { Mmap::map(file) } {
+            Ok(map) => map,
+            Err(_) => return Err(W2VError::NotMappable),
+        };
+        let (total_words, size, position) = parse_header(&map)?;
+
+        let capacity = filter.capacity(capacity_hint(total_words, size*4, Some(map.len())));
+        let mut words: Vec<Arc<str>> = Vec::with_capacity(capacity);
+        let mut rows: Vec<(usize,f32)> = Vec::with_capacity(capacity);
+        let mut index: HashMap<Arc<str>,usize> = HashMap::with_capacity(capacity);
+        let mut records = Records::new(&map, position, size);
+        let mut full = false;
+        let mut vector: Vec<f32> = vec![0.0; size];
+        for record in &mut records {
+            let (word, vector_start) = record?;
+            let word = match decode_word(word, utf8) {
+                Some(decoded) => decoded,
+                None => return Err(W2VError::InvalidUtf8 { record: records.read() - 1, offset: vector_start - 1 - word.len() }),
+            };
+            if filter.keeps(&word) {
+                LittleEndian::read_f32_into(&map[vector_start..vector_start + size*4], &mut vector);
+                // a repeated word keeps its first row and its last vector
+                match index.get(word.as_str()) {
+                    Some(row) => rows[*row] = (vector_start, norm(&vector)),
+                    None => {
+                        let word: Arc<str> = Arc::from(word);
+                        index.insert(word.clone(), words.len());
+                        words.push(word);
+                        rows.push((vector_start, norm(&vector)));
+                    }
+                }
+            }
+            if filter.is_full(words.len()) {
+                full = true;
+                break;
+            }
+        }
+        // the rest of the file is never read when the filter fills up
+        if !full {
+            check_word_count(total_words, records.read())?;
+        }
+
+        Ok((filter.total_words(total_words, words.len()), MappedVectors {
+            map,
+            words,
+            rows,
+            index,
+            size,
+            decoded: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub(super) fn contains_key(&self, word: &str) -> bool {
+        self.index.contains_key(word)
+    }
+
+    // words ordered by where their vectors sit in the file
+    pub(super) fn words(&self) -> Vec<&str> {
+        self.words.iter().map(|word| word.as_ref()).collect()
+    }
+
+    // A slice of the mapping when the row is aligned, otherwise a decoded
+    // copy that is kept for next time
+    pub(super) fn get(&self, word: &str) -> Option<&[f32]> {
+        let row = *self.index.get(word)?;
+        if let Some(values) = self.aligned(row) {
+            return Some(values);
+        }
+        let mut decoded = self.decoded.lock().unwrap();
+        let values = decoded.entry(row).or_insert_with(|| self.vector(row).to_vec().into_boxed_slice());
+        // the box outlives the lock, see `decoded`
+        Some(unsafe { std::slice::from_raw_parts(values.as_ptr(), values.len()) })
+    }
+
+    // Like get, but a row that isn't aligned is copied for the caller
+    // rather than kept
+    pub(super) fn get_with_norm(&self, word: &str) -> Option<(Cow<'_, [f32]>, f32)> {
+        self.index.get(word).map(|row| {
+            let vector = match self.aligned(*row) {
+                Some(values) => Cow::Borrowed(values),
+                None => Cow::Owned(self.vector(*row).to_vec()),
+            };
+            (vector, self.rows[*row].1)
+        })
+    }
+
+    pub(super) fn iter_with_norms(&self) -> impl Iterator<Item=(&str, Floats<'_>, f32)> {
+        self.iter_range(0, self.len())
+    }
+
+    // rows start..end, in file order, read in place
+    pub(super) fn iter_range(&self, start: usize, end: usize) -> impl Iterator<Item=(&str, Floats<'_>, f32)> {
+        (start..end).map(move |row| (self.words[row].as_ref(), self.vector(row), self.rows[row].1))
+    }
+
+    fn vector(&self, row: usize) -> Floats<'_> {
+        let offset = self.rows[row].0;
+        Floats::from_le_bytes(&self.map[offset..offset + self.size*4])
+    }
+
+    // Vectors follow variable length words, so they are only 4 byte aligned
+    // some of the time
+    fn aligned(&self, row: usize) -> Option<&[f32]> {
+        let offset = self.rows[row].0;
+        let bytes = &self.map[offset..offset + self.size*4];
+        if bytes.as_ptr().align_offset(mem::align_of::<f32>()) == 0 {
+            Some(unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const f32, self.size) })
+        } else {
+            None
+        }
+    }
+}
+
+// Walks the records of a binary model, yielding each word and the offset of
+// the vector that follows it. Stops at the end of the slice, and reports
+// records that are cut short or whose word never ends.
+pub(super) struct Records<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    record_bytes: usize,
+    read: usize,
+}
+
+impl<'a> Records<'a> {
+    pub(super) fn new(bytes: &'a [u8], position: usize, size: usize) -> Records<'a> {
+        Records {
+            bytes,
+            position,
+            record_bytes: size*4,
+            read: 0,
+        }
+    }
+
+    // offset of the next record to be read
+    pub(super) fn position(&self) -> usize {
+        self.position
+    }
+
+    // number of records yielded so far
+    pub(super) fn read(&self) -> usize {
+        self.read
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<(&'a [u8], usize), W2VError>;
+
+    fn next(&mut self) -> Option<Result<(&'a [u8], usize), W2VError>> {
+        let bytes = self.bytes;
+        loop {
+            if self.position >= bytes.len() {
+                return None;
+            }
+            // records are optionally separated by \n
+            if bytes[self.position] == b'\n' {
+                self.position += 1;
+                continue;
+            }
+            let record_start = self.position;
+            let word_limit = bytes.len().min(record_start + MAX_WORD_BYTES + 1);
+            let word_end = match bytes[record_start..word_limit].iter().position(|b| *b == b' ') {
+                Some(length) => record_start + length,
+                None => {
+                    // stop here, there is nothing sensible to read after this
+                    self.position = bytes.len();
+                    return Some(Err(if word_limit == bytes.len() {
+                        W2VError::TruncatedRecord { record: self.read, offset: record_start }
+                    } else {
+                        W2VError::WordTooLong { record: self.read, offset: record_start }
+                    }));
+                }
+            };
+            let vector_start = word_end + 1;
+            if vector_start + self.record_bytes > bytes.len() {
+                self.position = bytes.len();
+                return Some(Err(W2VError::TruncatedRecord { record: self.read, offset: record_start }));
+            }
+            let word = &bytes[record_start..word_end];
+            self.position = vector_start + self.record_bytes;
+            self.read += 1;
+            return Some(Ok((word, vector_start)));
+        }
+    }
+}
+
+// returns (total_words, size, offset of the first record)
+pub(super) fn parse_header(bytes: &[u8]) -> Result<(usize, usize, usize), W2VError> {
+    let header_end = match bytes.iter().take(MAX_HEADER_BYTES).position(|b| *b == b'\n') {
+        Some(index) => index,
+        None => return Err(W2VError::BadHeader(bytes.len().min(MAX_HEADER_BYTES))),
+    };
+    // (offset, text) of each whitespace separated field
+    let mut fields: Vec<(usize, &[u8])> = Vec::with_capacity(2);
+    let mut field_start: Option<usize> = None;
+    for (index, byte) in bytes[..=header_end].iter().enumerate() {
+        match (byte.is_ascii_whitespace(), field_start) {
+            (true, Some(start)) => {
+                fields.push((start, &bytes[start..index]));
+                field_start = None;
+            }
+            (false, None) => field_start = Some(index),
+            _ => {}
+        }
+    }
+    let mut values: [usize; 2] = [0; 2];
+    for (value, (offset, text)) in values.iter_mut().zip(fields.iter()) {
+        *value = match std::str::from_utf8(text).ok().and_then(|text| text.parse::<usize>().ok()) {
+            Some(parsed) => parsed,
+            None => return Err(W2VError::BadHeader(*offset)),
+        };
+    }
+    if fields.len() != 2 {
+        let offset = fields.get(2).map(|(offset, _)| *offset).unwrap_or(header_end);
+        return Err(W2VError::BadHeader(offset));
+    }
+    let (total_words, size) = (values[0], values[1]);
+    check_dimension(size)?;
+    Ok((total_words, size, header_end + 1))
+}
@@ -0,0 +1,100 @@
+use super::mapped::{parse_header, Records};
+use super::{check_word_count, decode_word, Lookup, Model, Matrix, SearchPool, Utf8Policy, VocabFilter, W2VError};
+use byteorder::{ByteOrder, LittleEndian};
+use std::time::Instant;
+
+// Parses a binary model on several threads. One cheap pass over the words
+// finds record boundaries that split the file into roughly equal byte ranges,
+// each range is then decoded on its own thread, and the per-thread maps are
+// merged in file order so a repeated word ends up with the same vector the
+// sequential loader would give it.
+pub(super) fn read_binary_parallel(map: &[u8], threads: usize, filter: &VocabFilter, utf8: Utf8Policy) -> Result<Model, W2VError> {
+    let start_time = Instant::now();
+    let (total_words, size, position) = parse_header(map)?;
+    let chunks = split_chunks(map, position, size, threads, utf8)?;
+    check_word_count(total_words, chunks.iter().map(|chunk| chunk.2).sum())?;
+
+    let parts: Vec<Matrix> = crossbeam::scope(|scope| {
+        let handles: Vec<_> = chunks.iter().map(|(start, end, _)| {
+            let bytes = &map[..*end];
+            let start = *start;
+            scope.spawn(move |_| parse_chunk(bytes, start, size, filter, utf8))
+        }).collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    }).unwrap();
+
+    let mut lookup = Matrix::with_capacity(parts.iter().map(|part| part.len()).sum(), size);
+    for part in parts {
+        lookup.append(part);
+        if filter.is_full(lookup.len()) {
+            break;
+        }
+    }
+    if let Some(max_words) = filter.max_words {
+        lookup.truncate(max_words);
+    }
+
+    let seconds = start_time.elapsed().as_secs_f64();
+    println!("parsed {} words on {} threads in {:.2}s ({:.1} MB/s, {:.0} words/s)",
+        lookup.len(), chunks.len(), seconds,
+        map.len() as f64 / 1_000_000.0 / seconds,
+        lookup.len() as f64 / seconds);
+
+    Ok(Model {
+        total_words: filter.total_words(total_words, lookup.len()),
+        size,
+        lookup: Lookup::Owned(lookup),
+        subwords: None,
+        search: SearchPool::new(1),
+        hnsw: None,
+        ivfpq: None,
+        utf8: Utf8Policy::default(),
+    })
+}
+
+// Returns (first record, end of last record, record count) for each chunk.
+// Every record is validated here, words included when invalid UTF-8 is
+// rejected, so the chunks handed to the threads are known to be well formed.
+fn split_chunks(bytes: &[u8], position: usize, size: usize, threads: usize, utf8: Utf8Policy) -> Result<Vec<(usize, usize, usize)>, W2VError> {
+    let threads = threads.max(1);
+    let target = (bytes.len() - position) / threads + 1;
+    let mut chunks: Vec<(usize, usize, usize)> = Vec::with_capacity(threads);
+    let mut chunk_start = position;
+    let mut chunk_first_record = 0;
+    let mut records = Records::new(bytes, position, size);
+    while let Some(record) = records.next() {
+        let (word, vector_start) = record?;
+        if utf8 == Utf8Policy::Reject && std::str::from_utf8(word).is_err() {
+            return Err(W2VError::InvalidUtf8 { record: records.read() - 1, offset: vector_start - 1 - word.len() });
+        }
+        if records.position() - chunk_start >= target {
+            chunks.push((chunk_start, records.position(), records.read() - chunk_first_record));
+            chunk_start = records.position();
+            chunk_first_record = records.read();
+        }
+    }
+    if chunk_start < bytes.len() {
+        chunks.push((chunk_start, bytes.len(), records.read() - chunk_first_record));
+    }
+    Ok(chunks)
+}
+
+// Each chunk is filtered on its own, so it can stop once it alone holds
+// enough words. The merge trims the total back down.
+fn parse_chunk(bytes: &[u8], start: usize, size: usize, filter: &VocabFilter, utf8: Utf8Policy) -> Matrix {
+    let mut lookup = Matrix::with_capacity(0, size);
+    let mut vector: Vec<f32> = vec![0.0; size];
+    for (word, vector_start) in Records::new(bytes, start, size).flatten() {
+        // split_chunks has already turned away any word this could reject
+        let word = decode_word(word, utf8).unwrap_or_default();
+        if !filter.keeps(&word) {
+            continue;
+        }
+        LittleEndian::read_f32_into(&bytes[vector_start..vector_start + size*4], &mut vector);
+        lookup.insert(word, &vector);
+        if filter.is_full(lookup.len()) {
+            break;
+        }
+    }
+    lookup
+}
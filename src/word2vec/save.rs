@@ -0,0 +1,54 @@
+use super::{encode_word, Model, W2VError};
+use std::io::prelude::*;
+
+// Writes Google's binary layout: a `count dim` header, then each word, a
+// space, its values as little-endian f32s and a \n, in vocabulary order.
+pub(super) fn write_binary<W: Write>(model: &Model, mut writer: W) -> Result<(), W2VError> {
+    let rows = model.lookup.len();
+    if writeln!(writer, "{} {}", rows, model.size).is_err() {
+        return Err(W2VError::WriteError(0));
+    }
+    let mut bytes: Vec<u8> = Vec::with_capacity(model.size*4 + 64);
+    for (record, (word, vector, _)) in model.lookup.iter_with_norms().enumerate() {
+        bytes.clear();
+        bytes.extend_from_slice(&encode_word(word, model.utf8));
+        bytes.push(b' ');
+        for value in vector.iter() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.push(b'\n');
+        if writer.write_all(&bytes).is_err() {
+            return Err(W2VError::WriteError(record));
+        }
+    }
+    match writer.flush() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(W2VError::WriteError(rows)),
+    }
+}
+
+// Writes the text layout with a `count dim` header. Values use the shortest
+// decimal form that parses back to the same f32, so nothing is lost.
+pub(super) fn write_text<W: Write>(model: &Model, mut writer: W) -> Result<(), W2VError> {
+    let rows = model.lookup.len();
+    if writeln!(writer, "{} {}", rows, model.size).is_err() {
+        return Err(W2VError::WriteError(0));
+    }
+    let mut line = String::with_capacity(model.size*12 + 64);
+    for (record, (word, vector, _)) in model.lookup.iter_with_norms().enumerate() {
+        line.clear();
+        line.push_str(word);
+        for value in vector.iter() {
+            line.push(' ');
+            line.push_str(&value.to_string());
+        }
+        line.push('\n');
+        if writer.write_all(line.as_bytes()).is_err() {
+            return Err(W2VError::WriteError(record));
+        }
+    }
+    match writer.flush() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(W2VError::WriteError(rows)),
+    }
+}
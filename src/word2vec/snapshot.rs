@@ -0,0 +1,429 @@
+use super::{check_dimension, Lookup, Model, SearchPool, Utf8Policy, W2VError};
+use memmap2::Mmap;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::mem;
+
+// Layout, all little-endian, every section starting on a 64 byte boundary:
+//
+//   header     magic, version, total_words, size, rows, word bytes, index
+//              slots, then a checksum over everything else in the file
+//   offsets    u64 x (rows + 1), where each word starts in the word bytes
+//   words      UTF-8, concatenated in row order
+//   matrix     f32 x rows x size, row-major
+//   norms      f32 x rows, the L2 norm of each row
+//   index      u32 x slots, open addressed hash table of row + 1, 0 is empty
+//
+// The file is mapped as is, so opening one costs a checksum pass and nothing
+// is parsed or copied.
+pub(super) const SNAPSHOT_MAGIC: &[u8; 8] = b"W2VSNAP\0";
+const SNAPSHOT_VERSION: u32 = 1;
+const HEADER_BYTES: usize = 64;
+const CHECKSUM_OFFSET: usize = 56;
+const ALIGN: usize = 64;
+
+#[derive(Debug)]
+pub(super) struct Snapshot {
+    map: Mmap,
+    total_words: usize,
+    size: usize,
+    rows: usize,
+    slots: usize,
+    offsets_start: usize,
+    words_start: usize,
+    matrix_start: usize,
+    norms_start: usize,
+    index_start: usize,
+}
+
+// byte ranges of each section, given the header's counts
+struct Sections {
+    offsets_start: usize,
+    words_start: usize,
+    matrix_start: usize,
+    norms_start: usize,
+    index_start: usize,
+    end: usize,
+}
+
+impl Sections {
+    fn new(rows: usize, size: usize, word_bytes: usize, slots: usize) -> Option<Sections> {
+        let offsets_start = HEADER_BYTES;
+        let words_start = padded(offsets_start, rows.checked_add(1)?.checked_mul(8)?)?;
+        let matrix_start = padded(words_start, word_bytes)?;
+        let norms_start = padded(matrix_start, rows.checked_mul(size)?.checked_mul(4)?)?;
+        let index_start = padded(norms_start, rows.checked_mul(4)?)?;
+        let end = padded(index_start, slots.checked_mul(4)?)?;
+        Some(Sections {
+            offsets_start,
+            words_start,
+            matrix_start,
+            norms_start,
+            index_start,
+            end,
+        })
+    }
+}
+
+// start of the section after one of `length` bytes at `start`
+fn padded(start: usize, length: usize) -> Option<usize> {
+    let end = start.checked_add(length)?;
+    end.checked_add((ALIGN - end % ALIGN) % ALIGN)
+}
+
+impl Snapshot {
+    pub(super) fn open(file: &fs::File) -> Result<Snapshot, W2VError> {
+        if cfg!(target_endian = "big") {
+            return Err(W2VError::NotMappable);
+        }
+        let map = match unsafe { Mmap::map(file) } {
+            Ok(map) => map,
+            Err(_) => return Err(W2VError::NotMappable),
+        };
+        if map.len() < HEADER_BYTES || &map[..8] != SNAPSHOT_MAGIC {
+            return Err(W2VError::BadHeader(0));
+        }
+        let version = read_u32(&map, 8);
+        if version != SNAPSHOT_VERSION {
+            return Err(W2VError::SnapshotVersion(version));
+        }
+        let field = |offset: usize| read_u64(&map, offset) as usize;
+        let (total_words, size, rows, word_bytes, slots) = (field(16), field(24), field(32), field(40), field(48));
+        check_dimension(size)?;
+        let sections = match Sections::new(rows, size, word_bytes, slots) {
+            Some(sections) => sections,
+            None => return Err(W2VError::BadHeader(32)),
+        };
+        if sections.end != map.len() {
+            return Err(W2VError::TruncatedRecord { record: 0, offset: map.len() });
+        }
+        let mut hasher = Checksum::new();
+        hasher.update(&map[..CHECKSUM_OFFSET]);
+        hasher.update(&map[HEADER_BYTES..]);
+        if hasher.finish() != read_u64(&map, CHECKSUM_OFFSET) {
+            return Err(W2VError::ChecksumMismatch);
+        }
+
+        let snapshot = Snapshot {
+            total_words,
+            size,
+            rows,
+            slots,
+            offsets_start: sections.offsets_start,
+            words_start: sections.words_start,
+            matrix_start: sections.matrix_start,
+            norms_start: sections.norms_start,
+            index_start: sections.index_start,
+            map,
+        };
+        // the checksum only proves the file is what was written, so make sure
+        // what was written can't send a lookup out of bounds
+        let words = &snapshot.map[snapshot.words_start..snapshot.words_start + word_bytes];
+        if std::str::from_utf8(words).is_err() {
+            return Err(W2VError::BadHeader(snapshot.words_start));
+        }
+        let mut previous = 0;
+        for row in 0..=rows {
+            let offset = read_u64(&snapshot.map, snapshot.offsets_start + row*8) as usize;
+            if offset < previous || offset > word_bytes || !is_char_boundary(words, offset) {
+                return Err(W2VError::BadHeader(snapshot.offsets_start + row*8));
+            }
+            previous = offset;
+        }
+        if rows > 0 && slots <= rows || slots & slots.wrapping_sub(1) != 0 {
+            return Err(W2VError::BadHeader(48));
+        }
+        // a lookup of a missing word probes until it reaches an empty slot,
+        // so there has to be one
+        let mut empty = slots == 0;
+        for slot in 0..slots {
+            match read_u32(&snapshot.map, snapshot.index_start + slot*4) as usize {
+                0 => empty = true,
+                entry if entry > rows => return Err(W2VError::BadHeader(snapshot.index_start + slot*4)),
+                _ => {}
+            }
+        }
+        if !empty {
+            return Err(W2VError::BadHeader(snapshot.index_start));
+        }
+        Ok(snapshot)
+    }
+
+    pub(super) fn total_words(&self) -> usize {
+        self.total_words
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.rows
+    }
+
+    pub(super) fn word(&self, row: usize) -> &str {
+        let start = read_u64(&self.map, self.offsets_start + row*8) as usize;
+        let end = read_u64(&self.map, self.offsets_start + (row + 1)*8) as usize;
+        let bytes = &self.map[self.words_start + start..self.words_start + end];
+        // checked when the snapshot was opened
+        unsafe { std::str::from_utf8_unchecked(bytes) }
+    }
+
+    pub(super) fn row(&self, row: usize) -> &[f32] {
+        self.floats(self.matrix_start + row*self.size*4, self.size)
+    }
+
+    pub(super) fn norm(&self, row: usize) -> f32 {
+        self.floats(self.norms_start + row*4, 1)[0]
+    }
+
+    pub(super) fn find(&self, word: &str) -> Option<usize> {
+        if self.slots == 0 {
+            return None;
+        }
+        let mask = self.slots - 1;
+        let mut slot = hash(word.as_bytes()) as usize & mask;
+        loop {
+            match read_u32(&self.map, self.index_start + slot*4) as usize {
+                0 => return None,
+                entry if self.word(entry - 1) == word => return Some(entry - 1),
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+    }
+
+    pub(super) fn get(&self, word: &str) -> Option<&[f32]> {
+        self.find(word).map(|row| self.row(row))
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item=(&str, &[f32])> {
+        (0..self.rows).map(move |row| (self.word(row), self.row(row)))
+    }
+
+    pub(super) fn words(&self) -> Vec<&str> {
+        (0..self.rows).map(|row| self.word(row)).collect()
+    }
+
+    // Sections start on 64 byte boundaries of a page aligned mapping, and the
+    // file is little-endian like the host, so the floats can be used in place.
+    fn floats(&self, start: usize, count: usize) -> &[f32] {
+        let bytes = &self.map[start..start + count*4];
+        debug_assert!(bytes.as_ptr().align_offset(mem::align_of::<f32>()) == 0);
+        unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const f32, count) }
+    }
+}
+
+fn is_char_boundary(words: &[u8], offset: usize) -> bool {
+    offset == words.len() || words[offset] & 0xC0 != 0x80
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+// FNV-1a, used to place words in the index
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+// A cheap 64 bit checksum that mixes in eight bytes at a time, so verifying
+// a multi-gigabyte snapshot runs at close to memory speed. Input can arrive
+// in pieces of any length.
+pub(super) struct Checksum {
+    state: u64,
+    pending: [u8; 8],
+    pending_len: usize,
+    total: u64,
+}
+
+impl Checksum {
+    pub(super) fn new() -> Checksum {
+        Checksum {
+            state: 0x9e3779b97f4a7c15,
+            pending: [0; 8],
+            pending_len: 0,
+            total: 0,
+        }
+    }
+
+    fn mix(&mut self, word: u64) {
+        self.state = (self.state ^ word).wrapping_mul(0x100000001b3).rotate_left(29);
+    }
+
+    pub(super) fn update(&mut self, mut bytes: &[u8]) {
+        self.total += bytes.len() as u64;
+        if self.pending_len > 0 {
+            let take = bytes.len().min(8 - self.pending_len);
+            self.pending[self.pending_len..self.pending_len + take].copy_from_slice(&bytes[..take]);
+            self.pending_len += take;
+            bytes = &bytes[take..];
+            if self.pending_len < 8 {
+                return;
+            }
+            let word = u64::from_le_bytes(self.pending);
+            self.mix(word);
+            self.pending_len = 0;
+        }
+        let mut words = bytes.chunks_exact(8);
+        for chunk in &mut words {
+            let mut word = [0; 8];
+            word.copy_from_slice(chunk);
+            self.mix(u64::from_le_bytes(word));
+        }
+        let rest = words.remainder();
+        self.pending[..rest.len()].copy_from_slice(rest);
+        self.pending_len = rest.len();
+    }
+
+    pub(super) fn finish(mut self) -> u64 {
+        if self.pending_len > 0 {
+            let mut word = [0; 8];
+            word[..self.pending_len].copy_from_slice(&self.pending[..self.pending_len]);
+            self.mix(u64::from_le_bytes(word));
+        }
+        let total = self.total;
+        self.mix(total);
+        self.state
+    }
+}
+
+// Passes everything written through to the file, and into the checksum
+struct ChecksumWriter<W> {
+    inner: W,
+    checksum: Checksum,
+    written: usize,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), W2VError> {
+        self.checksum.update(bytes);
+        self.written += bytes.len();
+        match self.inner.write_all(bytes) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(W2VError::WriteError(self.written)),
+        }
+    }
+
+    // zeros up to the start of the next section
+    fn pad(&mut self) -> Result<(), W2VError> {
+        let padding = (ALIGN - self.written % ALIGN) % ALIGN;
+        self.write(&[0; ALIGN][..padding])
+    }
+}
+
+pub(super) fn write_snapshot(model: &Model, file: fs::File) -> Result<(), W2VError> {
+    let words = model.lookup.words();
+    let rows = words.len();
+    let word_bytes: usize = words.iter().map(|word| word.len()).sum();
+    let slots = (rows*2).next_power_of_two();
+
+    let mut header = [0u8; HEADER_BYTES];
+    header[..8].copy_from_slice(SNAPSHOT_MAGIC);
+    header[8..12].copy_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    for (offset, value) in [(16, model.total_words), (24, model.size), (32, rows), (40, word_bytes), (48, slots)].iter() {
+        header[*offset..*offset + 8].copy_from_slice(&(*value as u64).to_le_bytes());
+    }
+
+    let mut writer = ChecksumWriter {
+        inner: BufWriter::with_capacity(1000000, file),
+        checksum: Checksum::new(),
+        written: 0,
+    };
+    writer.checksum.update(&header[..CHECKSUM_OFFSET]);
+    // the real header goes in once the checksum is known
+    if writer.inner.write_all(&header).is_err() {
+        return Err(W2VError::WriteError(0));
+    }
+    writer.written = HEADER_BYTES;
+
+    let mut offset: u64 = 0;
+    writer.write(&offset.to_le_bytes())?;
+    for word in words.iter() {
+        offset += word.len() as u64;
+        writer.write(&offset.to_le_bytes())?;
+    }
+    writer.pad()?;
+    for word in words.iter() {
+        writer.write(word.as_bytes())?;
+    }
+    writer.pad()?;
+
+    let mut norms: Vec<f32> = Vec::with_capacity(rows);
+    let mut bytes: Vec<u8> = Vec::with_capacity(model.size*4);
+    for (_, vector, norm) in model.lookup.iter_with_norms() {
+        bytes.clear();
+        for value in vector.iter() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        writer.write(&bytes)?;
+        norms.push(norm);
+    }
+    writer.pad()?;
+    for norm in norms.iter() {
+        writer.write(&norm.to_le_bytes())?;
+    }
+    writer.pad()?;
+
+    let mut index: Vec<u32> = vec![0; slots];
+    let mask = slots - 1;
+    for (row, word) in words.iter().enumerate() {
+        let mut slot = hash(word.as_bytes()) as usize & mask;
+        while index[slot] != 0 {
+            slot = (slot + 1) & mask;
+        }
+        index[slot] = row as u32 + 1;
+    }
+    for entry in index.iter() {
+        writer.write(&entry.to_le_bytes())?;
+    }
+    writer.pad()?;
+
+    let checksum = writer.checksum.finish();
+    header[CHECKSUM_OFFSET..].copy_from_slice(&checksum.to_le_bytes());
+    let mut file = match writer.inner.into_inner() {
+        Ok(file) => file,
+        Err(_) => return Err(W2VError::WriteError(writer.written)),
+    };
+    match file.seek(SeekFrom::Start(0)).and_then(|_| file.write_all(&header)) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(W2VError::WriteError(0)),
+    }
+}
+
+// Copies the rows that pass a filter onto the heap, for when a snapshot is
+// opened with a vocabulary filter
+pub(super) fn filtered(snapshot: &Snapshot, filter: &super::VocabFilter) -> Model {
+    let mut matrix = super::Matrix::with_capacity(filter.capacity(snapshot.len()), snapshot.size());
+    for (word, vector) in snapshot.iter() {
+        if filter.is_full(matrix.len()) {
+            break;
+        }
+        if filter.keeps(word) {
+            matrix.insert(word.to_string(), vector);
+        }
+    }
+    Model {
+        total_words: filter.total_words(snapshot.total_words(), matrix.len()),
+        size: snapshot.size(),
+        lookup: Lookup::Owned(matrix),
+        subwords: None,
+        search: SearchPool::new(1),
+        hnsw: None,
+        ivfpq: None,
+        utf8: Utf8Policy::default(),
+    }
+}
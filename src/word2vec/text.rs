@@ -0,0 +1,95 @@
+use super::{capacity_hint, check_dimension, check_word_count, decode_word, Lookup, Model, Matrix, SearchPool, Utf8Policy, VocabFilter, W2VError};
+use std::io::prelude::*;
+
+// Reads the text layout: an optional `count dim` header line, then one word
+// per line followed by its values. GloVe dumps have no header, in which case
+// the dimension is taken from the first record and the word count is however
+// many records the file holds.
+pub(super) fn read_text<R: BufRead>(mut reader: R, file_len: Option<usize>, filter: &VocabFilter, utf8: Utf8Policy) -> Result<Model, W2VError> {
+    let mut line: Vec<u8> = Vec::with_capacity(4096);
+    let mut line_number: usize = 0;
+    let mut header: Option<(usize, usize)> = None;
+    let mut lookup = Matrix::default();
+    let mut size: usize = 0;
+    let mut records: usize = 0;
+    let mut offset: usize = 0;
+
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(_) => return Err(W2VError::ReadError(line_number)),
+        }
+        // the values are ASCII, so only the word can be invalid
+        let text = match decode_word(&line, utf8) {
+            Some(text) => text,
+            None => return Err(W2VError::InvalidUtf8 { record: records, offset }),
+        };
+        offset += line.len();
+        let mut fields = text.split_ascii_whitespace();
+        let word = match fields.next() {
+            Some(word) => word,
+            None => {
+                // blank line
+                line_number += 1;
+                continue;
+            }
+        };
+        let values: Vec<&str> = fields.collect();
+
+        if line_number == 0 && values.len() == 1 {
+            if let (Ok(total_words), Ok(dim)) = (word.parse::<usize>(), values[0].parse::<usize>()) {
+                check_dimension(dim)?;
+                header = Some((total_words, dim));
+                size = dim;
+                // each value takes at least a digit and a separator
+                lookup = Matrix::with_capacity(filter.capacity(capacity_hint(total_words, dim*2, file_len)), dim);
+                line_number += 1;
+                continue;
+            }
+        }
+        if size == 0 {
+            check_dimension(values.len())?;
+            size = values.len();
+        }
+        if values.len() != size {
+            return Err(W2VError::ReadError(line_number));
+        }
+        let mut vector: Vec<f32> = Vec::with_capacity(size);
+        for value in values {
+            match value.parse::<f32>() {
+                Ok(value) => vector.push(value),
+                Err(_) => return Err(W2VError::ReadError(line_number)),
+            }
+        }
+        if filter.keeps(word) {
+            lookup.insert(word.to_string(), &vector);
+        }
+        records += 1;
+        line_number += 1;
+        if filter.is_full(lookup.len()) {
+            // the rest of the file is never read, so can't be checked
+            header = None;
+            break;
+        }
+    }
+
+    let total_words = match header {
+        Some((total_words, _)) => {
+            check_word_count(total_words, records)?;
+            filter.total_words(total_words, lookup.len())
+        }
+        None => filter.total_words(records, lookup.len()),
+    };
+    Ok(Model {
+        total_words,
+        size,
+        lookup: Lookup::Owned(lookup),
+        subwords: None,
+        search: SearchPool::new(1),
+        hnsw: None,
+        ivfpq: None,
+        utf8: Utf8Policy::default(),
+    })
+}
@@ -0,0 +1,110 @@
+// The k best scores out of a scan, kept in a min-heap whose root is the worst
+// entry still in the running. Picking the top k of n words costs O(n log k)
+// and never holds more than k entries, all of them borrowing their word.
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+struct Candidate<'a> {
+    score: f32,
+    // where the word came in the scan, so equal scores come out in scan order
+    position: usize,
+    word: &'a str,
+}
+
+// Greater means better: a higher score, then an earlier position
+impl Ord for Candidate<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score).then_with(|| other.position.cmp(&self.position))
+    }
+}
+
+impl PartialOrd for Candidate<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate<'_> {}
+
+pub(super) struct TopK<'a> {
+    k: usize,
+    heap: BinaryHeap<Reverse<Candidate<'a>>>,
+}
+
+impl<'a> TopK<'a> {
+    pub(super) fn new(k: usize) -> TopK<'a> {
+        TopK {
+            k,
+            // k may be a huge "give me everything", so don't trust it for the
+            // allocation
+            heap: BinaryHeap::with_capacity(k.min(1024) + 1),
+        }
+    }
+
+    // NaN scores (vectors holding NaN) are never kept
+    pub(super) fn push(&mut self, position: usize, word: &'a str, score: f32) {
+        if self.k == 0 || score.is_nan() {
+            return;
+        }
+        let candidate = Candidate { score, position, word };
+        if self.heap.len() < self.k {
+            self.heap.push(Reverse(candidate));
+        } else if let Some(mut worst) = self.heap.peek_mut() {
+            if candidate > worst.0 {
+                *worst = Reverse(candidate);
+            }
+        }
+    }
+
+    pub(super) fn merge(&mut self, other: TopK<'a>) {
+        for Reverse(candidate) in other.heap {
+            self.push(candidate.position, candidate.word, candidate.score);
+        }
+    }
+
+    // best first
+    pub(super) fn into_sorted_vec(self) -> Vec<(&'a str, f32)> {
+        self.into_sorted_entries().into_iter().map(|(_, word, score)| (word, score)).collect()
+    }
+
+    // best first, with the position each entry was pushed with
+    pub(super) fn into_sorted_entries(self) -> Vec<(usize, &'a str, f32)> {
+        // sorting the Reverse entries ascending puts the best candidate first
+        self.heap.into_sorted_vec().into_iter().map(|Reverse(candidate)| (candidate.position, candidate.word, candidate.score)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_best_in_order() {
+        let scores = [0.1, 0.9, -0.5, 0.9, f32::NAN, 0.3, 0.7];
+        let words = ["a", "b", "c", "d", "e", "f", "g"];
+        let mut top = TopK::new(3);
+        for (position, (word, score)) in words.iter().zip(scores.iter()).enumerate() {
+            top.push(position, word, *score);
+        }
+        assert_eq!(top.into_sorted_vec(), vec![("b", 0.9), ("d", 0.9), ("g", 0.7)]);
+
+        // splitting the scan and merging gives the same answer
+        let (mut first, mut second) = (TopK::new(3), TopK::new(3));
+        for (position, (word, score)) in words.iter().zip(scores.iter()).enumerate() {
+            if position % 2 == 0 { &mut first } else { &mut second }.push(position, word, *score);
+        }
+        first.merge(second);
+        assert_eq!(first.into_sorted_vec(), vec![("b", 0.9), ("d", 0.9), ("g", 0.7)]);
+
+        assert!(TopK::new(0).into_sorted_vec().is_empty());
+        let mut all = TopK::new(usize::MAX);
+        all.push(0, "a", 0.5);
+        assert_eq!(all.into_sorted_vec(), vec![("a", 0.5)]);
+    }
+}